@@ -0,0 +1,437 @@
+/// AccessLogEntry is one HTTP request, or one TCP connection, served to a peer.
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+    /// when we started serving the request
+    pub time: std::time::SystemTime,
+    pub remote_id52: String,
+    pub protocol: crate::Protocol,
+    /// `None` for TCP connections
+    pub method: Option<String>,
+    /// for TCP connections this is the address we connected to
+    pub uri: String,
+    /// the HTTP version of the request, say `HTTP/1.1`, `None` for TCP connections
+    pub version: Option<String>,
+    /// `None` if we could not send a response
+    pub status: Option<u16>,
+    /// bytes read from the peer, for HTTP only the body is counted
+    pub bytes_in: u64,
+    /// bytes sent to the peer, for HTTP only the body is counted
+    pub bytes_out: u64,
+    pub duration: std::time::Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// AccessLogSink is where the access log entries go, `FileAccessLog` writes them to a file. `log()`
+/// is called once the request is done, from whichever task served it.
+pub trait AccessLogSink: Send + Sync {
+    fn log(&self, entry: &AccessLogEntry);
+}
+
+/// AccessLog is passed to everything that serves peers. it is cheap to clone, and does nothing if
+/// no sink is configured, which is the default.
+///
+/// one AccessLog is used per exposed service, the protocol the service is exposed with is recorded
+/// in every entry.
+#[derive(Clone, Default)]
+pub struct AccessLog {
+    sink: Option<std::sync::Arc<dyn AccessLogSink>>,
+    protocol: Option<crate::Protocol>,
+}
+
+impl AccessLog {
+    pub fn new(sink: std::sync::Arc<dyn AccessLogSink>, protocol: crate::Protocol) -> Self {
+        Self {
+            sink: Some(sink),
+            protocol: Some(protocol),
+        }
+    }
+
+    /// nothing is written, but the requests are still counted in the metrics, under `protocol`
+    pub fn disabled(protocol: crate::Protocol) -> Self {
+        Self {
+            sink: None,
+            protocol: Some(protocol),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// a new entry for a request being served right now, fill in the rest and pass it to `log()`
+    pub fn entry(&self, remote_id52: &str, uri: impl Into<String>) -> AccessLogEntry {
+        AccessLogEntry {
+            time: std::time::SystemTime::now(),
+            remote_id52: remote_id52.to_string(),
+            protocol: self.protocol.unwrap_or(crate::Protocol::Http),
+            method: None,
+            uri: uri.into(),
+            version: None,
+            status: None,
+            bytes_in: 0,
+            bytes_out: 0,
+            duration: std::time::Duration::ZERO,
+            referer: None,
+            user_agent: None,
+        }
+    }
+
+    /// records the entry, `duration` is set to the time since the entry was created
+    pub fn log(&self, mut entry: AccessLogEntry) {
+        entry.duration = entry.time.elapsed().unwrap_or_default();
+        crate::metrics::request_served(&entry);
+
+        if let Some(sink) = &self.sink {
+            sink.log(&entry);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// one JSON object per line
+    Json,
+    /// the Apache / nginx "combined" format, the remote id52 is used as the client address
+    Combined,
+}
+
+impl std::str::FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(AccessLogFormat::Json),
+            "combined" => Ok(AccessLogFormat::Combined),
+            _ => Err(format!(
+                "unknown access log format {s:?}, use json or combined"
+            )),
+        }
+    }
+}
+
+/// escape() makes a field safe for the combined format, like nginx does: `"`, `\\` and every byte
+/// that is not printable ASCII is written as `\xHH`. the fields come from the peer, without this
+/// they could end the quoted string, or the line, and forge entries.
+fn escape(v: &str) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::with_capacity(v.len());
+    for b in v.bytes() {
+        if b == b'"' || b == b'\\' || !(b' '..=b'~').contains(&b) {
+            let _ = write!(out, "\\x{b:02X}");
+        } else {
+            out.push(b as char);
+        }
+    }
+    out
+}
+
+impl AccessLogFormat {
+    pub fn format(&self, entry: &AccessLogEntry) -> String {
+        match self {
+            AccessLogFormat::Json => serde_json::json!({
+                "time": rfc3339(entry.time),
+                "remote_id52": entry.remote_id52,
+                "protocol": entry.protocol,
+                "method": entry.method,
+                "uri": entry.uri,
+                "version": entry.version,
+                "status": entry.status,
+                "bytes_in": entry.bytes_in,
+                "bytes_out": entry.bytes_out,
+                "duration_ms": entry.duration.as_millis() as u64,
+                "referer": entry.referer,
+                "user_agent": entry.user_agent,
+            })
+            .to_string(),
+            AccessLogFormat::Combined => {
+                let quoted = |v: Option<&str>| v.map_or_else(|| "-".to_string(), escape);
+                format!(
+                    "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+                    escape(&entry.remote_id52),
+                    clf_time(entry.time),
+                    quoted(entry.method.as_deref()),
+                    escape(&entry.uri),
+                    quoted(entry.version.as_deref()),
+                    entry
+                        .status
+                        .map_or_else(|| "-".to_string(), |v| v.to_string()),
+                    entry.bytes_out,
+                    quoted(entry.referer.as_deref()),
+                    quoted(entry.user_agent.as_deref()),
+                )
+            }
+        }
+    }
+}
+
+/// FileAccessLog appends the entries to a file, one per line.
+///
+/// if `max_size` is set, the file is rotated once it grows past it: `access.log` is renamed to
+/// `access.log.1`, `access.log.1` to `access.log.2` and so on, and only `keep` old files are kept.
+///
+/// the lines are written synchronously, under a lock. they are small and go to a local file, and
+/// this way the log is in order and nothing is lost if we exit right after serving a request.
+pub struct FileAccessLog {
+    path: std::path::PathBuf,
+    format: AccessLogFormat,
+    max_size: Option<u64>,
+    keep: usize,
+    /// the open file and its size
+    file: std::sync::Mutex<(std::fs::File, u64)>,
+}
+
+impl FileAccessLog {
+    pub fn new(
+        path: impl Into<std::path::PathBuf>,
+        format: AccessLogFormat,
+        max_size: Option<u64>,
+        keep: usize,
+    ) -> eyre::Result<Self> {
+        use eyre::WrapErr;
+
+        let path = path.into();
+        let file = open(&path).wrap_err_with(|| format!("failed to open {path:?}"))?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            format,
+            max_size,
+            keep,
+            file: std::sync::Mutex::new((file, size)),
+        })
+    }
+
+    fn rotate(&self) -> std::io::Result<std::fs::File> {
+        let rotated = |i: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{i}"));
+            std::path::PathBuf::from(path)
+        };
+
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                match std::fs::rename(rotated(i), rotated(i + 1)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+
+        open(&self.path)
+    }
+}
+
+impl AccessLogSink for FileAccessLog {
+    fn log(&self, entry: &AccessLogEntry) {
+        use std::io::Write;
+
+        let mut line = self.format.format(entry);
+        line.push('\n');
+
+        let mut file = match self.file.lock() {
+            Ok(v) => v,
+            Err(e) => e.into_inner(),
+        };
+
+        if let Some(max_size) = self.max_size
+            && file.1 > 0
+            && file.1 + line.len() as u64 > max_size
+        {
+            match self.rotate() {
+                Ok(v) => *file = (v, 0),
+                Err(e) => tracing::error!(path = ?self.path, "failed to rotate access log: {e:?}"),
+            }
+        }
+
+        match file.0.write_all(line.as_bytes()) {
+            Ok(()) => file.1 += line.len() as u64,
+            Err(e) => tracing::error!(path = ?self.path, "failed to write access log: {e:?}"),
+        }
+    }
+}
+
+fn open(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+/// Counted counts the bytes read from or written to the wrapped stream.
+pub(crate) struct Counted<T> {
+    inner: T,
+    count: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T, count: std::sync::Arc<std::sync::atomic::AtomicU64>) -> Self {
+        Self { inner, count }
+    }
+
+    fn add(&self, n: usize) {
+        self.count
+            .fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+impl<T: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let r = std::pin::Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        self.add(n);
+        r
+    }
+}
+
+impl<T: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let r = std::pin::Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let std::task::Poll::Ready(Ok(n)) = r {
+            self.add(n);
+        }
+        r
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// `2025-01-02T03:04:05Z`
+fn rfc3339(time: std::time::SystemTime) -> String {
+    let (year, month, day, h, m, s) = utc(time);
+    format!("{year:04}-{month:02}-{day:02}T{h:02}:{m:02}:{s:02}Z")
+}
+
+/// `02/Jan/2025:03:04:05 +0000`, the time format of the common log format
+fn clf_time(time: std::time::SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, h, m, s) = utc(time);
+    format!(
+        "{day:02}/{}/{year:04}:{h:02}:{m:02}:{s:02} +0000",
+        MONTHS[month as usize - 1]
+    )
+}
+
+/// (year, month, day, hour, minute, second) in UTC. the date part is Howard Hinnant's
+/// `civil_from_days()`, we do not need a date library for just this.
+fn utc(time: std::time::SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_format() {
+        let mut entry = super::AccessLog::default().entry("peer", "/a \"b\"");
+        entry.time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1709251200 + 3723);
+        entry.method = Some("GET".to_string());
+        entry.version = Some("HTTP/1.1".to_string());
+        entry.status = Some(200);
+        entry.bytes_out = 42;
+
+        assert_eq!(
+            super::AccessLogFormat::Combined.format(&entry),
+            "peer - - [01/Mar/2024:01:02:03 +0000] \"GET /a \\x22b\\x22 HTTP/1.1\" 200 42 \"-\" \"-\""
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&super::AccessLogFormat::Json.format(&entry)).unwrap();
+        assert_eq!(json["time"], "2024-03-01T01:02:03Z");
+        assert_eq!(json["protocol"], "Http");
+        assert_eq!(json["status"], 200);
+    }
+
+    #[test]
+    fn test_format_escapes() {
+        let mut entry = super::AccessLog::default().entry("peer", "/a\\b");
+        entry.time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1709251200 + 3723);
+        entry.method = Some("GET\n".to_string());
+        entry.version = Some("HTTP/1.1".to_string());
+        entry.status = Some(200);
+        // a peer trying to end the line, and add one of its own
+        entry.user_agent = Some(
+            "curl\r\npeer - - [01/Mar/2024:01:02:03 +0000] \"GET /forged HTTP/1.1\" 200 0 \
+             \"-\" \"é"
+                .to_string(),
+        );
+
+        let line = super::AccessLogFormat::Combined.format(&entry);
+        assert!(!line.contains(['\r', '\n']), "{line}");
+        assert_eq!(
+            line,
+            "peer - - [01/Mar/2024:01:02:03 +0000] \"GET\\x0A /a\\x5Cb HTTP/1.1\" 200 0 \"-\" \
+             \"curl\\x0D\\x0Apeer - - [01/Mar/2024:01:02:03 +0000] \\x22GET /forged HTTP/1.1\\x22 \
+             200 0 \\x22-\\x22 \\x22\\xC3\\xA9\""
+        );
+    }
+
+    #[test]
+    fn test_rotation() {
+        use super::AccessLogSink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log =
+            super::FileAccessLog::new(&path, super::AccessLogFormat::Json, Some(1), 2).unwrap();
+
+        let entry = super::AccessLog::default().entry("peer", "/");
+        for _ in 0..4 {
+            log.log(&entry);
+        }
+
+        let lines = |name: &str| {
+            std::fs::read_to_string(dir.path().join(name))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        assert_eq!(lines("access.log"), 1);
+        assert_eq!(lines("access.log.1"), 1);
+        assert_eq!(lines("access.log.2"), 1);
+        assert!(!dir.path().join("access.log.3").exists());
+    }
+}
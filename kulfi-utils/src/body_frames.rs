@@ -0,0 +1,197 @@
+//! the framed body mode of the Http protocol.
+//!
+//! normally the body is sent as raw bytes after the request or response header, and the end of the
+//! stream marks the end of the body. this can not carry trailers, which gRPC needs (the status is
+//! sent as `grpc-status` trailer), nor can it tell the end of the request body without closing
+//! the stream.
+//!
+//! when the request has `framed: true` (the bridge does this if the client sent `TE: trailers`),
+//! the body in both directions is a sequence of frames: one byte kind, four bytes (big endian)
+//! length, and then the payload. a data frame carries a chunk of the body, a trailers frame
+//! carries the trailers as JSON, in the same format as headers, and ends the body. if there are no
+//! trailers, an end frame with no payload ends the body.
+//!
+//! the length comes from the peer, so a frame can not be bigger than [MAX_FRAME_SIZE], larger
+//! chunks of the body are sent as several data frames.
+
+const DATA: u8 = 0;
+const TRAILERS: u8 = 1;
+const END: u8 = 2;
+
+/// the largest payload a frame can have, we refuse bigger frames before allocating for them
+pub(crate) const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// copies `body` to `send` as frames, till the end of the body
+pub(crate) async fn write_body<B, W>(mut body: B, send: &mut W) -> eyre::Result<()>
+where
+    B: hyper::body::Body<Data = hyper::body::Bytes> + Unpin,
+    B::Error: std::fmt::Debug,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use http_body_util::BodyExt;
+
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| eyre::anyhow!("read_chunk error: {e:?}"))?;
+        let frame = match frame.into_data() {
+            Ok(data) => {
+                tracing::trace!("sending data of size: {}", data.len());
+                for chunk in data.chunks(MAX_FRAME_SIZE) {
+                    write_frame(send, DATA, chunk).await?;
+                }
+                continue;
+            }
+            Err(frame) => frame,
+        };
+
+        if let Ok(trailers) = frame.into_trailers() {
+            let trailers: Vec<(String, Vec<u8>)> = trailers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+                .collect();
+            tracing::trace!("sending trailers: {trailers:?}");
+            return write_frame(send, TRAILERS, &serde_json::to_vec(&trailers)?).await;
+        }
+    }
+
+    write_frame(send, END, &[]).await
+}
+
+/// reads frames from `recv` as a body, the body ends with the trailers or the end frame
+pub(crate) fn read_body<R>(recv: R) -> crate::HttpBody
+where
+    R: tokio::io::AsyncRead + Unpin + Send + Sync + 'static,
+{
+    let frames = futures_util::stream::try_unfold(Some(recv), |recv| async move {
+        let mut recv = match recv {
+            Some(v) => v,
+            // we have already sent the trailers
+            None => return Ok(None),
+        };
+
+        let (kind, payload) = read_frame(&mut recv).await?;
+        match kind {
+            DATA => {
+                tracing::trace!("got data frame of size: {}", payload.len());
+                Ok(Some((hyper::body::Frame::data(payload.into()), Some(recv))))
+            }
+            TRAILERS => {
+                let trailers: Vec<(String, Vec<u8>)> = serde_json::from_slice(&payload)?;
+                tracing::trace!("got trailers: {trailers:?}");
+                let mut map = hyper::HeaderMap::new();
+                for (k, v) in trailers {
+                    map.append(
+                        hyper::header::HeaderName::from_bytes(k.as_bytes())?,
+                        hyper::header::HeaderValue::from_bytes(&v)?,
+                    );
+                }
+                Ok(Some((hyper::body::Frame::trailers(map), None)))
+            }
+            END => Ok(None),
+            _ => Err(eyre::anyhow!("unknown body frame kind: {kind}")),
+        }
+    });
+
+    http_body_util::BodyExt::boxed(http_body_util::StreamBody::new(frames))
+}
+
+async fn write_frame<W>(send: &mut W, kind: u8, payload: &[u8]) -> eyre::Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(eyre::anyhow!(
+            "body frame too large: {} bytes",
+            payload.len()
+        ));
+    }
+    let len = payload.len() as u32;
+
+    send.write_u8(kind).await?;
+    send.write_u32(len).await?;
+    send.write_all(payload).await?;
+
+    Ok(())
+}
+
+async fn read_frame<R>(recv: &mut R) -> eyre::Result<(u8, Vec<u8>)>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use eyre::WrapErr;
+    use tokio::io::AsyncReadExt;
+
+    let kind = recv
+        .read_u8()
+        .await
+        .wrap_err_with(|| "stream closed before the end of the body")?;
+    let len = recv.read_u32().await? as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(eyre::anyhow!(
+            "body frame too large: {len} bytes, the limit is {MAX_FRAME_SIZE}"
+        ));
+    }
+
+    let mut payload = vec![0; len];
+    recv.read_exact(&mut payload).await?;
+
+    Ok((kind, payload))
+}
+
+#[cfg(test)]
+mod test {
+    #[tokio::test]
+    async fn test_body_frames() {
+        use http_body_util::BodyExt;
+
+        let (mut send, recv) = tokio::io::duplex(64);
+
+        let mut trailers = hyper::HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        let frames: Vec<eyre::Result<_>> = vec![
+            Ok(hyper::body::Frame::data(hyper::body::Bytes::from("hello "))),
+            Ok(hyper::body::Frame::data(hyper::body::Bytes::from("world"))),
+            Ok(hyper::body::Frame::trailers(trailers.clone())),
+        ];
+        let body = http_body_util::StreamBody::new(futures_util::stream::iter(frames));
+
+        tokio::spawn(async move { super::write_body(body, &mut send).await.unwrap() });
+
+        let collected = super::read_body(recv).collect().await.unwrap();
+        assert_eq!(collected.trailers(), Some(&trailers));
+        assert_eq!(collected.to_bytes(), "hello world");
+
+        // a body without trailers
+        let (mut send, recv) = tokio::io::duplex(64);
+        let body = http_body_util::Full::new(hyper::body::Bytes::from("just data"));
+        super::write_body(body, &mut send).await.unwrap();
+
+        let collected = super::read_body(recv).collect().await.unwrap();
+        assert!(collected.trailers().is_none());
+        assert_eq!(collected.to_bytes(), "just data");
+    }
+
+    #[tokio::test]
+    async fn test_large_frames() {
+        use http_body_util::BodyExt;
+        use tokio::io::AsyncWriteExt;
+
+        // a chunk bigger than a frame goes as several frames
+        let data = vec![7u8; super::MAX_FRAME_SIZE + 10];
+        let (mut send, recv) = tokio::io::duplex(64 * 1024);
+        let body = http_body_util::Full::new(hyper::body::Bytes::from(data.clone()));
+        tokio::spawn(async move { super::write_body(body, &mut send).await.unwrap() });
+
+        let collected = super::read_body(recv).collect().await.unwrap();
+        assert_eq!(collected.to_bytes(), data);
+
+        // a peer sending a huge length is refused without reading the payload
+        let (mut send, recv) = tokio::io::duplex(64);
+        send.write_u8(super::DATA).await.unwrap();
+        send.write_u32(u32::MAX).await.unwrap();
+
+        let err = super::read_body(recv).collect().await.unwrap_err();
+        assert!(err.to_string().contains("too large"), "{err}");
+    }
+}
@@ -1,6 +1,7 @@
 pub async fn get_endpoint(secret_key: kulfi_id52::SecretKey) -> eyre::Result<iroh::Endpoint> {
     // Convert kulfi_id52::SecretKey to iroh::SecretKey
     let iroh_secret_key = iroh::SecretKey::from_bytes(&secret_key.to_bytes());
+    crate::time::add_signing_key(&secret_key);
 
     match iroh::Endpoint::builder()
         .discovery(iroh::discovery::pkarr::PkarrPublisher::n0_dns())
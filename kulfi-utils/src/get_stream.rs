@@ -5,7 +5,11 @@ pub type PeerStreamSenders = std::sync::Arc<
     tokio::sync::Mutex<std::collections::HashMap<(SelfID52, RemoteID52), StreamRequestSender>>,
 >;
 
-type Stream = (iroh::endpoint::SendStream, iroh::endpoint::RecvStream);
+type Stream = (
+    iroh::endpoint::Connection,
+    iroh::endpoint::SendStream,
+    iroh::endpoint::RecvStream,
+);
 type StreamResult = eyre::Result<Stream>;
 type ReplyChannel = tokio::sync::oneshot::Sender<StreamResult>;
 type RemoteID52 = String;
@@ -16,6 +20,72 @@ type StreamRequest = (crate::ProtocolHeader, ReplyChannel);
 type StreamRequestSender = tokio::sync::mpsc::Sender<StreamRequest>;
 type StreamRequestReceiver = tokio::sync::mpsc::Receiver<StreamRequest>;
 
+/// how long we wait for a connection to a peer
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+/// how long we fail requests for a peer right away after failing to connect to it the first time,
+/// doubled with every failure after that, up to `MAX_BACKOFF`
+const MIN_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// the peers we failed to connect to recently. when a peer is offline, every request would
+/// otherwise wait for a new connection attempt to fail, so we fail them right away till the
+/// backoff is over, and then try again. a peer is forgotten once we connect to it.
+static UNREACHABLE: std::sync::LazyLock<
+    std::sync::Mutex<std::collections::HashMap<(SelfID52, RemoteID52), Backoff>>,
+> = std::sync::LazyLock::new(Default::default);
+
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    failures: u32,
+    until: std::time::Instant,
+}
+
+impl Backoff {
+    /// the backoff after one more failure
+    fn next(previous: Option<Backoff>, now: std::time::Instant) -> Backoff {
+        let failures = previous.map_or(1, |b| b.failures.saturating_add(1));
+        let wait = MIN_BACKOFF
+            .saturating_mul(2u32.saturating_pow(failures - 1))
+            .min(MAX_BACKOFF);
+        Backoff {
+            failures,
+            until: now + wait,
+        }
+    }
+}
+
+fn unreachable()
+-> std::sync::MutexGuard<'static, std::collections::HashMap<(SelfID52, RemoteID52), Backoff>> {
+    UNREACHABLE
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+fn connect_failed(self_id52: &str, remote_id52: &str) {
+    let now = std::time::Instant::now();
+    let mut unreachable = unreachable();
+    // peers that have not failed in a while start over
+    unreachable.retain(|_, b| b.until + MAX_BACKOFF > now);
+    let key = (self_id52.to_string(), remote_id52.to_string());
+    let backoff = Backoff::next(unreachable.get(&key).copied(), now);
+    tracing::info!(remote_id52, ?backoff, "backing off");
+    unreachable.insert(key, backoff);
+}
+
+/// the error for a request to a peer we are backing off from
+fn backing_off(self_id52: &str, remote_id52: &str) -> Option<eyre::Report> {
+    let key = (self_id52.to_string(), remote_id52.to_string());
+    let backoff = *unreachable().get(&key)?;
+    let left = backoff
+        .until
+        .checked_duration_since(std::time::Instant::now())?;
+    Some(
+        eyre::Report::new(crate::http::ProxyError::PeerUnreachable).wrap_err(format!(
+            "{remote_id52} was not reachable, backing off for {left:?} more"
+        )),
+    )
+}
+
 /// get_stream tries to check if the bidirectional stream is healthy, as simply opening
 /// a bidirectional stream, or even simply writing on it does not guarantee that the stream is
 /// open. only the read request times out to tell us something is wrong. this is why get_stream
@@ -23,6 +93,11 @@ type StreamRequestReceiver = tokio::sync::mpsc::Receiver<StreamRequest>;
 /// sends the protocol and waits for an ack. if the ack is not received within a certain time, it
 /// assumes the connection is not healthy, and tries to recreate the connection.
 ///
+/// when it can not get a stream, the error can be downcast to `http::ProxyError` to tell why: the
+/// peer is offline (`PeerUnreachable`, also returned right away for a while after a failed
+/// connection attempt), connecting took too long (`PeerTimeout`), the peer does not serve the
+/// protocol (`PeerRefused`) or the id52 is not valid (`InvalidPeerId`).
+///
 /// for managing connection, we use a spawned task. this task listens for incoming stream requests
 /// and manages the connection as part of the task local data.
 #[tracing::instrument(skip_all)]
@@ -33,9 +108,34 @@ pub async fn get_stream(
     peer_stream_senders: PeerStreamSenders,
     graceful: crate::Graceful,
 ) -> eyre::Result<(iroh::endpoint::SendStream, iroh::endpoint::RecvStream)> {
+    let (_conn, send, recv) = get_stream_with_connection(
+        self_endpoint,
+        header,
+        remote_node_id52,
+        peer_stream_senders,
+        graceful,
+    )
+    .await?;
+    Ok((send, recv))
+}
+
+/// same as `get_stream()`, along with the connection the stream is on, for the QUIC datagrams of
+/// `Protocol::Udp` flows
+pub async fn get_stream_with_connection(
+    self_endpoint: iroh::Endpoint,
+    header: crate::ProtocolHeader,
+    remote_node_id52: RemoteID52,
+    peer_stream_senders: PeerStreamSenders,
+    graceful: crate::Graceful,
+) -> StreamResult {
     use eyre::WrapErr;
 
     tracing::trace!("get_stream: {header:?}");
+    let self_id52 = data_encoding::BASE32_DNSSEC.encode(self_endpoint.id().as_bytes());
+    if let Some(e) = backing_off(&self_id52, &remote_node_id52) {
+        return Err(e);
+    }
+
     let stream_request_sender = get_stream_request_sender(
         self_endpoint,
         remote_node_id52,
@@ -136,6 +236,7 @@ async fn connection_manager(
     // affected by this. tho, since something wrong has happened with the connection, they will
     // eventually fail too.
     tracing::error!("connection manager worker error: {e:?}");
+    let kind = e.downcast_ref::<crate::http::ProxyError>().copied();
 
     // once we close the receiver, any tasks that have gotten access to the corresponding sender
     // will fail when sending.
@@ -143,10 +244,13 @@ async fn connection_manager(
 
     // send an error to all the tasks that are waiting for stream for this receiver.
     while let Some((_protocol, reply_channel)) = receiver.recv().await {
-        if reply_channel
-            .send(Err(eyre::anyhow!("failed to create connection: {e:?}")))
-            .is_err()
-        {
+        let error = match kind {
+            Some(kind) => {
+                eyre::Report::new(kind).wrap_err(format!("failed to create connection: {e:?}"))
+            }
+            None => eyre::anyhow!("failed to create connection: {e:?}"),
+        };
+        if reply_channel.send(Err(error)).is_err() {
             tracing::error!("failed to send error reply: {e:?}");
         }
     }
@@ -159,25 +263,46 @@ async fn connection_manager_(
     remote_node_id52: RemoteID52,
     graceful: crate::Graceful,
 ) -> eyre::Result<()> {
-    let conn = match self_endpoint
-        .connect(
-            {
-                // Convert ID52 to iroh::NodeId
-                use std::str::FromStr;
-                let public_key = kulfi_id52::PublicKey::from_str(&remote_node_id52)
-                    .map_err(|e| eyre::anyhow!("{}", e))?;
-                iroh::EndpointId::from_bytes(&public_key.to_bytes())?
-            },
-            crate::APNS_IDENTITY,
-        )
-        .await
+    use crate::http::ProxyError;
+
+    let remote = {
+        // Convert ID52 to iroh::NodeId
+        use std::str::FromStr;
+        kulfi_id52::PublicKey::from_str(&remote_node_id52)
+            .ok()
+            .and_then(|public_key| iroh::EndpointId::from_bytes(&public_key.to_bytes()).ok())
+            .ok_or_else(|| {
+                eyre::Report::new(ProxyError::InvalidPeerId)
+                    .wrap_err(format!("invalid id52: {remote_node_id52:?}"))
+            })?
+    };
+    let self_id52 = data_encoding::BASE32_DNSSEC.encode(self_endpoint.id().as_bytes());
+
+    let conn = match tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        self_endpoint.connect(remote, crate::APNS_IDENTITY),
+    )
+    .await
     {
-        Ok(v) => v,
-        Err(e) => {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
             tracing::error!("failed to create connection: {e:?}");
-            return Err(eyre::anyhow!("failed to create connection: {e:?}"));
+            crate::metrics::get_stream_connect(false);
+            connect_failed(&self_id52, &remote_node_id52);
+            return Err(eyre::Report::new(ProxyError::PeerUnreachable)
+                .wrap_err(format!("failed to create connection: {e:?}")));
+        }
+        Err(_) => {
+            tracing::error!("timed out creating connection");
+            crate::metrics::get_stream_connect(false);
+            connect_failed(&self_id52, &remote_node_id52);
+            return Err(eyre::Report::new(ProxyError::PeerTimeout)
+                .wrap_err(format!("connecting took more than {CONNECT_TIMEOUT:?}")));
         }
     };
+    crate::metrics::get_stream_connect(true);
+    unreachable().remove(&(self_id52, remote_node_id52.clone()));
+    let _active = crate::metrics::ActivePeer::opened(&remote_node_id52);
 
     let timeout = std::time::Duration::from_secs(12);
     let mut idle_counter = 0;
@@ -264,53 +389,23 @@ async fn handle_request(
     header: crate::ProtocolHeader,
     reply_channel: ReplyChannel,
 ) -> eyre::Result<()> {
-    use eyre::WrapErr;
-
     tracing::trace!("handling request: {header:?}");
 
-    let (mut send, mut recv) = match conn.open_bi().await {
-        Ok(v) => {
-            tracing::trace!("opened bi-stream");
-            v
-        }
-        Err(e) => {
-            tracing::error!("failed to open_bi: {e:?}");
-            return Err(eyre::anyhow!("failed to open_bi: {e:?}"));
+    let r = match crate::open_bi(conn, header)
+        .await
+        .map(|(send, recv)| (conn.clone(), send, recv))
+    {
+        // when the peer refuses the stream, the connection is fine, and is kept for other streams
+        Err(e)
+            if e.downcast_ref::<crate::http::ProxyError>()
+                != Some(&crate::http::ProxyError::PeerRefused) =>
+        {
+            return Err(e);
         }
+        r => r,
     };
 
-    send.write_all(
-        &serde_json::to_vec(&header.protocol)
-            .wrap_err_with(|| format!("failed to serialize protocol: {:?}", header.protocol))?,
-    )
-    .await?;
-    tracing::trace!("wrote protocol");
-
-    send.write(b"\n")
-        .await
-        .wrap_err_with(|| "failed to write newline")?;
-
-    tracing::trace!("wrote newline");
-
-    if let Some(extra) = header.extra {
-        send.write_all(extra.as_bytes()).await?;
-        tracing::trace!("wrote protocol");
-
-        send.write(b"\n")
-            .await
-            .wrap_err_with(|| "failed to write newline")?;
-    }
-
-    let msg = crate::next_string(&mut recv).await?;
-
-    if msg != crate::ACK {
-        tracing::error!("failed to read ack: {msg:?}");
-        return Err(eyre::anyhow!("failed to read ack: {msg:?}"));
-    }
-
-    tracing::trace!("received ack");
-
-    reply_channel.send(Ok((send, recv))).unwrap_or_else(|e| {
+    reply_channel.send(r).unwrap_or_else(|e| {
         tracing::error!("failed to send reply: {e:?}");
     });
 
@@ -318,3 +413,18 @@ async fn handle_request(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_backoff() {
+        let now = std::time::Instant::now();
+        let waits: Vec<_> = std::iter::successors(Some(super::Backoff::next(None, now)), |b| {
+            Some(super::Backoff::next(Some(*b), now))
+        })
+        .take(9)
+        .map(|b| (b.until - now).as_secs())
+        .collect();
+        assert_eq!(waits, [1, 2, 4, 8, 16, 32, 60, 60, 60]);
+    }
+}
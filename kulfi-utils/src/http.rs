@@ -3,6 +3,14 @@ pub struct Request {
     pub uri: String,
     pub method: String,
     pub headers: Vec<(String, Vec<u8>)>,
+    /// the bodies of this request and its response are sent as frames, so they can carry
+    /// trailers, see `body_frames.rs`. older peers do not send this field.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub framed: bool,
+    /// the HTTP version the client used with the bridge, say `HTTP/1.1`, for the access log.
+    /// older peers do not send this field.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
 }
 
 impl From<hyper::http::request::Parts> for Request {
@@ -20,6 +28,8 @@ impl From<hyper::http::request::Parts> for Request {
             uri: r.uri.to_string(),
             method: r.method.to_string(),
             headers,
+            framed: false,
+            version: Some(format!("{:?}", r.version)),
         }
     }
 }
@@ -28,6 +38,311 @@ impl From<hyper::http::request::Parts> for Request {
 pub struct Response {
     pub status: u16,
     pub headers: Vec<(String, Vec<u8>)>,
+    /// set if the request was framed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub framed: bool,
+}
+
+/// is_upgrade() tells if the request wants to switch protocols (say to websocket), or if the
+/// response agreed to it. such requests are not streamed like regular requests, once the upstream
+/// responds with `101 Switching Protocols`, the stream is used to pipe raw bytes both ways.
+pub fn is_upgrade(headers: &hyper::HeaderMap) -> bool {
+    let connection_upgrade = headers
+        .get_all(hyper::header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case("upgrade"));
+
+    connection_upgrade && headers.contains_key(hyper::header::UPGRADE)
+}
+
+/// wants_trailers() tells if the client can accept trailers in the response, gRPC clients always
+/// send `TE: trailers`.
+pub fn wants_trailers(headers: &hyper::HeaderMap) -> bool {
+    headers
+        .get_all(hyper::header::TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// hop-by-hop headers only make sense for a single connection, proxies must not forward them
+/// (RFC 9110 section 7.6.1). `Upgrade` is kept if the request is being upgraded, and `TE` if it
+/// only asks for trailers, as gRPC needs that end to end.
+pub fn remove_hop_by_hop_headers(headers: &mut hyper::HeaderMap) {
+    let upgrade = if is_upgrade(headers) {
+        headers.get(hyper::header::UPGRADE).cloned()
+    } else {
+        None
+    };
+    let trailers = wants_trailers(headers);
+
+    // `Connection` lists more headers that are only meant for this hop
+    let listed: Vec<_> = headers
+        .get_all(hyper::header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| hyper::header::HeaderName::from_bytes(v.trim().as_bytes()).ok())
+        .collect();
+    let proxy: Vec<_> = headers
+        .keys()
+        .filter(|k| k.as_str().starts_with("proxy-"))
+        .cloned()
+        .collect();
+
+    for name in listed.into_iter().chain(proxy) {
+        headers.remove(name);
+    }
+    for name in [
+        hyper::header::CONNECTION,
+        hyper::header::TE,
+        hyper::header::UPGRADE,
+        hyper::header::HeaderName::from_static("keep-alive"),
+    ] {
+        headers.remove(name);
+    }
+
+    if let Some(upgrade) = upgrade {
+        headers.insert(
+            hyper::header::CONNECTION,
+            hyper::header::HeaderValue::from_static("upgrade"),
+        );
+        headers.insert(hyper::header::UPGRADE, upgrade);
+    }
+    if trailers {
+        headers.insert(
+            hyper::header::TE,
+            hyper::header::HeaderValue::from_static("trailers"),
+        );
+    }
+}
+
+/// the headers a proxy uses to tell the upstream about the original request. they are only as
+/// trustworthy as whoever set them, see `PeerToHttpOptions::trusted_bridges`.
+pub const FORWARDED_HEADERS: [&str; 5] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    // set by `malai http-bridge --path-routing`
+    "x-forwarded-prefix",
+];
+
+/// set_forwarded_headers() is called by the bridge, it is where the request enters kulfi, so any
+/// `Forwarded` or `X-Forwarded-*` headers sent by the client are replaced, the client can not
+/// pretend to be someone else. `Via` is appended to, as it lists every proxy on the way.
+pub fn set_forwarded_headers(
+    headers: &mut hyper::HeaderMap,
+    version: hyper::Version,
+    client: std::net::IpAddr,
+    proto: &str,
+    host: Option<&str>,
+) {
+    use hyper::header::HeaderValue;
+
+    for name in FORWARDED_HEADERS {
+        headers.remove(name);
+    }
+
+    // IPv6 addresses have to be quoted, and put in brackets
+    let for_ = match client {
+        std::net::IpAddr::V4(ip) => ip.to_string(),
+        std::net::IpAddr::V6(ip) => format!("\"[{ip}]\""),
+    };
+    let mut forwarded = format!("for={for_};proto={proto}");
+
+    let host = host.and_then(|h| HeaderValue::from_str(h).ok());
+    if let Some(host) = &host {
+        // the host is quoted as it may have a port, quotes and backslashes are not valid in a host
+        // anyway, we skip it if they show up
+        if let Ok(h) = host.to_str()
+            && !h.contains(['"', '\\'])
+        {
+            forwarded.push_str(&format!(";host=\"{h}\""));
+        }
+        headers.insert("x-forwarded-host", host.clone());
+    }
+
+    if let Ok(v) = HeaderValue::from_str(&forwarded) {
+        headers.insert(hyper::header::FORWARDED, v);
+    }
+    if let Ok(v) = HeaderValue::from_str(&client.to_string()) {
+        headers.insert("x-forwarded-for", v);
+    }
+    if let Ok(v) = HeaderValue::from_str(proto) {
+        headers.insert("x-forwarded-proto", v);
+    }
+
+    let protocol = match version {
+        hyper::Version::HTTP_10 => "1.0",
+        hyper::Version::HTTP_2 => "2",
+        hyper::Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    let via = match headers
+        .get(hyper::header::VIA)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(v) => format!("{v}, {protocol} kulfi"),
+        None => format!("{protocol} kulfi"),
+    };
+    if let Ok(v) = HeaderValue::from_str(&via) {
+        headers.insert(hyper::header::VIA, v);
+    }
+}
+
+/// set on error responses generated by kulfi itself (and not by the service being shared), the
+/// value is `ProxyError::code()`.
+pub const ERROR_CODE_HEADER: &str = "x-kulfi-error";
+
+/// ProxyError is why we could not get a response from the service being shared. the exposing side
+/// reports the upstream errors to the bridge as a regular response, the bridge adds the ones about
+/// reaching the peer, or refusing to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyError {
+    /// the upstream refused the connection, or it could not be established
+    UpstreamUnreachable,
+    /// the upstream did not accept the connection or send the response header in time
+    UpstreamTimeout,
+    /// the upstream closed the connection without responding
+    UpstreamFailed,
+    /// the peer sharing the service could not be reached, it is probably offline
+    PeerUnreachable,
+    /// connecting to the peer took too long
+    PeerTimeout,
+    /// the peer does not serve the protocol we asked for, say it shares a TCP service and not HTTP
+    PeerRefused,
+    /// the peer closed the stream without sending a response
+    PeerFailed,
+    /// the bridge is configured to not serve this peer, see `malai http-bridge --allow`
+    PeerNotAllowed,
+    /// the request is not for a valid id52
+    InvalidPeerId,
+}
+
+const PROXY_ERRORS: [ProxyError; 9] = [
+    ProxyError::UpstreamUnreachable,
+    ProxyError::UpstreamTimeout,
+    ProxyError::UpstreamFailed,
+    ProxyError::PeerUnreachable,
+    ProxyError::PeerTimeout,
+    ProxyError::PeerRefused,
+    ProxyError::PeerFailed,
+    ProxyError::PeerNotAllowed,
+    ProxyError::InvalidPeerId,
+];
+
+impl ProxyError {
+    pub fn status(&self) -> hyper::StatusCode {
+        match self {
+            ProxyError::UpstreamTimeout | ProxyError::PeerTimeout => {
+                hyper::StatusCode::GATEWAY_TIMEOUT
+            }
+            ProxyError::PeerNotAllowed => hyper::StatusCode::FORBIDDEN,
+            ProxyError::InvalidPeerId => hyper::StatusCode::BAD_REQUEST,
+            _ => hyper::StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProxyError::UpstreamUnreachable => "upstream-unreachable",
+            ProxyError::UpstreamTimeout => "upstream-timeout",
+            ProxyError::UpstreamFailed => "upstream-failed",
+            ProxyError::PeerUnreachable => "peer-unreachable",
+            ProxyError::PeerTimeout => "peer-timeout",
+            ProxyError::PeerRefused => "peer-refused",
+            ProxyError::PeerFailed => "peer-failed",
+            ProxyError::PeerNotAllowed => "peer-not-allowed",
+            ProxyError::InvalidPeerId => "invalid-id52",
+        }
+    }
+
+    /// the error for an `x-kulfi-error` value
+    pub fn from_code(code: &str) -> Option<Self> {
+        PROXY_ERRORS.into_iter().find(|e| e.code() == code)
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ProxyError::UpstreamUnreachable => "The service being shared is not reachable.",
+            ProxyError::UpstreamTimeout => "The service being shared did not respond in time.",
+            ProxyError::UpstreamFailed => {
+                "The service being shared closed the connection without responding."
+            }
+            ProxyError::PeerUnreachable => {
+                "The peer sharing this service is offline, or can not be reached."
+            }
+            ProxyError::PeerTimeout => "The peer sharing this service took too long to connect.",
+            ProxyError::PeerRefused => "The peer does not share an HTTP service.",
+            ProxyError::PeerFailed => {
+                "The peer sharing this service closed the connection without responding."
+            }
+            ProxyError::PeerNotAllowed => {
+                "This bridge does not serve the peer sharing this service."
+            }
+            ProxyError::InvalidPeerId => "The address does not have a valid id52.",
+        }
+    }
+
+    /// the content type and body of the error page, JSON if `accept` asks for it and HTML
+    /// otherwise.
+    pub fn body(&self, accept: Option<&[u8]>) -> (&'static str, Vec<u8>) {
+        if wants_json(accept) {
+            let body = serde_json::json!({"error": self.code(), "message": self.message()});
+            return ("application/json", body.to_string().into_bytes());
+        }
+
+        let status = self.status();
+        let title = format!(
+            "{} {}",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or_default()
+        );
+        let body = format!(
+            "<!DOCTYPE html>\n<html><head><title>{title}</title></head>\
+             <body><h1>{title}</h1><p>{}</p><p><code>{}</code></p></body></html>\n",
+            self.message(),
+            self.code()
+        );
+        ("text/html; charset=utf-8", body.into_bytes())
+    }
+
+    /// the error response, to be sent by the bridge to the client
+    pub fn response<E>(&self, accept: Option<&hyper::header::HeaderValue>) -> ProxyResponse<E> {
+        crate::metrics::proxy_error(*self);
+        let (content_type, body) = self.body(accept.map(|v| v.as_bytes()));
+        let mut r = bytes_to_resp(body, self.status());
+        r.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static(content_type),
+        );
+        r.headers_mut().insert(
+            ERROR_CODE_HEADER,
+            hyper::header::HeaderValue::from_static(self.code()),
+        );
+        r
+    }
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// `get_stream()` errors can be downcast to a `ProxyError`, to tell why the peer could not be
+/// reached
+impl std::error::Error for ProxyError {}
+
+/// does the `Accept` header ask for JSON, we send HTML otherwise
+pub fn wants_json(accept: Option<&[u8]>) -> bool {
+    accept
+        .and_then(|v| std::str::from_utf8(v).ok())
+        .is_some_and(|v| v.contains("application/json"))
 }
 
 pub type ProxyResponse<E = hyper::Error> =
@@ -124,3 +439,30 @@ pub async fn response_to_static(
 
     Ok(new_resp)
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_set_forwarded_headers() {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.1".parse().unwrap());
+        headers.insert(hyper::header::VIA, "1.1 nginx".parse().unwrap());
+
+        super::set_forwarded_headers(
+            &mut headers,
+            hyper::Version::HTTP_2,
+            "2001:db8::1".parse().unwrap(),
+            "https",
+            Some("docs.ourteam.dev:8443"),
+        );
+
+        assert_eq!(
+            headers[hyper::header::FORWARDED],
+            "for=\"[2001:db8::1]\";proto=https;host=\"docs.ourteam.dev:8443\""
+        );
+        assert_eq!(headers["x-forwarded-for"], "2001:db8::1");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-host"], "docs.ourteam.dev:8443");
+        assert_eq!(headers[hyper::header::VIA], "1.1 nginx, 2 kulfi");
+    }
+}
@@ -2,46 +2,235 @@ pub type HttpConnectionPool = bb8::Pool<HttpConnectionManager>;
 pub type HttpConnectionPools =
     std::sync::Arc<tokio::sync::Mutex<std::collections::HashMap<String, HttpConnectionPool>>>;
 
+/// UpstreamAddr is where the service we are exposing listens.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpstreamAddr {
+    /// `host:port`
+    Tcp(String),
+    /// path of a unix domain socket, only supported on unix platforms
+    Unix(std::path::PathBuf),
+}
+
+impl std::fmt::Display for UpstreamAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamAddr::Tcp(addr) => write!(f, "{addr}"),
+            UpstreamAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<String> for UpstreamAddr {
+    fn from(addr: String) -> Self {
+        UpstreamAddr::Tcp(addr)
+    }
+}
+
+/// HttpUpstream describes how to reach the HTTP service we are forwarding requests to.
+#[derive(Clone, Debug)]
+pub struct HttpUpstream {
+    pub addr: UpstreamAddr,
+    /// if set, the upstream speaks HTTPS
+    pub tls: Option<crate::UpstreamTls>,
+    /// talk HTTP/2 to the upstream without negotiating it first (h2c with prior knowledge), this
+    /// is what gRPC servers expect.
+    pub h2c: bool,
+    /// how long to wait for a new connection to the upstream, including the TLS handshake
+    pub connect_timeout: std::time::Duration,
+    /// how long to wait for the response header once the request is sent, the response body can
+    /// take as long as it likes.
+    pub read_timeout: std::time::Duration,
+}
+
+pub const DEFAULT_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+pub const DEFAULT_READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+impl HttpUpstream {
+    /// connection pools are stored in `HttpConnectionPools` using this key. a plain upstream is
+    /// keyed by its `addr`, so code that looks up pools by addr keeps working, and a TLS upstream
+    /// on the same addr gets its own pool.
+    pub fn pool_key(&self) -> String {
+        match (&self.tls, self.h2c) {
+            (Some(tls), _) => format!("https://{};{}", self.addr, tls.key()),
+            (None, true) => format!("h2c://{}", self.addr),
+            (None, false) => self.addr.to_string(),
+        }
+    }
+}
+
+impl From<String> for HttpUpstream {
+    fn from(addr: String) -> Self {
+        Self {
+            addr: addr.into(),
+            tls: None,
+            h2c: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        }
+    }
+}
+
+impl From<&str> for HttpUpstream {
+    fn from(addr: &str) -> Self {
+        addr.to_string().into()
+    }
+}
+
+impl std::fmt::Display for HttpUpstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.tls, self.h2c) {
+            (Some(_), _) => write!(f, "https://{}", self.addr),
+            (None, true) => write!(f, "h2c://{}", self.addr),
+            (None, false) => write!(f, "http://{}", self.addr),
+        }
+    }
+}
+
+/// HttpConnection is a connection to the upstream, the pool keeps these.
+pub enum HttpConnection {
+    Http1(hyper::client::conn::http1::SendRequest<HttpBody>),
+    Http2(hyper::client::conn::http2::SendRequest<HttpBody>),
+}
+
+pub type HttpBody = http_body_util::combinators::BoxBody<hyper::body::Bytes, eyre::Error>;
+
+impl HttpConnection {
+    pub async fn send_request(
+        &mut self,
+        req: hyper::Request<HttpBody>,
+    ) -> hyper::Result<hyper::Response<hyper::body::Incoming>> {
+        match self {
+            HttpConnection::Http1(c) => c.send_request(req).await,
+            HttpConnection::Http2(c) => c.send_request(req).await,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        match self {
+            HttpConnection::Http1(c) => c.is_closed(),
+            HttpConnection::Http2(c) => c.is_closed(),
+        }
+    }
+
+    /// an http1 connection whose response we stopped waiting for (the read timeout) still has
+    /// the request in flight, and can not take another one till the upstream answers. http2
+    /// connections carry many requests at once, so they are never busy this way.
+    pub fn has_request_in_flight(&self) -> bool {
+        match self {
+            HttpConnection::Http1(c) => !c.is_closed() && !c.is_ready(),
+            HttpConnection::Http2(_) => false,
+        }
+    }
+}
+
 pub struct HttpConnectionManager {
-    addr: String,
+    upstream: HttpUpstream,
 }
 
 impl HttpConnectionManager {
-    pub fn new(addr: String) -> Self {
-        Self { addr }
+    pub fn new(upstream: impl Into<HttpUpstream>) -> Self {
+        Self {
+            upstream: upstream.into(),
+        }
     }
 
-    pub async fn connect(
-        &self,
-    ) -> eyre::Result<
-        hyper::client::conn::http1::SendRequest<
-            http_body_util::combinators::BoxBody<hyper::body::Bytes, eyre::Error>,
-        >,
-    > {
+    /// connect() gives up after `HttpUpstream.connect_timeout`, the error can be downcast to
+    /// `tokio::time::error::Elapsed` in that case.
+    pub async fn connect(&self) -> eyre::Result<HttpConnection> {
         use eyre::WrapErr;
 
-        let stream = tokio::net::TcpStream::connect(&self.addr)
+        tokio::time::timeout(self.upstream.connect_timeout, self.connect_())
             .await
-            .wrap_err_with(|| "failed to open tcp connection")?;
-        let io = hyper_util::rt::TokioIo::new(stream);
+            .wrap_err_with(|| format!("timed out connecting to {}", self.upstream))?
+    }
 
-        let (sender, conn) = hyper::client::conn::http1::handshake(io)
-            .await
-            .wrap_err_with(|| "failed to do http1 handshake")?;
-        tokio::task::spawn(async move {
-            if let Err(err) = conn.await.wrap_err_with(|| "connection failed") {
-                tracing::error!("Connection failed: {err:?}");
+    async fn connect_(&self) -> eyre::Result<HttpConnection> {
+        use eyre::WrapErr;
+
+        match &self.upstream.addr {
+            UpstreamAddr::Tcp(addr) => {
+                let stream = tokio::net::TcpStream::connect(addr)
+                    .await
+                    .wrap_err_with(|| "failed to open tcp connection")?;
+                self.handshake(addr, stream).await
+            }
+            #[cfg(unix)]
+            UpstreamAddr::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path)
+                    .await
+                    .wrap_err_with(|| format!("failed to connect to {path:?}"))?;
+                // there is no host to verify the certificate against, pass --sni if the
+                // certificate is not for localhost.
+                self.handshake("localhost", stream).await
             }
-        });
+            #[cfg(not(unix))]
+            UpstreamAddr::Unix(_) => Err(eyre::anyhow!(
+                "unix sockets are not supported on this platform"
+            )),
+        }
+    }
 
-        Ok(sender)
+    async fn handshake<IO>(&self, addr: &str, stream: IO) -> eyre::Result<HttpConnection>
+    where
+        IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        match (&self.upstream.tls, self.upstream.h2c) {
+            (Some(tls), _) => handshake_http1(tls.connect(addr, stream).await?).await,
+            (None, true) => handshake_h2c(stream).await,
+            (None, false) => handshake_http1(stream).await,
+        }
     }
 }
 
+async fn handshake_h2c<IO>(stream: IO) -> eyre::Result<HttpConnection>
+where
+    IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use eyre::WrapErr;
+
+    let io = hyper_util::rt::TokioIo::new(stream);
+
+    let (sender, conn) =
+        hyper::client::conn::http2::handshake(hyper_util::rt::TokioExecutor::new(), io)
+            .await
+            .wrap_err_with(|| "failed to do http2 handshake")?;
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.await.wrap_err_with(|| "connection failed") {
+            tracing::error!("Connection failed: {err:?}");
+        }
+    });
+
+    Ok(HttpConnection::Http2(sender))
+}
+
+async fn handshake_http1<IO>(stream: IO) -> eyre::Result<HttpConnection>
+where
+    IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use eyre::WrapErr;
+
+    let io = hyper_util::rt::TokioIo::new(stream);
+
+    let (sender, conn) = hyper::client::conn::http1::handshake(io)
+        .await
+        .wrap_err_with(|| "failed to do http1 handshake")?;
+    tokio::task::spawn(async move {
+        // with_upgrades() so a connection whose request got `101 Switching Protocols` can be
+        // taken over with `hyper::upgrade::on()`.
+        if let Err(err) = conn
+            .with_upgrades()
+            .await
+            .wrap_err_with(|| "connection failed")
+        {
+            tracing::error!("Connection failed: {err:?}");
+        }
+    });
+
+    Ok(HttpConnection::Http1(sender))
+}
+
 impl bb8::ManageConnection for HttpConnectionManager {
-    type Connection = hyper::client::conn::http1::SendRequest<
-        http_body_util::combinators::BoxBody<hyper::body::Bytes, eyre::Error>,
-    >;
+    type Connection = HttpConnection;
     type Error = eyre::Error;
 
     fn connect(&self) -> impl Future<Output = Result<Self::Connection, Self::Error>> + Send {
@@ -61,7 +250,9 @@ impl bb8::ManageConnection for HttpConnectionManager {
         })
     }
 
+    /// checked when a connection goes back to the pool, one with a request in flight is closed
+    /// instead
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        conn.is_closed()
+        conn.is_closed() || conn.has_request_in_flight()
     }
 }
@@ -1,43 +1,95 @@
+/// forwards `req` to the peer, the request body is usually a `hyper::body::Incoming`, but can be
+/// any body, for requests we make ourselves
 #[tracing::instrument(skip_all)]
-pub async fn http_to_peer(
+pub async fn http_to_peer<B>(
     header: crate::ProtocolHeader,
-    req: hyper::Request<hyper::body::Incoming>,
+    mut req: hyper::Request<B>,
     self_endpoint: iroh::Endpoint,
     remote_node_id52: &str,
     peer_connections: crate::PeerStreamSenders,
     graceful: crate::Graceful,
-) -> crate::http::ProxyResult<eyre::Error> {
+) -> crate::http::ProxyResult<eyre::Error>
+where
+    B: hyper::body::Body<Data = hyper::body::Bytes> + Unpin + Send + 'static,
+    B::Error: std::fmt::Debug + Send,
+{
     use http_body_util::BodyExt;
 
     tracing::info!("peer_proxy: {remote_node_id52}");
 
-    let (mut send, mut recv) = crate::get_stream(
+    // has to be called before we take the request apart, it resolves once we have responded with
+    // `101 Switching Protocols`.
+    let on_upgrade = crate::http::is_upgrade(req.headers()).then(|| hyper::upgrade::on(&mut req));
+
+    // errors reaching the peer are reported to the client, errors from the upstream are already
+    // turned into responses by the peer.
+    let accept = req.headers().get(hyper::header::ACCEPT).cloned();
+
+    let (mut send, mut recv) = match crate::get_stream(
         self_endpoint,
         header,
         remote_node_id52.to_string(),
         peer_connections.clone(),
-        graceful,
+        graceful.clone(),
     )
-    .await?;
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("failed to reach {remote_node_id52}: {e:?}");
+            let error = e
+                .downcast_ref::<crate::http::ProxyError>()
+                .copied()
+                .unwrap_or(crate::http::ProxyError::PeerUnreachable);
+            return Ok(error.response(accept.as_ref()));
+        }
+    };
 
     tracing::info!("wrote protocol");
 
+    // upgraded connections are piped as is, framing only makes sense for regular requests
+    let framed = on_upgrade.is_none() && crate::http::wants_trailers(req.headers());
     let (head, mut body) = req.into_parts();
-    send.write_all(&serde_json::to_vec(&crate::http::Request::from(head))?)
-        .await?;
+    let request = crate::http::Request {
+        framed,
+        ..crate::http::Request::from(head)
+    };
+    send.write_all(&serde_json::to_vec(&request)?).await?;
     send.write_all(b"\n").await?;
 
     tracing::info!("sent request header");
 
+    if request.framed {
+        // the request body is sent while we read the response, as gRPC can stream both ways
+        graceful.spawn(async move {
+            if let Err(e) = crate::body_frames::write_body(body, &mut send).await {
+                tracing::error!("failed to send request body: {e:?}");
+            }
+        });
+
+        let r: crate::http::Response = match crate::next_json(&mut recv).await {
+            Ok(v) => v,
+            Err(e) => return Ok(peer_failed(remote_node_id52, e, accept.as_ref())),
+        };
+        tracing::info!("got response header: {:?}", r);
+
+        if !r.framed {
+            return Err(eyre::anyhow!("peer does not support framed bodies"));
+        }
+
+        return Ok(response_builder(&r)?.body(crate::body_frames::read_body(recv))?);
+    }
+
     while let Some(chunk) = body.frame().await {
         match chunk {
-            Ok(v) => {
-                let data = v
-                    .data_ref()
-                    .ok_or_else(|| eyre::anyhow!("chunk data is None"))?;
-                tracing::trace!("sending chunk of size: {}", data.len());
-                send.write_all(data).await?;
-            }
+            Ok(v) => match v.data_ref() {
+                Some(data) => {
+                    tracing::trace!("sending chunk of size: {}", data.len());
+                    send.write_all(data).await?;
+                }
+                // the raw body has no way to carry trailers
+                None => tracing::warn!("dropping request trailers, the request is not framed"),
+            },
             Err(e) => {
                 tracing::error!("error reading chunk: {e:?}");
                 return Err(eyre::anyhow!("read_chunk error: {e:?}"));
@@ -47,10 +99,37 @@ pub async fn http_to_peer(
 
     tracing::info!("sent body");
 
-    let r: crate::http::Response = crate::next_json(&mut recv).await?;
+    let r: crate::http::Response = match crate::next_json(&mut recv).await {
+        Ok(v) => v,
+        Err(e) => return Ok(peer_failed(remote_node_id52, e, accept.as_ref())),
+    };
 
     tracing::info!("got response header: {:?}", r);
 
+    let res = response_builder(&r)?;
+
+    if let Some(on_upgrade) = on_upgrade
+        && r.status == hyper::http::StatusCode::SWITCHING_PROTOCOLS
+    {
+        tracing::info!("peer switched protocols, piping upgraded connection");
+        graceful.spawn(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(v) => hyper_util::rt::TokioIo::new(v),
+                Err(e) => {
+                    tracing::error!("failed to upgrade connection: {e:?}");
+                    return;
+                }
+            };
+            let (tcp_recv, tcp_send) = tokio::io::split(upgraded);
+            if let Err(e) = crate::pipe_tcp_stream_over_iroh(tcp_recv, tcp_send, send, recv).await {
+                tracing::error!("failed to pipe upgraded connection: {e:?}");
+            }
+        });
+
+        let body = http_body_util::Empty::new().map_err(|e| match e {}).boxed();
+        return Ok(res.body(body)?);
+    }
+
     let stream = tokio_util::io::ReaderStream::new(recv);
 
     use futures_util::TryStreamExt;
@@ -69,18 +148,31 @@ pub async fn http_to_peer(
 
     let boxed_body = http_body_util::BodyExt::boxed(stream_body);
 
+    let res = res.body(boxed_body)?;
+
+    tracing::info!("all done");
+    Ok(res)
+}
+
+fn peer_failed(
+    remote_node_id52: &str,
+    e: eyre::Report,
+    accept: Option<&hyper::header::HeaderValue>,
+) -> crate::http::ProxyResponse<eyre::Error> {
+    tracing::error!("no response from {remote_node_id52}: {e:?}");
+    crate::http::ProxyError::PeerFailed.response(accept)
+}
+
+fn response_builder(r: &crate::http::Response) -> eyre::Result<hyper::http::response::Builder> {
     let mut res = hyper::Response::builder().status(hyper::http::StatusCode::from_u16(r.status)?);
 
-    for (k, v) in r.headers {
+    for (k, v) in &r.headers {
         res = res.header(
             hyper::http::header::HeaderName::from_bytes(k.as_bytes())?,
-            hyper::http::header::HeaderValue::from_bytes(&v)?,
+            hyper::http::header::HeaderValue::from_bytes(v)?,
         );
     }
 
-    let res = res.body(boxed_body)?;
-
-    tracing::info!("all done");
     Ok(res)
 }
 
@@ -1,5 +1,7 @@
 extern crate self as kulfi_utils;
 
+mod access_log;
+mod body_frames;
 pub mod dot_kulfi;
 pub mod get_endpoint;
 mod get_stream;
@@ -7,30 +9,47 @@ mod graceful;
 pub mod http;
 mod http_connection_manager;
 mod http_to_peer;
+pub mod metrics;
 mod peer_to_http;
 mod ping;
 pub mod protocol;
 mod secret;
 mod tcp;
+pub mod time;
+pub mod udp;
+mod upstream_tls;
 mod utils;
 mod utils_iroh;
 
+pub use access_log::{AccessLog, AccessLogEntry, AccessLogFormat, AccessLogSink, FileAccessLog};
 pub use get_endpoint::get_endpoint;
-pub use get_stream::{PeerStreamSenders, get_stream};
+pub use get_stream::{PeerStreamSenders, get_stream, get_stream_with_connection};
 pub use graceful::Graceful;
 pub use http::ProxyResult;
-pub use http_connection_manager::{HttpConnectionManager, HttpConnectionPool, HttpConnectionPools};
+pub use http_connection_manager::{
+    DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT, HttpBody, HttpConnection, HttpConnectionManager,
+    HttpConnectionPool, HttpConnectionPools, HttpUpstream, UpstreamAddr,
+};
 pub use http_to_peer::{http_to_peer, http_to_peer_non_streaming};
-pub use peer_to_http::peer_to_http;
+pub use peer_to_http::{
+    PEER_ID52_HEADER, PEER_SIGNATURE_HEADER, PEER_TIMESTAMP_HEADER, PeerToHttpOptions,
+    peer_id52_signature_message, peer_to_http, verify_peer_id52_signature,
+};
 pub use ping::{PONG, ping};
 pub use protocol::{APNS_IDENTITY, Protocol, ProtocolHeader};
 pub use secret::{
-    SECRET_KEY_FILE, generate_and_save_key, generate_secret_key, get_secret_key, read_or_create_key,
+    SECRET_KEY_FILE, generate_and_save_key, generate_secret_key, get_secret_key, read_key_file,
+    read_or_create_key,
 };
+#[cfg(unix)]
+pub use tcp::peer_to_unix;
 pub use tcp::{peer_to_tcp, pipe_tcp_stream_over_iroh, tcp_to_peer};
+pub use udp::peer_to_udp;
+pub use upstream_tls::UpstreamTls;
 pub use utils::mkdir;
 pub use utils_iroh::{
     accept_bi, accept_bi_with, get_remote_id52, global_iroh_endpoint, next_json, next_string,
+    open_bi, set_global_identity,
 };
 
 // Deprecated helper functions - use kulfi_id52 directly
@@ -48,3 +67,9 @@ pub use utils::{id52_to_public_key, public_key_to_id52};
 pub type IDMap = std::sync::Arc<tokio::sync::Mutex<Vec<(String, (u16, iroh::endpoint::Endpoint))>>>;
 
 pub const ACK: &str = "ack";
+/// sent instead of `ACK` when the peer does not serve the protocol asked for
+pub const REFUSED: &str = "refused";
+
+/// how many requests from a single peer connection are served at the same time, the rest wait for
+/// their turn.
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 64;
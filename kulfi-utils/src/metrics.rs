@@ -0,0 +1,438 @@
+//! counters, gauges and histograms about the connections and requests we handle, served in the
+//! prometheus text format by `malai <command> --metrics-addr`.
+//!
+//! we only need a handful of metrics, so instead of pulling in a metrics library we keep them in a
+//! global registry here. every metric we record is listed in `METRICS`, with its help text.
+//!
+//! the exposing side records requests when they are done, in `AccessLog::log()`, so the numbers
+//! match the access log. bridges and proxies count the connections they accept from clients, and
+//! the bytes that go through them.
+
+const METRICS: &[(&str, Kind, &str)] = &[
+    (
+        "kulfi_client_connections_total",
+        Kind::Counter,
+        "Connections accepted by a bridge or proxy.",
+    ),
+    (
+        "kulfi_client_connections_active",
+        Kind::Gauge,
+        "Connections to a bridge or proxy that are open right now.",
+    ),
+    (
+        "kulfi_client_bytes_total",
+        Kind::Counter,
+        "Bytes received from (in) or sent to (out) the clients of a bridge or proxy.",
+    ),
+    (
+        "kulfi_peer_connections_total",
+        Kind::Counter,
+        "Connections accepted from peers.",
+    ),
+    (
+        "kulfi_peer_connections_active",
+        Kind::Gauge,
+        "Connections with each peer that are open right now.",
+    ),
+    (
+        "kulfi_get_stream_connects_total",
+        Kind::Counter,
+        "Connections opened to a peer to get a stream, the first one or after the previous one \
+         broke or went idle.",
+    ),
+    (
+        "kulfi_streams_total",
+        Kind::Counter,
+        "Streams accepted from (in) or opened to (out) peers, by protocol.",
+    ),
+    (
+        "kulfi_request_duration_seconds",
+        Kind::Histogram,
+        "How long requests, or TCP streams, took to serve.",
+    ),
+    (
+        "kulfi_bytes_total",
+        Kind::Counter,
+        "Bytes received from (in) or sent to (out) peers by the exposing side, request and \
+         response bodies for HTTP.",
+    ),
+    (
+        "kulfi_proxy_errors_total",
+        Kind::Counter,
+        "Requests answered with an error page by kulfi instead of the service, by the x-kulfi-error code.",
+    ),
+    (
+        "kulfi_bridge_cache_total",
+        Kind::Counter,
+        "Requests to a bridge with a cache, by how the cache answered them, the x-kulfi-cache value.",
+    ),
+];
+
+/// the upper bounds of the duration histogram buckets, in seconds
+const BUCKETS: [f64; 13] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// the metric name and its labels
+type Series = (&'static str, Vec<(&'static str, String)>);
+
+#[derive(Default)]
+struct Registry {
+    values: std::collections::BTreeMap<Series, i64>,
+    histograms: std::collections::BTreeMap<Series, Histogram>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// the bytes through every client connection, counted on each read and write. these are kept out
+/// of the registry, so the hot path is an atomic add instead of a lock and a label allocation.
+static CLIENT_BYTES_IN: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+static CLIENT_BYTES_OUT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// the counters that live outside the registry: name, label and the value of the label
+static ATOMIC_COUNTERS: [(&str, (&str, &str), &std::sync::atomic::AtomicU64); 2] = [
+    (
+        "kulfi_client_bytes_total",
+        ("direction", "in"),
+        &CLIENT_BYTES_IN,
+    ),
+    (
+        "kulfi_client_bytes_total",
+        ("direction", "out"),
+        &CLIENT_BYTES_OUT,
+    ),
+];
+
+static REGISTRY: std::sync::LazyLock<std::sync::Mutex<Registry>> =
+    std::sync::LazyLock::new(Default::default);
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    // a panic while holding the lock can not leave the registry in a bad state, at worst one
+    // number was not updated
+    REGISTRY
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+fn series(name: &'static str, labels: &[(&'static str, &str)]) -> Series {
+    (
+        name,
+        labels.iter().map(|(k, v)| (*k, v.to_string())).collect(),
+    )
+}
+
+fn add(name: &'static str, labels: &[(&'static str, &str)], n: i64) {
+    let mut registry = registry();
+    let key = series(name, labels);
+    let value = registry.values.entry(key.clone()).or_default();
+    *value += n;
+
+    // gauges with a label per peer would grow forever, so we forget the ones that drop to zero
+    if *value == 0 && labels.iter().any(|(k, _)| *k == "peer") {
+        registry.values.remove(&key);
+    }
+}
+
+fn observe(name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+    let mut registry = registry();
+    let h = registry.histograms.entry(series(name, labels)).or_default();
+    for (bucket, le) in h.buckets.iter_mut().zip(BUCKETS) {
+        if value <= le {
+            *bucket += 1;
+        }
+    }
+    h.count += 1;
+    h.sum += value;
+}
+
+fn protocol_label(protocol: crate::Protocol) -> String {
+    // same as the access log, `Http`, `Tcp` etc.
+    format!("{protocol:?}")
+}
+
+/// a stream was accepted from a peer (`incoming`), or opened to one
+pub fn stream(protocol: crate::Protocol, incoming: bool) {
+    let direction = if incoming { "in" } else { "out" };
+    add(
+        "kulfi_streams_total",
+        &[
+            ("protocol", &protocol_label(protocol)),
+            ("direction", direction),
+        ],
+        1,
+    );
+}
+
+/// a request (or TCP stream) was served, called for every access log entry
+pub fn request_served(entry: &crate::AccessLogEntry) {
+    let protocol = protocol_label(entry.protocol);
+    observe(
+        "kulfi_request_duration_seconds",
+        &[("protocol", &protocol)],
+        entry.duration.as_secs_f64(),
+    );
+    for (direction, n) in [("in", entry.bytes_in), ("out", entry.bytes_out)] {
+        add(
+            "kulfi_bytes_total",
+            &[("protocol", &protocol), ("direction", direction)],
+            n as i64,
+        );
+    }
+}
+
+/// a request was forwarded to a peer by a bridge or proxy
+pub fn request_forwarded(protocol: crate::Protocol, duration: std::time::Duration) {
+    observe(
+        "kulfi_request_duration_seconds",
+        &[("protocol", &protocol_label(protocol))],
+        duration.as_secs_f64(),
+    );
+}
+
+pub fn proxy_error(error: crate::http::ProxyError) {
+    add("kulfi_proxy_errors_total", &[("error", error.code())], 1);
+}
+
+/// a bridge with a cache answered a request, `result` is `hit`, `miss` etc.
+pub fn bridge_cache(result: &str) {
+    add("kulfi_bridge_cache_total", &[("result", result)], 1);
+}
+
+/// get_stream() tried to connect to a peer, `ok` tells if it could
+pub fn get_stream_connect(ok: bool) {
+    let result = if ok { "ok" } else { "error" };
+    add("kulfi_get_stream_connects_total", &[("result", result)], 1);
+}
+
+/// ActivePeer counts a connection with a peer as active till it is dropped
+pub struct ActivePeer(String);
+
+impl ActivePeer {
+    /// for connections we accepted, `get_stream()` uses `opened()`
+    pub fn accepted(remote_id52: &str) -> Self {
+        add("kulfi_peer_connections_total", &[], 1);
+        Self::opened(remote_id52)
+    }
+
+    pub fn opened(remote_id52: &str) -> Self {
+        add("kulfi_peer_connections_active", &[("peer", remote_id52)], 1);
+        Self(remote_id52.to_string())
+    }
+}
+
+impl Drop for ActivePeer {
+    fn drop(&mut self) {
+        add("kulfi_peer_connections_active", &[("peer", &self.0)], -1);
+    }
+}
+
+/// ClientConnection wraps a connection accepted by a bridge or proxy, it is counted as active till
+/// it is dropped, and the bytes read from and written to it are counted.
+pub struct ClientConnection<T> {
+    inner: T,
+}
+
+impl<T> ClientConnection<T> {
+    pub fn new(inner: T) -> Self {
+        add("kulfi_client_connections_total", &[], 1);
+        add("kulfi_client_connections_active", &[], 1);
+        Self { inner }
+    }
+}
+
+impl<T> Drop for ClientConnection<T> {
+    fn drop(&mut self) {
+        add("kulfi_client_connections_active", &[], -1);
+    }
+}
+
+impl<T: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for ClientConnection<T> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let r = std::pin::Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        CLIENT_BYTES_IN.fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
+        r
+    }
+}
+
+impl<T: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for ClientConnection<T> {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let r = std::pin::Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let std::task::Poll::Ready(Ok(n)) = r {
+            CLIENT_BYTES_OUT.fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
+        }
+        r
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// all the metrics, in the prometheus text exposition format
+pub fn render() -> String {
+    use std::fmt::Write;
+
+    let registry = registry();
+    let mut out = String::new();
+
+    for (name, kind, help) in METRICS {
+        let kind_name = match kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        };
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind_name}");
+
+        if *kind == Kind::Histogram {
+            for ((_, labels), h) in registry.histograms.iter().filter(|((n, _), _)| n == name) {
+                for (le, count) in BUCKETS.iter().zip(h.buckets) {
+                    let le = le.to_string();
+                    let _ = writeln!(
+                        out,
+                        "{name}_bucket{} {count}",
+                        format_labels(labels, Some(&le))
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{} {}",
+                    format_labels(labels, Some("+Inf")),
+                    h.count
+                );
+                let _ = writeln!(out, "{name}_sum{} {}", format_labels(labels, None), h.sum);
+                let _ = writeln!(
+                    out,
+                    "{name}_count{} {}",
+                    format_labels(labels, None),
+                    h.count
+                );
+            }
+            continue;
+        }
+
+        for (_, (k, v), value) in ATOMIC_COUNTERS.iter().filter(|(n, ..)| n == name) {
+            let labels = [(*k, v.to_string())];
+            let value = value.load(std::sync::atomic::Ordering::Relaxed);
+            let _ = writeln!(out, "{name}{} {value}", format_labels(&labels, None));
+        }
+
+        for ((_, labels), value) in registry.values.iter().filter(|((n, _), _)| n == name) {
+            let _ = writeln!(out, "{name}{} {value}", format_labels(labels, None));
+        }
+    }
+
+    out
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut parts: Vec<_> = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{k}=\"{v}\"")
+        })
+        .collect();
+    if let Some(le) = le {
+        parts.push(format!("le=\"{le}\""));
+    }
+
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_render() {
+        {
+            let _peer = super::ActivePeer::accepted("test-peer");
+            super::stream(crate::Protocol::Tcp, true);
+            super::observe(
+                "kulfi_request_duration_seconds",
+                &[("protocol", "Test")],
+                0.3,
+            );
+
+            let out = super::render();
+            assert!(out.contains("# TYPE kulfi_streams_total counter\n"));
+            assert!(out.contains("kulfi_peer_connections_active{peer=\"test-peer\"} 1\n"));
+            assert!(out.contains(
+                "kulfi_request_duration_seconds_bucket{protocol=\"Test\",le=\"0.25\"} 0\n"
+            ));
+            assert!(out.contains(
+                "kulfi_request_duration_seconds_bucket{protocol=\"Test\",le=\"0.5\"} 1\n"
+            ));
+            assert!(out.contains("kulfi_request_duration_seconds_count{protocol=\"Test\"} 1\n"));
+        }
+
+        // the peer is gone once the connection is dropped
+        assert!(!super::render().contains("test-peer"));
+    }
+
+    #[tokio::test]
+    async fn test_client_bytes() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (a, mut b) = tokio::io::duplex(64);
+        let mut conn = super::ClientConnection::new(a);
+
+        conn.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        b.read_exact(&mut buf).await.unwrap();
+        b.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        conn.read_exact(&mut buf).await.unwrap();
+
+        let value = |direction: &str| -> u64 {
+            let prefix = format!("kulfi_client_bytes_total{{direction=\"{direction}\"}} ");
+            super::render()
+                .lines()
+                .find_map(|l| l.strip_prefix(&prefix))
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        // other tests may be counting as well
+        assert!(value("out") >= 5);
+        assert!(value("in") >= 2);
+    }
+}
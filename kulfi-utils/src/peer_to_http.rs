@@ -1,68 +1,307 @@
+/// the id52 of the peer that sent the request, added by the exposing side
+pub const PEER_ID52_HEADER: &str = "x-kulfi-peer-id52";
+/// unix timestamp (in seconds) at which the exposing side signed the request
+pub const PEER_TIMESTAMP_HEADER: &str = "x-kulfi-peer-timestamp";
+/// hex encoded ed25519 signature, see `peer_id52_signature_message()`
+pub const PEER_SIGNATURE_HEADER: &str = "x-kulfi-peer-signature";
+
+/// PeerToHttpOptions controls what the exposing side tells the upstream HTTP service about the
+/// peer that sent the request.
+///
+/// the peer headers sent by the remote peer are always removed, so the upstream can trust them if
+/// it knows it is only reachable via kulfi. if the upstream is reachable some other way as well,
+/// it should verify the signature against the id52 of the exposer instead.
+#[derive(Clone, Default)]
+pub struct PeerToHttpOptions {
+    /// add `X-Kulfi-Peer-Id52` to every request
+    pub forward_peer_id52: bool,
+    /// if set, also add `X-Kulfi-Peer-Timestamp` and `X-Kulfi-Peer-Signature`, signed with this key
+    pub signing_key: Option<std::sync::Arc<kulfi_id52::SecretKey>>,
+    /// every request is recorded here
+    pub access_log: crate::AccessLog,
+    /// if not empty, the `Forwarded` and `X-Forwarded-*` headers set by a bridge are only passed
+    /// to the upstream for requests coming from these id52s. any peer can set them, so without
+    /// this the upstream should not rely on them for anything that matters.
+    pub trusted_bridges: Vec<String>,
+}
+
+/// the message that gets signed, the fields are joined by newlines so none of them can be used to
+/// forge another.
+pub fn peer_id52_signature_message(
+    peer_id52: &str,
+    timestamp: u64,
+    method: &str,
+    uri: &str,
+) -> String {
+    format!("{peer_id52}\n{timestamp}\n{}\n{uri}", method.to_uppercase())
+}
+
+/// verify_peer_id52_signature() can be used by the upstream service, `exposer` is the id52 of the
+/// `malai http` (or kulfi) instance that forwarded the request. checking that the timestamp is
+/// recent enough is left to the caller.
+pub fn verify_peer_id52_signature(
+    exposer: &str,
+    peer_id52: &str,
+    timestamp: u64,
+    method: &str,
+    uri: &str,
+    signature: &str,
+) -> eyre::Result<()> {
+    use std::str::FromStr;
+
+    let exposer =
+        kulfi_id52::PublicKey::from_str(exposer).map_err(|e| eyre::anyhow!("{exposer}: {e}"))?;
+    let signature: [u8; 64] = data_encoding::HEXLOWER_PERMISSIVE
+        .decode(signature.as_bytes())?
+        .try_into()
+        .map_err(|_| eyre::anyhow!("signature must be 64 bytes"))?;
+    let signature = kulfi_id52::Signature::from_bytes(&signature)?;
+
+    exposer.verify(
+        peer_id52_signature_message(peer_id52, timestamp, method, uri).as_bytes(),
+        &signature,
+    )?;
+
+    Ok(())
+}
+
+fn is_peer_header(name: &str) -> bool {
+    [
+        PEER_ID52_HEADER,
+        PEER_TIMESTAMP_HEADER,
+        PEER_SIGNATURE_HEADER,
+    ]
+    .iter()
+    .any(|h| h.eq_ignore_ascii_case(name))
+}
+
 pub async fn peer_to_http(
-    addr: &str,
+    upstream: &crate::HttpUpstream,
+    remote_id52: &str,
+    options: &PeerToHttpOptions,
     client_pools: crate::HttpConnectionPools,
     send: &mut iroh::endpoint::SendStream,
     mut recv: iroh::endpoint::RecvStream,
 ) -> eyre::Result<()> {
-    use eyre::WrapErr;
-    use http_body_util::BodyExt;
-
-    tracing::info!("http request with {addr}");
+    tracing::info!("http request with {upstream}");
     let start = std::time::Instant::now();
 
     let req: crate::http::Request = crate::next_json(&mut recv).await?;
 
     tracing::info!("got request: {req:?}");
 
+    let header = |name: &str| {
+        req.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| String::from_utf8_lossy(v).into_owned())
+    };
+    let mut entry = options.access_log.entry(remote_id52, &req.uri);
+    entry.method = Some(req.method.to_uppercase());
+    entry.version = Some(
+        req.version
+            .clone()
+            .unwrap_or_else(|| "HTTP/1.1".to_string()),
+    );
+    entry.referer = header("referer");
+    entry.user_agent = header("user-agent");
+
+    let bytes_in = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let recv = crate::access_log::Counted::new(recv, bytes_in.clone());
+
+    let r = serve_request(
+        upstream,
+        options,
+        client_pools,
+        send,
+        recv,
+        &req,
+        &mut entry,
+    )
+    .await;
+
+    entry.bytes_in = bytes_in.load(std::sync::atomic::Ordering::Relaxed);
+    let status = entry.status;
+    options.access_log.log(entry);
+
+    tracing::info!("handled http request in {:?}", start.elapsed());
+
+    {
+        use colored::Colorize;
+        println!(
+            "{} {} {} in {}",
+            req.method.to_uppercase().green(),
+            req.uri,
+            status
+                .map_or_else(|| "-".to_string(), |v| v.to_string())
+                .on_blue()
+                .black(),
+            format!("{}ms", start.elapsed().as_millis()).yellow()
+        );
+    }
+
+    r
+}
+
+async fn serve_request(
+    upstream: &crate::HttpUpstream,
+    options: &PeerToHttpOptions,
+    client_pools: crate::HttpConnectionPools,
+    send: &mut iroh::endpoint::SendStream,
+    recv: crate::access_log::Counted<iroh::endpoint::RecvStream>,
+    req: &crate::http::Request,
+    entry: &mut crate::AccessLogEntry,
+) -> eyre::Result<()> {
+    use eyre::WrapErr;
+    use http_body_util::BodyExt;
+
+    let remote_id52 = entry.remote_id52.as_str();
+
+    let trusted = options.trusted_bridges.is_empty()
+        || options.trusted_bridges.iter().any(|v| v == remote_id52);
+
     let mut r = hyper::Request::builder()
         .method(req.method.as_str())
         .uri(&req.uri);
-    for (name, value) in req.headers {
-        r = r.header(name, value);
+    for (name, value) in &req.headers {
+        if is_peer_header(name) {
+            tracing::warn!(remote_id52, "dropping {name} header sent by peer");
+            continue;
+        }
+        if !trusted
+            && crate::http::FORWARDED_HEADERS
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name))
+        {
+            tracing::debug!(remote_id52, "dropping {name} header sent by untrusted peer");
+            continue;
+        }
+        r = r.header(name.as_str(), value.as_slice());
+    }
+    if let Some(headers) = r.headers_mut() {
+        crate::http::remove_hop_by_hop_headers(headers);
+    }
+
+    if options.forward_peer_id52 {
+        r = r.header(PEER_ID52_HEADER, remote_id52);
+    }
+
+    if let Some(key) = &options.signing_key {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        let message = peer_id52_signature_message(remote_id52, timestamp, &req.method, &req.uri);
+        r = r.header(PEER_TIMESTAMP_HEADER, timestamp).header(
+            PEER_SIGNATURE_HEADER,
+            data_encoding::HEXLOWER.encode(&key.sign(message.as_bytes()).to_bytes()),
+        );
     }
 
     tracing::debug!("request: {r:?}");
 
-    let pool = get_pool(addr, client_pools).await?;
-    tracing::trace!("got pool");
-    let mut client = match pool.get().await {
-        Ok(v) => v,
-        Err(e) => {
-            tracing::error!("failed to get connection: {e:?}");
-            return Err(eyre::anyhow!("failed to get connection: {e:?}"));
-        }
-    };
-    // tracing::info!("got client");
-
-    use futures_util::TryStreamExt;
-    let stream = tokio_util::io::ReaderStream::new(recv);
-    let stream_body = http_body_util::StreamBody::new(
-        stream
-            .map_ok(|b| {
-                tracing::trace!("got chunk of size: {}", b.len());
-                hyper::body::Frame::data(b)
-            })
-            .map_err(|e| {
-                tracing::info!("error reading chunk: {e:?}");
-                eyre::anyhow!("read_chunk error: {e:?}")
-            }),
-    );
+    let upgrade = r.headers_ref().is_some_and(crate::http::is_upgrade);
+
+    let pool = get_pool(upstream, client_pools).await?;
+    let mut pooled: Option<bb8::PooledConnection<crate::HttpConnectionManager>> = None;
+
+    let (resp, recv) = if upgrade {
+        // an upgraded connection can not go back to the pool, so it gets a connection of its own.
+        // upgrade requests have no body, once the upstream switches protocols the rest of `recv`
+        // is piped to it as is.
+        tracing::info!("upgrade request");
+        let mut client = match crate::HttpConnectionManager::new(upstream.clone())
+            .connect()
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => return write_error(send, req, entry, connect_error(&e), e).await,
+        };
+        let body = http_body_util::Empty::new().map_err(|e| match e {}).boxed();
+        let resp = client.send_request(r.body(body)?);
+        (
+            tokio::time::timeout(upstream.read_timeout, resp).await,
+            Some(recv),
+        )
+    } else {
+        let client: &mut crate::HttpConnection = match pool.get().await {
+            Ok(v) => pooled.insert(v),
+            Err(bb8::RunError::User(e)) => {
+                return write_error(send, req, entry, connect_error(&e), e).await;
+            }
+            // bb8 only reports connect errors to its error sink and lets `get()` wait till it
+            // times out. if the pool has no connection at all, we could not make one, otherwise
+            // they are all busy with other requests.
+            Err(bb8::RunError::TimedOut) if pool.state().connections == 0 => {
+                let e = eyre::anyhow!(
+                    "could not connect to {upstream} in {:?}",
+                    upstream.connect_timeout
+                );
+                let error = crate::http::ProxyError::UpstreamUnreachable;
+                return write_error(send, req, entry, error, e).await;
+            }
+            Err(bb8::RunError::TimedOut) => {
+                let e = eyre::anyhow!("timed out waiting for a connection to {upstream}");
+                let error = crate::http::ProxyError::UpstreamTimeout;
+                return write_error(send, req, entry, error, e).await;
+            }
+        };
 
-    let boxed_body = http_body_util::BodyExt::boxed(stream_body);
+        let boxed_body = if req.framed {
+            crate::body_frames::read_body(recv)
+        } else {
+            use futures_util::TryStreamExt;
+            let stream = tokio_util::io::ReaderStream::new(recv);
+            let stream_body = http_body_util::StreamBody::new(
+                stream
+                    .map_ok(|b| {
+                        tracing::trace!("got chunk of size: {}", b.len());
+                        hyper::body::Frame::data(b)
+                    })
+                    .map_err(|e| {
+                        tracing::info!("error reading chunk: {e:?}");
+                        eyre::anyhow!("read_chunk error: {e:?}")
+                    }),
+            );
 
-    let (resp, mut body) = client
-        .send_request(r.body(boxed_body)?)
-        .await
-        .wrap_err_with(|| "failed to send request")?
-        .into_parts();
+            http_body_util::BodyExt::boxed(stream_body)
+        };
+
+        let resp = client.send_request(r.body(boxed_body)?);
+        (
+            tokio::time::timeout(upstream.read_timeout, resp).await,
+            None,
+        )
+    };
+
+    let mut resp = match resp {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            let e = eyre::Report::new(e).wrap_err("failed to send request");
+            return write_error(send, req, entry, crate::http::ProxyError::UpstreamFailed, e).await;
+        }
+        Err(_) => {
+            let e = eyre::anyhow!("{upstream} did not respond in {:?}", upstream.read_timeout);
+            return write_error(
+                send,
+                req,
+                entry,
+                crate::http::ProxyError::UpstreamTimeout,
+                e,
+            )
+            .await;
+        }
+    };
+    let status = resp.status();
+    entry.status = Some(status.as_u16());
 
     let r = crate::http::Response {
-        status: resp.status.as_u16(),
+        status: status.as_u16(),
         headers: resp
-            .headers
+            .headers()
             .iter()
             .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
             .collect(),
+        framed: req.framed,
     };
 
     send.write_all(
@@ -73,64 +312,201 @@ pub async fn peer_to_http(
     .await?;
     send.write_all(b"\n").await?;
 
-    tracing::debug!(
-        "got response body of size: {:?} bytes",
-        hyper::body::Body::size_hint(&body)
-    );
+    match recv {
+        Some(recv) if status == hyper::StatusCode::SWITCHING_PROTOCOLS => {
+            let upgraded = hyper::upgrade::on(&mut resp)
+                .await
+                .wrap_err_with(|| "failed to upgrade connection")?;
+            tracing::info!("upgraded connection to {upstream}");
+            entry.bytes_out = pipe_upgraded(upgraded, send, recv).await?;
+        }
+        _ if req.framed => {
+            let bytes_out = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+            let r = crate::body_frames::write_body(
+                resp.into_body(),
+                &mut crate::access_log::Counted::new(&mut *send, bytes_out.clone()),
+            )
+            .await;
+            entry.bytes_out = bytes_out.load(std::sync::atomic::Ordering::Relaxed);
+            r?
+        }
+        _ => {
+            let mut body = resp.into_body();
+            tracing::debug!(
+                "got response body of size: {:?} bytes",
+                hyper::body::Body::size_hint(&body)
+            );
 
-    while let Some(chunk) = body.frame().await {
-        match chunk {
-            Ok(v) => {
-                let data = v
-                    .data_ref()
-                    .ok_or_else(|| eyre::anyhow!("chunk data is None"))?;
-                tracing::trace!("sending chunk of size: {}", data.len());
-                send.write_all(data).await?;
-            }
-            Err(e) => {
-                tracing::error!("error reading chunk: {e:?}");
-                return Err(eyre::anyhow!("read_chunk error: {e:?}"));
+            while let Some(chunk) = body.frame().await {
+                match chunk {
+                    Ok(v) => match v.data_ref() {
+                        Some(data) => {
+                            tracing::trace!("sending chunk of size: {}", data.len());
+                            send.write_all(data).await?;
+                            entry.bytes_out += data.len() as u64;
+                        }
+                        // the raw body has no way to carry trailers
+                        None => tracing::warn!("dropping trailers, the request was not framed"),
+                    },
+                    Err(e) => {
+                        tracing::error!("error reading chunk: {e:?}");
+                        return Err(eyre::anyhow!("read_chunk error: {e:?}"));
+                    }
+                }
             }
         }
     }
 
-    tracing::info!("handled http request in {:?}", start.elapsed());
+    Ok(())
+}
 
-    {
-        use colored::Colorize;
-        println!(
-            "{} {} {} in {}",
-            req.method.to_uppercase().green(),
-            req.uri,
-            resp.status.as_str().on_blue().black(),
-            format!("{}ms", start.elapsed().as_millis()).yellow()
-        );
+fn connect_error(e: &eyre::Report) -> crate::http::ProxyError {
+    if e.downcast_ref::<tokio::time::error::Elapsed>().is_some() {
+        crate::http::ProxyError::UpstreamTimeout
+    } else {
+        crate::http::ProxyError::UpstreamUnreachable
     }
+}
 
-    Ok(())
+/// write_error() sends an error response in place of the one the upstream could not give us, so
+/// the bridge can tell the client what went wrong instead of dropping the connection. `e` is
+/// returned so the caller logs it like any other failure.
+async fn write_error(
+    send: &mut iroh::endpoint::SendStream,
+    req: &crate::http::Request,
+    entry: &mut crate::AccessLogEntry,
+    error: crate::http::ProxyError,
+    e: eyre::Report,
+) -> eyre::Result<()> {
+    let accept = req
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("accept"))
+        .map(|(_, v)| v.as_slice());
+    let (content_type, body) = error.body(accept);
+    crate::metrics::proxy_error(error);
+    entry.status = Some(error.status().as_u16());
+    entry.bytes_out = body.len() as u64;
+
+    let r = crate::http::Response {
+        status: error.status().as_u16(),
+        headers: vec![
+            ("content-type".to_string(), content_type.as_bytes().to_vec()),
+            (
+                crate::http::ERROR_CODE_HEADER.to_string(),
+                error.code().as_bytes().to_vec(),
+            ),
+        ],
+        framed: req.framed,
+    };
+
+    send.write_all(&serde_json::to_vec(&r)?).await?;
+    send.write_all(b"\n").await?;
+
+    if req.framed {
+        crate::body_frames::write_body(
+            http_body_util::Full::new(hyper::body::Bytes::from(body)),
+            send,
+        )
+        .await?;
+    } else {
+        send.write_all(&body).await?;
+    }
+
+    Err(e.wrap_err(error.code()))
+}
+
+/// pipes the upgraded upstream connection and the iroh stream till both sides are done, returns
+/// the number of bytes sent to the peer. the caller finishes `send`.
+async fn pipe_upgraded(
+    upgraded: hyper::upgrade::Upgraded,
+    send: &mut iroh::endpoint::SendStream,
+    mut recv: impl tokio::io::AsyncRead + Unpin,
+) -> eyre::Result<u64> {
+    use tokio::io::AsyncWriteExt;
+
+    let (mut upstream_recv, mut upstream_send) =
+        tokio::io::split(hyper_util::rt::TokioIo::new(upgraded));
+
+    let (_, bytes_out) = tokio::try_join!(
+        async {
+            tokio::io::copy(&mut recv, &mut upstream_send).await?;
+            upstream_send.shutdown().await
+        },
+        tokio::io::copy(&mut upstream_recv, send),
+    )?;
+
+    Ok(bytes_out)
 }
 
 async fn get_pool(
-    addr: &str,
+    upstream: &crate::HttpUpstream,
     client_pools: crate::HttpConnectionPools,
 ) -> eyre::Result<bb8::Pool<crate::HttpConnectionManager>> {
     tracing::trace!("get pool called");
     let mut pools = client_pools.lock().await;
+    let key = upstream.pool_key();
 
-    Ok(match pools.get(addr) {
+    Ok(match pools.get(&key) {
         Some(v) => {
-            tracing::debug!("found existing pool for {addr}");
+            tracing::debug!("found existing pool for {upstream}");
             v.clone()
         }
         None => {
-            tracing::debug!("creating new pool for {addr}");
+            tracing::debug!("creating new pool for {upstream}");
 
             let pool = bb8::Pool::builder()
-                .build(crate::HttpConnectionManager::new(addr.to_string()))
+                .connection_timeout(upstream.connect_timeout)
+                .error_sink(Box::new(ConnectErrors(upstream.to_string())))
+                .build(crate::HttpConnectionManager::new(upstream.clone()))
                 .await?;
 
-            pools.insert(addr.to_string(), pool.clone());
+            pools.insert(key, pool.clone());
             pool
         }
     })
 }
+
+/// ConnectErrors logs the errors of the connections the pool makes, `get()` only tells us it
+/// timed out.
+#[derive(Debug, Clone)]
+struct ConnectErrors(String);
+
+impl bb8::ErrorSink<eyre::Error> for ConnectErrors {
+    fn sink(&self, e: eyre::Error) {
+        tracing::warn!("failed to connect to {}: {e:?}", self.0);
+    }
+
+    fn boxed_clone(&self) -> Box<dyn bb8::ErrorSink<eyre::Error>> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_peer_id52_signature() {
+        let exposer = kulfi_id52::SecretKey::generate();
+        let peer = kulfi_id52::SecretKey::generate().id52();
+
+        let message = super::peer_id52_signature_message(&peer, 1700000000, "get", "/foo?a=b");
+        let signature =
+            data_encoding::HEXLOWER.encode(&exposer.sign(message.as_bytes()).to_bytes());
+
+        let verify = |method, uri, timestamp| {
+            super::verify_peer_id52_signature(
+                &exposer.id52(),
+                &peer,
+                timestamp,
+                method,
+                uri,
+                &signature,
+            )
+        };
+
+        assert!(verify("GET", "/foo?a=b", 1700000000).is_ok());
+        assert!(verify("POST", "/foo?a=b", 1700000000).is_err());
+        assert!(verify("GET", "/foo", 1700000000).is_err());
+        assert!(verify("GET", "/foo?a=b", 1700000001).is_err());
+    }
+}
@@ -80,12 +80,13 @@
 /// in future to webassembly, and JS engines have decent security sandbox. we do not allow npm/deno
 /// etc., and only run the most sandboxed, browser like JS code. fastn applications can also use
 /// webassembly compiled code, which again is sandboxed.
-#[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, PartialEq)]
 pub enum Protocol {
     /// client can send this message to check if the connection is open / healthy.
     Ping,
     /// client may not be using NTP, or may only have p2p access and no other internet access, in
-    /// which case it can ask for the time from the peers and try to create a consensus.
+    /// which case it can ask for the time from the peers and try to create a consensus. the peer
+    /// answers with its time, signed, as described in `time.rs`.
     WhatTimeIsIt,
     /// client wants to make an HTTP request to a device whose ID is specified. note that the exact
     /// ip:port is not known to peers, they only the "device id" for the service. server will figure
@@ -97,6 +98,17 @@ pub enum Protocol {
     /// to access it.
     Socks5,
     Tcp,
+    /// a TCP connection to one of the services shared by name with `malai tcp`, the name follows
+    /// the header as `extra`. a separate protocol from `Tcp`, so a peer that shares a single
+    /// service refuses it, instead of passing the name on to the service as the first bytes of
+    /// the connection.
+    TcpService,
+    /// the datagrams of one UDP flow, framed as described in `udp.rs`.
+    Udp,
+    /// client wants the server to listen on a port for it, and send every connection it gets on
+    /// that port back to the client, as a `Tcp` stream opened by the server on the same
+    /// connection. the tunnel lasts as long as the client keeps this stream open.
+    TcpReverse,
     // TODO: RTP/"RTCP" for audio video streaming
 }
 
@@ -31,6 +31,16 @@ fn handle_secret(secret: &str) -> eyre::Result<(String, kulfi_id52::SecretKey)>
     Ok((id52, secret_key))
 }
 
+/// reads a secret key written by `malai keygen --file`
+pub async fn read_key_file(
+    path: &std::path::Path,
+) -> eyre::Result<(String, kulfi_id52::SecretKey)> {
+    let secret = tokio::fs::read_to_string(path)
+        .await
+        .wrap_err_with(|| format!("failed to read {path:?}"))?;
+    handle_secret(secret.trim())
+}
+
 pub fn get_secret_key(_id52: &str, _path: &str) -> eyre::Result<kulfi_id52::SecretKey> {
     // intentionally left unimplemented as design is changing in kulfi
     // this is not used in malai
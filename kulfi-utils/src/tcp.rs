@@ -16,7 +16,7 @@ pub async fn peer_to_tcp(
     addr: &str,
     send: iroh::endpoint::SendStream,
     recv: iroh::endpoint::RecvStream,
-) -> eyre::Result<()> {
+) -> eyre::Result<(u64, u64)> {
     // todo: call identity server (fastn server running on behalf of identity
     //       /api/v1/identity/{id}/tcp/ with remote_id and id and get the ip:port
     //       to connect to.
@@ -26,24 +26,46 @@ pub async fn peer_to_tcp(
     pipe_tcp_stream_over_iroh(tcp_recv, tcp_send, send, recv).await
 }
 
+/// same as `peer_to_tcp()`, but for a service listening on a unix domain socket
+#[cfg(unix)]
+pub async fn peer_to_unix(
+    path: &std::path::Path,
+    send: iroh::endpoint::SendStream,
+    recv: iroh::endpoint::RecvStream,
+) -> eyre::Result<(u64, u64)> {
+    use eyre::WrapErr;
+
+    let stream = tokio::net::UnixStream::connect(path)
+        .await
+        .wrap_err_with(|| format!("failed to connect to {path:?}"))?;
+    let (unix_recv, unix_send) = tokio::io::split(stream);
+    pipe_tcp_stream_over_iroh(unix_recv, unix_send, send, recv).await
+}
+
+/// returns the number of bytes read from the peer, and the number of bytes sent to it.
 pub async fn pipe_tcp_stream_over_iroh(
     mut tcp_recv: impl tokio::io::AsyncRead + Unpin + Send + 'static,
     tcp_send: impl tokio::io::AsyncWrite + Unpin + Send + 'static,
     mut send: iroh::endpoint::SendStream,
     mut recv: iroh::endpoint::RecvStream,
-) -> eyre::Result<()> {
+) -> eyre::Result<(u64, u64)> {
     tracing::trace!("pipe_tcp_stream_over_iroh");
 
     let t = tokio::spawn(async move {
+        use tokio::io::AsyncWriteExt;
+
         let mut t = tcp_send;
-        let r = tokio::io::copy(&mut recv, &mut t).await;
+        let bytes_in = tokio::io::copy(&mut recv, &mut t).await?;
         tracing::trace!("piping tcp stream, copy done");
-        r.map(|_| ())
+        // the peer is done sending, let the local side know, else a service that waits for the
+        // end of input before responding (or closing) never does.
+        t.shutdown().await?;
+        Ok::<_, std::io::Error>(bytes_in)
     });
 
     tracing::trace!("copying tcp stream to iroh stream");
 
-    tokio::io::copy(&mut tcp_recv, &mut send).await?;
+    let bytes_out = tokio::io::copy(&mut tcp_recv, &mut send).await?;
 
     tracing::trace!("pipe_tcp_stream_over_iroh copy done");
 
@@ -52,15 +74,15 @@ pub async fn pipe_tcp_stream_over_iroh(
     tracing::trace!("closed send stream");
     drop(send);
 
-    let r = Ok(t.await??);
+    let bytes_in = t.await??;
     tracing::trace!("pipe_tcp_stream_over_iroh done");
-    r
+    Ok((bytes_in, bytes_out))
 }
 
 pub async fn tcp_to_peer(
     header: crate::ProtocolHeader,
     self_endpoint: iroh::Endpoint,
-    stream: tokio::net::TcpStream,
+    stream: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
     remote_node_id52: &str,
     peer_connections: crate::PeerStreamSenders,
     graceful: crate::Graceful,
@@ -79,5 +101,6 @@ pub async fn tcp_to_peer(
     tracing::info!("got stream");
 
     let (tcp_recv, tcp_send) = tokio::io::split(stream);
-    pipe_tcp_stream_over_iroh(tcp_recv, tcp_send, send, recv).await
+    pipe_tcp_stream_over_iroh(tcp_recv, tcp_send, send, recv).await?;
+    Ok(())
 }
@@ -0,0 +1,285 @@
+//! asking peers for the time, `Protocol::WhatTimeIsIt`.
+//!
+//! a device without NTP, say one that only has p2p access, can still check its clock against a
+//! few peers it trusts. every peer answers with its time, signed with the key of its id52, along
+//! with the nonce we sent, so the answer can not be replayed or made up by anyone else.
+//!
+//! each answer tells us the peer's clock is within half the round trip of what it said, when the
+//! answer arrived. `consensus()` finds the offset most of the peers agree on, so a peer with a
+//! wrong clock, or a slow link, does not throw off the estimate.
+
+/// how many times we ask each peer, the answer with the shortest round trip is the most precise
+const SAMPLES_PER_PEER: usize = 4;
+/// how long we wait for the connection to a peer
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+/// how long we wait for the `TimeRequest` of a stream, it is sent along with the protocol header
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// the keys of the endpoints in this process, by id52, `get_endpoint()` adds them
+static SIGNING_KEYS: std::sync::LazyLock<
+    std::sync::Mutex<std::collections::HashMap<String, std::sync::Arc<kulfi_id52::SecretKey>>>,
+> = std::sync::LazyLock::new(Default::default);
+
+fn signing_keys() -> std::sync::MutexGuard<
+    'static,
+    std::collections::HashMap<String, std::sync::Arc<kulfi_id52::SecretKey>>,
+> {
+    SIGNING_KEYS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// sent after the `Protocol::WhatTimeIsIt` header
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct TimeRequest {
+    /// the peer we are asking, it has to be the id52 the connection is with. a process can run
+    /// endpoints for several identities, but each one only answers for its own: signing for the
+    /// others would tell anyone connected to one of them that they are the same device.
+    pub id52: String,
+    pub nonce: String,
+}
+
+/// the answer to a `TimeRequest`
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SignedTime {
+    /// microseconds since the unix epoch
+    pub time: u64,
+    /// hex encoded signature of `signature_message()`
+    pub signature: String,
+}
+
+/// what we learnt from one peer
+#[derive(Debug, Clone)]
+pub struct TimeSample {
+    pub id52: String,
+    /// how far ahead of our clock the peer's clock is, in microseconds, negative if it is behind
+    pub offset: i64,
+    pub rtt: std::time::Duration,
+}
+
+/// the offset most peers agree on, see `consensus()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consensus {
+    /// how far ahead of our clock the peers are, in microseconds
+    pub offset: i64,
+    /// the offset is off by at most this much, going by the round trips, in microseconds
+    pub error: i64,
+    /// how many of the peers agree on the offset
+    pub agreeing: usize,
+    pub peers: usize,
+}
+
+/// lets the endpoint for this key answer `Protocol::WhatTimeIsIt`, on the connections it accepts
+/// or opens
+pub fn add_signing_key(secret_key: &kulfi_id52::SecretKey) {
+    signing_keys().insert(
+        secret_key.id52(),
+        std::sync::Arc::new(kulfi_id52::SecretKey::from_bytes(&secret_key.to_bytes())),
+    );
+}
+
+pub fn signature_message(id52: &str, nonce: &str, time: u64) -> String {
+    format!("kulfi-time\n{id52}\n{nonce}\n{time}")
+}
+
+/// answers a `Protocol::WhatTimeIsIt` stream, `accept_bi()` calls this, whatever protocol it waits
+/// for. `self_id` is our endpoint on the connection, the only id52 we answer for.
+pub(crate) async fn answer(
+    self_id: iroh::EndpointId,
+    mut send: iroh::endpoint::SendStream,
+    mut recv: iroh::endpoint::RecvStream,
+) -> eyre::Result<()> {
+    let request: TimeRequest = tokio::time::timeout(REQUEST_TIMEOUT, crate::next_json(&mut recv))
+        .await
+        .map_err(|_| eyre::anyhow!("timed out waiting for the time request"))??;
+    if request.id52 != data_encoding::BASE32_DNSSEC.encode(self_id.as_bytes()) {
+        tracing::info!(
+            id52 = request.id52,
+            "not answering the time for an id52 the connection is not with"
+        );
+        send.finish()?;
+        return Ok(());
+    }
+    let key = signing_keys().get(&request.id52).cloned();
+    let Some(key) = key else {
+        tracing::info!(
+            id52 = request.id52,
+            "not answering the time for an unknown id52"
+        );
+        send.finish()?;
+        return Ok(());
+    };
+
+    let time = now()?;
+    let reply = SignedTime {
+        time,
+        signature: data_encoding::HEXLOWER.encode(
+            &key.sign(signature_message(&request.id52, &request.nonce, time).as_bytes())
+                .to_bytes(),
+        ),
+    };
+    send.write_all(format!("{}\n", serde_json::to_string(&reply)?).as_bytes())
+        .await?;
+    send.finish()?;
+    Ok(())
+}
+
+/// asks `id52` for the time a few times, and returns the most precise answer
+pub async fn ask_time(ep: &iroh::Endpoint, id52: &str) -> eyre::Result<TimeSample> {
+    use std::str::FromStr;
+
+    let public_key =
+        kulfi_id52::PublicKey::from_str(id52).map_err(|e| eyre::anyhow!("{id52}: {e}"))?;
+    let remote = iroh::EndpointId::from_bytes(&public_key.to_bytes())?;
+    let conn = tokio::time::timeout(CONNECT_TIMEOUT, ep.connect(remote, crate::APNS_IDENTITY))
+        .await
+        .map_err(|_| eyre::anyhow!("connecting took more than {CONNECT_TIMEOUT:?}"))??;
+
+    let mut best: Option<TimeSample> = None;
+    let mut r = Ok(());
+    for _ in 0..SAMPLES_PER_PEER {
+        match sample(&conn, id52, &public_key).await {
+            Ok(s) => {
+                if best.as_ref().is_none_or(|b| s.rtt < b.rtt) {
+                    best = Some(s);
+                }
+            }
+            Err(e) => {
+                r = Err(e);
+                break;
+            }
+        }
+    }
+    conn.close(0u32.into(), b"done");
+
+    match best {
+        Some(s) => Ok(s),
+        None => Err(r
+            .err()
+            .unwrap_or_else(|| eyre::anyhow!("no answer from {id52}"))),
+    }
+}
+
+async fn sample(
+    conn: &iroh::endpoint::Connection,
+    id52: &str,
+    public_key: &kulfi_id52::PublicKey,
+) -> eyre::Result<TimeSample> {
+    let nonce = data_encoding::HEXLOWER.encode(&rand::random::<[u8; 16]>());
+
+    let sent = now()?;
+    let start = std::time::Instant::now();
+    let (mut send, mut recv) = crate::open_bi(
+        conn,
+        crate::ProtocolHeader {
+            protocol: crate::Protocol::WhatTimeIsIt,
+            extra: Some(serde_json::to_string(&TimeRequest {
+                id52: id52.to_string(),
+                nonce: nonce.clone(),
+            })?),
+        },
+    )
+    .await?;
+    let reply: SignedTime = crate::next_json(&mut recv)
+        .await
+        .map_err(|e| eyre::anyhow!("{id52} did not answer with the time: {e}"))?;
+    let rtt = start.elapsed();
+    send.finish()?;
+
+    let signature: [u8; 64] = data_encoding::HEXLOWER_PERMISSIVE
+        .decode(reply.signature.as_bytes())?
+        .try_into()
+        .map_err(|_| eyre::anyhow!("signature must be 64 bytes"))?;
+    public_key
+        .verify(
+            signature_message(id52, &nonce, reply.time).as_bytes(),
+            &kulfi_id52::Signature::from_bytes(&signature)?,
+        )
+        .map_err(|e| eyre::anyhow!("{id52} sent a time with a bad signature: {e}"))?;
+
+    // the peer read its clock about half way through the round trip
+    let ours = sent + rtt.as_micros() as u64 / 2;
+    Ok(TimeSample {
+        id52: id52.to_string(),
+        offset: reply.time as i64 - ours as i64,
+        rtt,
+    })
+}
+
+/// the offset the most peers agree on. every sample says the offset is within half its round
+/// trip of what it measured, the answer is the range where the most of these overlap (Marzullo's
+/// algorithm). `None` if there are no samples.
+pub fn consensus(samples: &[TimeSample]) -> Option<Consensus> {
+    // the ends of the ranges, starts sort before ends at the same offset, so ranges that only
+    // touch still agree
+    let mut edges = Vec::with_capacity(samples.len() * 2);
+    for s in samples {
+        let half = (s.rtt.as_micros() / 2) as i64;
+        edges.push((s.offset - half, -1));
+        edges.push((s.offset + half, 1));
+    }
+    edges.sort();
+
+    let (mut best, mut count) = (0, 0);
+    let (mut low, mut high) = (0, 0);
+    for (i, &(offset, edge)) in edges.iter().enumerate() {
+        count -= edge;
+        if count > best {
+            best = count;
+            low = offset;
+            high = edges[i + 1].0;
+        }
+    }
+
+    if best == 0 {
+        return None;
+    }
+
+    Some(Consensus {
+        offset: low + (high - low) / 2,
+        error: (high - low) / 2,
+        agreeing: best as usize,
+        peers: samples.len(),
+    })
+}
+
+/// microseconds since the unix epoch
+fn now() -> eyre::Result<u64> {
+    Ok(std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_micros() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    fn sample(offset_ms: i64, rtt_ms: u64) -> super::TimeSample {
+        super::TimeSample {
+            id52: String::new(),
+            offset: offset_ms * 1000,
+            rtt: std::time::Duration::from_millis(rtt_ms),
+        }
+    }
+
+    #[test]
+    fn test_consensus() {
+        assert_eq!(super::consensus(&[]), None);
+
+        // two peers agree the offset is between 90ms and 110ms, and the peer with a clock an hour
+        // off is left out
+        let c =
+            super::consensus(&[sample(100, 20), sample(105, 30), sample(3_600_000, 10)]).unwrap();
+        assert_eq!(
+            c,
+            super::Consensus {
+                offset: 100_000,
+                error: 10_000,
+                agreeing: 2,
+                peers: 3,
+            }
+        );
+
+        // a single peer
+        let c = super::consensus(&[sample(-50, 10)]).unwrap();
+        assert_eq!((c.offset, c.error, c.agreeing), (-50_000, 5_000, 1));
+    }
+}
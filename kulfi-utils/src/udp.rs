@@ -0,0 +1,406 @@
+//! UDP over kulfi.
+//!
+//! the datagrams between one client address and the service, a "flow", have a bidirectional
+//! stream of their own (`Protocol::Udp`). when the connection supports QUIC datagrams, the
+//! datagrams of the flow are sent as QUIC datagrams, which are not retransmitted, so a lost packet
+//! does not hold up the ones after it. a QUIC datagram belongs to the connection, not to a stream,
+//! so each one starts with the id of the stream of its flow, as eight big endian bytes.
+//!
+//! everything else goes on the stream, every datagram written as a two byte, big endian, length
+//! followed by the datagram: all datagrams when the connection has no QUIC datagrams
+//! (`max_datagram_size()` is `None`), the ones too large for a QUIC datagram, and the ones sent
+//! before we know the other side reads the QUIC datagrams of the flow. the side that accepted the
+//! stream knows right away, as the side that opened it listens before it sends anything, and it
+//! sends an empty QUIC datagram to say it listens too. the stream also tells both sides when the
+//! flow is over.
+
+/// the largest datagram that fits the two byte length, this is also the largest UDP payload
+pub const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// the size of the flow id at the start of every QUIC datagram
+const FLOW_ID_SIZE: usize = 8;
+/// how many datagrams can wait for a flow to read them, more QUIC datagrams are dropped
+const FLOW_QUEUE: usize = 128;
+
+/// the flows that read QUIC datagrams, by `Connection::stable_id()`
+static CONNECTIONS: std::sync::LazyLock<
+    std::sync::Mutex<std::collections::HashMap<usize, ConnectionFlows>>,
+> = std::sync::LazyLock::new(Default::default);
+
+struct ConnectionFlows {
+    flows: std::collections::HashMap<u64, tokio::sync::mpsc::Sender<bytes::Bytes>>,
+    /// dropped with the last flow of the connection, which stops `dispatch()`
+    _stop: tokio::sync::oneshot::Sender<()>,
+}
+
+fn connections() -> std::sync::MutexGuard<'static, std::collections::HashMap<usize, ConnectionFlows>>
+{
+    CONNECTIONS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// the sending half of a flow
+pub struct FlowSender {
+    conn: iroh::endpoint::Connection,
+    id: u64,
+    send: iroh::endpoint::SendStream,
+    peer_listening: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+/// the receiving half of a flow, it stops reading QUIC datagrams for the flow when dropped
+pub struct FlowReceiver {
+    /// keeps the connection, so its `stable_id()` is not reused while we are registered under it
+    conn: iroh::endpoint::Connection,
+    id: u64,
+    datagrams: tokio::sync::mpsc::Receiver<bytes::Bytes>,
+    stream: tokio::sync::mpsc::Receiver<eyre::Result<bytes::Bytes>>,
+    reader: tokio::task::JoinHandle<()>,
+    peer_listening: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+/// the flow on a stream we opened with `get_stream_with_connection()`
+pub fn opened_flow(
+    conn: iroh::endpoint::Connection,
+    send: iroh::endpoint::SendStream,
+    recv: iroh::endpoint::RecvStream,
+) -> (FlowSender, FlowReceiver) {
+    flow(conn, send, recv, false)
+}
+
+/// the flow on a stream we accepted
+pub fn accepted_flow(
+    conn: iroh::endpoint::Connection,
+    send: iroh::endpoint::SendStream,
+    recv: iroh::endpoint::RecvStream,
+) -> (FlowSender, FlowReceiver) {
+    let (send, recv) = flow(conn, send, recv, true);
+    // tells the other side we read the QUIC datagrams of the flow, if this is lost, the first
+    // datagram we send does the same
+    if send.conn.max_datagram_size().is_some() {
+        let _ = send.conn.send_datagram(frame(send.id, &[]));
+    }
+    (send, recv)
+}
+
+fn flow(
+    conn: iroh::endpoint::Connection,
+    send: iroh::endpoint::SendStream,
+    mut recv: iroh::endpoint::RecvStream,
+    peer_listening: bool,
+) -> (FlowSender, FlowReceiver) {
+    let id = u64::from(send.id());
+    let peer_listening = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(peer_listening));
+
+    let (datagrams_tx, datagrams) = tokio::sync::mpsc::channel(FLOW_QUEUE);
+    {
+        let mut connections = connections();
+        let flows = connections.entry(conn.stable_id()).or_insert_with(|| {
+            let (stop_tx, stop) = tokio::sync::oneshot::channel();
+            // not on `Graceful`, it has no work of its own, and ends with the last flow
+            tokio::spawn(dispatch(conn.clone(), stop));
+            ConnectionFlows {
+                flows: Default::default(),
+                _stop: stop_tx,
+            }
+        });
+        flows.flows.insert(id, datagrams_tx);
+    }
+
+    // reading the stream in a task of its own, as `read_datagram()` can not be cancelled half way
+    // through a datagram, while waiting for the QUIC datagrams
+    let (stream_tx, stream) = tokio::sync::mpsc::channel(FLOW_QUEUE);
+    let reader = tokio::spawn(async move {
+        let mut buf = Vec::new();
+        loop {
+            let datagram = match read_datagram(&mut recv, &mut buf).await {
+                Ok(Some(datagram)) => Ok(bytes::Bytes::copy_from_slice(datagram)),
+                Ok(None) => break,
+                Err(e) => Err(e),
+            };
+            let failed = datagram.is_err();
+            if stream_tx.send(datagram).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    (
+        FlowSender {
+            conn: conn.clone(),
+            id,
+            send,
+            peer_listening: peer_listening.clone(),
+        },
+        FlowReceiver {
+            conn,
+            id,
+            datagrams,
+            stream,
+            reader,
+            peer_listening,
+        },
+    )
+}
+
+/// hands the QUIC datagrams of a connection to the flows they are for, till the last flow is gone
+async fn dispatch(conn: iroh::endpoint::Connection, mut stop: tokio::sync::oneshot::Receiver<()>) {
+    loop {
+        let datagram = tokio::select! {
+            _ = &mut stop => return,
+            d = conn.read_datagram() => match d {
+                Ok(d) => d,
+                Err(e) => {
+                    tracing::debug!("stopped reading datagrams: {e}");
+                    return;
+                }
+            },
+        };
+        let Some(id) = datagram.get(..FLOW_ID_SIZE) else {
+            tracing::debug!("dropping a datagram without a flow id");
+            continue;
+        };
+        let id = u64::from_be_bytes(id.try_into().expect("the flow id is eight bytes"));
+
+        let flow = connections()
+            .get(&conn.stable_id())
+            .and_then(|c| c.flows.get(&id).cloned());
+        match flow {
+            Some(flow) => {
+                if flow.try_send(datagram.slice(FLOW_ID_SIZE..)).is_err() {
+                    tracing::debug!(id, "flow is busy, dropping a datagram");
+                }
+            }
+            None => tracing::debug!(id, "dropping a datagram for an unknown flow"),
+        }
+    }
+}
+
+fn frame(id: u64, datagram: &[u8]) -> bytes::Bytes {
+    let mut frame = Vec::with_capacity(FLOW_ID_SIZE + datagram.len());
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(datagram);
+    frame.into()
+}
+
+impl FlowSender {
+    /// sends a datagram as a QUIC datagram, or on the stream, see the module docs
+    pub async fn send(&mut self, datagram: &[u8]) -> eyre::Result<()> {
+        // an empty QUIC datagram says the other side listens, empty datagrams go on the stream
+        if !datagram.is_empty()
+            && self
+                .peer_listening
+                .load(std::sync::atomic::Ordering::Relaxed)
+            && self
+                .conn
+                .max_datagram_size()
+                .is_some_and(|max| FLOW_ID_SIZE + datagram.len() <= max)
+        {
+            match self.conn.send_datagram(frame(self.id, datagram)) {
+                Ok(()) => return Ok(()),
+                Err(iroh::endpoint::SendDatagramError::ConnectionLost(e)) => return Err(e.into()),
+                Err(e) => tracing::debug!("sending the datagram on the stream: {e}"),
+            }
+        }
+        write_datagram(&mut self.send, datagram).await
+    }
+
+    /// tells the other side the flow is over
+    pub fn finish(&mut self) -> eyre::Result<()> {
+        self.send.finish()?;
+        Ok(())
+    }
+}
+
+impl FlowReceiver {
+    /// the next datagram, whichever way it came, `None` once the other side has finished the
+    /// stream
+    pub async fn recv(&mut self) -> eyre::Result<Option<bytes::Bytes>> {
+        loop {
+            tokio::select! {
+                Some(datagram) = self.datagrams.recv() => {
+                    self.peer_listening
+                        .store(true, std::sync::atomic::Ordering::Relaxed);
+                    if !datagram.is_empty() {
+                        return Ok(Some(datagram));
+                    }
+                }
+                datagram = self.stream.recv() => return datagram.transpose(),
+            }
+        }
+    }
+}
+
+impl Drop for FlowReceiver {
+    fn drop(&mut self) {
+        self.reader.abort();
+        let mut connections = connections();
+        if let Some(c) = connections.get_mut(&self.conn.stable_id()) {
+            c.flows.remove(&self.id);
+            if c.flows.is_empty() {
+                connections.remove(&self.conn.stable_id());
+            }
+        }
+    }
+}
+
+/// writes one datagram to the stream
+pub async fn write_datagram(
+    send: &mut (impl tokio::io::AsyncWrite + Unpin),
+    datagram: &[u8],
+) -> eyre::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let len = u16::try_from(datagram.len())
+        .map_err(|_| eyre::anyhow!("datagram of {} bytes is too large", datagram.len()))?;
+    // a single write, so the length and the datagram go out together
+    let mut frame = Vec::with_capacity(2 + datagram.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(datagram);
+    send.write_all(&frame).await?;
+    Ok(())
+}
+
+/// reads the next datagram from the stream into `buf`, `None` once the other side has finished the
+/// stream. the stream can only end between two datagrams.
+pub async fn read_datagram<'a>(
+    recv: &mut (impl tokio::io::AsyncRead + Unpin),
+    buf: &'a mut Vec<u8>,
+) -> eyre::Result<Option<&'a [u8]>> {
+    use tokio::io::AsyncReadExt;
+
+    let mut len = [0u8; 2];
+    match recv.read_exact(&mut len[..1]).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    recv.read_exact(&mut len[1..]).await?;
+
+    buf.resize(u16::from_be_bytes(len) as usize, 0);
+    recv.read_exact(buf).await?;
+    Ok(Some(buf))
+}
+
+/// this is the udp proxy, the exposer side of a flow.
+///
+/// the datagrams from the peer are sent to the service at `addr` from a socket of our own, so the
+/// service sees every flow as a different client, and whatever the service sends back to that
+/// socket goes to the peer. returns when the peer finishes the stream, with the number of bytes
+/// read from the peer, and the number of bytes sent to it.
+pub async fn peer_to_udp(
+    addr: &str,
+    conn: iroh::endpoint::Connection,
+    send: iroh::endpoint::SendStream,
+    recv: iroh::endpoint::RecvStream,
+) -> eyre::Result<(u64, u64)> {
+    use eyre::WrapErr;
+
+    let upstream = tokio::net::lookup_host(addr)
+        .await
+        .wrap_err_with(|| format!("failed to resolve {addr}"))?
+        .next()
+        .ok_or_else(|| eyre::anyhow!("{addr} did not resolve to any address"))?;
+    let local: std::net::SocketAddr = if upstream.is_ipv4() {
+        (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = tokio::net::UdpSocket::bind(local).await?;
+    socket.connect(upstream).await?;
+    let socket = std::sync::Arc::new(socket);
+
+    let (mut send, mut recv) = accepted_flow(conn, send, recv);
+
+    let (done, mut stop) = tokio::sync::oneshot::channel::<()>();
+    let replies = {
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            let mut bytes_out = 0;
+            loop {
+                let n = tokio::select! {
+                    _ = &mut stop => break,
+                    n = socket.recv(&mut buf) => n,
+                };
+                match n {
+                    Ok(n) => {
+                        send.send(&buf[..n]).await?;
+                        bytes_out += n as u64;
+                    }
+                    // the service is not listening (yet), as told by an icmp error, udp clients
+                    // just keep trying, so do we
+                    Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                        tracing::debug!("udp service refused a datagram: {e}");
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            send.finish()?;
+            Ok::<_, eyre::Report>(bytes_out)
+        })
+    };
+
+    let mut bytes_in = 0;
+    let r = loop {
+        match recv.recv().await {
+            Ok(Some(datagram)) => {
+                bytes_in += datagram.len() as u64;
+                match socket.send(&datagram).await {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                        tracing::debug!("udp service refused a datagram: {e}");
+                    }
+                    Err(e) => break Err(e.into()),
+                }
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+
+    let _ = done.send(());
+    let bytes_out = replies.await??;
+    r?;
+    Ok((bytes_in, bytes_out))
+}
+
+#[cfg(test)]
+mod test {
+    #[tokio::test]
+    async fn test_framing() {
+        let mut stream = Vec::new();
+        super::write_datagram(&mut stream, b"hello").await.unwrap();
+        super::write_datagram(&mut stream, b"").await.unwrap();
+        super::write_datagram(&mut stream, &[7; 300]).await.unwrap();
+        assert_eq!(&stream[..7], b"\0\x05hello");
+        assert!(
+            super::write_datagram(&mut Vec::new(), &vec![0; super::MAX_DATAGRAM_SIZE + 1])
+                .await
+                .is_err()
+        );
+
+        let mut recv = stream.as_slice();
+        let mut buf = Vec::new();
+        assert_eq!(
+            super::read_datagram(&mut recv, &mut buf).await.unwrap(),
+            Some(&b"hello"[..])
+        );
+        assert_eq!(
+            super::read_datagram(&mut recv, &mut buf).await.unwrap(),
+            Some(&b""[..])
+        );
+        assert_eq!(
+            super::read_datagram(&mut recv, &mut buf).await.unwrap(),
+            Some(&[7; 300][..])
+        );
+        assert_eq!(
+            super::read_datagram(&mut recv, &mut buf).await.unwrap(),
+            None
+        );
+
+        // the stream ending in the middle of a datagram is an error
+        let mut recv = &stream[..4];
+        assert!(super::read_datagram(&mut recv, &mut buf).await.is_err());
+    }
+}
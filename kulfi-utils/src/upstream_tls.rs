@@ -0,0 +1,185 @@
+/// UpstreamTls is used when the HTTP service we are exposing only listens on TLS, which is common
+/// for internal dev servers with self-signed certificates.
+///
+/// by default the upstream certificate is verified against the bundled web PKI roots. a custom CA
+/// bundle can be passed instead, the SNI sent can be overridden (say the service is on 127.0.0.1
+/// but the certificate is for `dev.internal`), and for localhost development verification can be
+/// turned off entirely.
+#[derive(Clone)]
+pub struct UpstreamTls {
+    connector: tokio_rustls::TlsConnector,
+    sni: Option<rustls::pki_types::ServerName<'static>>,
+    /// describes this configuration, used as part of the connection pool key
+    key: String,
+}
+
+impl UpstreamTls {
+    pub fn new(
+        ca_bundle: Option<&std::path::Path>,
+        sni: Option<String>,
+        accept_invalid_certs: bool,
+    ) -> eyre::Result<Self> {
+        use eyre::WrapErr;
+
+        // both ring and aws-lc-rs end up in our dependency tree, so we can not rely on rustls
+        // picking the process default provider, we pass the one we want explicitly.
+        let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .wrap_err_with(|| "failed to create tls config")?;
+
+        let config = if accept_invalid_certs {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(std::sync::Arc::new(AcceptInvalidCerts(provider)))
+                .with_no_client_auth()
+        } else {
+            builder
+                .with_root_certificates(root_store(ca_bundle)?)
+                .with_no_client_auth()
+        };
+
+        let sni = match sni {
+            Some(sni) => Some(
+                rustls::pki_types::ServerName::try_from(sni.clone())
+                    .wrap_err_with(|| format!("invalid sni: {sni}"))?,
+            ),
+            None => None,
+        };
+
+        let key = format!(
+            "ca={ca_bundle:?};sni={sni:?};accept_invalid_certs={accept_invalid_certs}",
+            sni = sni.as_ref().map(|v| v.to_str())
+        );
+
+        Ok(Self {
+            connector: tokio_rustls::TlsConnector::from(std::sync::Arc::new(config)),
+            sni,
+            key,
+        })
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// does the tls handshake over `stream`, `addr` is the `host:port` we connected to, the host
+    /// part is used as server name unless an sni override was given.
+    pub async fn connect<IO>(
+        &self,
+        addr: &str,
+        stream: IO,
+    ) -> eyre::Result<tokio_rustls::client::TlsStream<IO>>
+    where
+        IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        use eyre::WrapErr;
+
+        let server_name = match &self.sni {
+            Some(v) => v.clone(),
+            None => server_name_from_addr(addr)?,
+        };
+
+        self.connector
+            .connect(server_name, stream)
+            .await
+            .wrap_err_with(|| format!("tls handshake with {addr} failed"))
+    }
+}
+
+impl std::fmt::Debug for UpstreamTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("UpstreamTls").field(&self.key).finish()
+    }
+}
+
+fn root_store(ca_bundle: Option<&std::path::Path>) -> eyre::Result<rustls::RootCertStore> {
+    use eyre::WrapErr;
+    use rustls::pki_types::pem::PemObject;
+
+    let ca_bundle = match ca_bundle {
+        Some(v) => v,
+        None => {
+            return Ok(rustls::RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            });
+        }
+    };
+
+    let mut store = rustls::RootCertStore::empty();
+    for cert in rustls::pki_types::CertificateDer::pem_file_iter(ca_bundle)
+        .wrap_err_with(|| format!("failed to read ca bundle: {ca_bundle:?}"))?
+    {
+        let cert = cert.wrap_err_with(|| format!("failed to parse ca bundle: {ca_bundle:?}"))?;
+        store
+            .add(cert)
+            .wrap_err_with(|| format!("invalid certificate in ca bundle: {ca_bundle:?}"))?;
+    }
+
+    if store.is_empty() {
+        return Err(eyre::anyhow!("no certificates found in {ca_bundle:?}"));
+    }
+
+    Ok(store)
+}
+
+fn server_name_from_addr(addr: &str) -> eyre::Result<rustls::pki_types::ServerName<'static>> {
+    let host = match addr.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => addr,
+    };
+    // ipv6 addresses are written as [::1]:443
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    rustls::pki_types::ServerName::try_from(host.to_string())
+        .map_err(|e| eyre::anyhow!("can not use {host} as tls server name: {e}"))
+}
+
+#[derive(Debug)]
+struct AcceptInvalidCerts(std::sync::Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for AcceptInvalidCerts {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    // we still check the handshake signatures, the only thing we skip is the certificate chain.
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
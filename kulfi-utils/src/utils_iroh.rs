@@ -16,8 +16,13 @@ async fn ack(send: &mut iroh::endpoint::SendStream) -> eyre::Result<()> {
     Ok(())
 }
 
+/// waits for the peer to open a stream for `expected`, answering `Protocol::Ping` and
+/// `Protocol::WhatTimeIsIt` streams meanwhile. `self_id` is the id of our endpoint that accepted,
+/// or opened, `conn`: the time is only signed for it, and not for the other identities the
+/// process may have.
 pub async fn accept_bi(
     conn: &iroh::endpoint::Connection,
+    self_id: iroh::EndpointId,
     expected: crate::Protocol,
 ) -> eyre::Result<(iroh::endpoint::SendStream, iroh::endpoint::RecvStream)> {
     loop {
@@ -25,18 +30,37 @@ pub async fn accept_bi(
         match accept_bi_(conn).await? {
             (mut send, _recv, crate::Protocol::Ping) => {
                 tracing::trace!("got ping");
+                ack(&mut send).await?;
                 tracing::trace!("sending PONG");
                 send.write_all(crate::PONG)
                     .await
                     .inspect_err(|e| tracing::error!("failed to write PONG: {e:?}"))?;
                 tracing::trace!("sent PONG");
             }
-            (s, r, found) => {
+            // answered by every server, like `Ping`, in a task of its own as we have to wait for
+            // the request. the task is not on a `Graceful`, we do not have one here, and it has
+            // nothing to finish on shutdown: it ends within `time::REQUEST_TIMEOUT` of starting,
+            // and a peer that loses its answer asks again.
+            (mut send, recv, crate::Protocol::WhatTimeIsIt) => {
+                ack(&mut send).await?;
+                tokio::spawn(async move {
+                    if let Err(e) = crate::time::answer(self_id, send, recv).await {
+                        tracing::info!("failed to answer the time: {e:?}");
+                    }
+                });
+            }
+            (mut send, recv, found) if found == expected => {
                 tracing::trace!("got bidirectional stream: {found:?}");
-                if found != expected {
-                    return Err(eyre::anyhow!("expected: {expected:?}, got {found:?}"));
-                }
-                return Ok((s, r));
+                ack(&mut send).await?;
+                return Ok((send, recv));
+            }
+            // the peer asking is told so, instead of an ack, and the connection stays up for its
+            // other streams
+            (mut send, _recv, found) => {
+                tracing::info!("refusing stream, expected: {expected:?}, got {found:?}");
+                send.write_all(format!("{}\n", crate::REFUSED).as_bytes())
+                    .await?;
+                send.finish()?;
             }
         }
     }
@@ -44,9 +68,10 @@ pub async fn accept_bi(
 
 pub async fn accept_bi_with<T: serde::de::DeserializeOwned>(
     conn: &iroh::endpoint::Connection,
+    self_id: iroh::EndpointId,
     expected: crate::Protocol,
 ) -> eyre::Result<(T, iroh::endpoint::SendStream, iroh::endpoint::RecvStream)> {
-    let (send, mut recv) = accept_bi(conn, expected).await?;
+    let (send, mut recv) = accept_bi(conn, self_id, expected).await?;
     let next = next_json(&mut recv)
         .await
         .inspect_err(|e| tracing::error!("failed to read next message: {e}"))?;
@@ -54,6 +79,72 @@ pub async fn accept_bi_with<T: serde::de::DeserializeOwned>(
     Ok((next, send, recv))
 }
 
+/// opens a bidirectional stream for `header.protocol`, and waits for the peer to accept it, this is
+/// the other end of `accept_bi()`. either side of a connection can open streams, not just the one
+/// that connected.
+///
+/// if the peer does not serve the protocol, the error can be downcast to
+/// `http::ProxyError::PeerRefused`.
+pub async fn open_bi(
+    conn: &iroh::endpoint::Connection,
+    header: crate::ProtocolHeader,
+) -> eyre::Result<(iroh::endpoint::SendStream, iroh::endpoint::RecvStream)> {
+    use eyre::WrapErr;
+
+    crate::metrics::stream(header.protocol, false);
+
+    let (mut send, mut recv) = match conn.open_bi().await {
+        Ok(v) => {
+            tracing::trace!("opened bi-stream");
+            v
+        }
+        Err(e) => {
+            tracing::error!("failed to open_bi: {e:?}");
+            return Err(eyre::anyhow!("failed to open_bi: {e:?}"));
+        }
+    };
+
+    send.write_all(
+        &serde_json::to_vec(&header.protocol)
+            .wrap_err_with(|| format!("failed to serialize protocol: {:?}", header.protocol))?,
+    )
+    .await?;
+    tracing::trace!("wrote protocol");
+
+    send.write(b"\n")
+        .await
+        .wrap_err_with(|| "failed to write newline")?;
+
+    tracing::trace!("wrote newline");
+
+    if let Some(extra) = header.extra {
+        send.write_all(extra.as_bytes()).await?;
+        tracing::trace!("wrote protocol");
+
+        send.write(b"\n")
+            .await
+            .wrap_err_with(|| "failed to write newline")?;
+    }
+
+    let msg = crate::next_string(&mut recv).await?;
+
+    // the connection is fine, it is only this stream the peer does not want
+    if msg == crate::REFUSED {
+        tracing::info!("peer refused {:?}", header.protocol);
+        let error = eyre::Report::new(crate::http::ProxyError::PeerRefused)
+            .wrap_err(format!("peer does not serve {:?}", header.protocol));
+        return Err(error);
+    }
+
+    if msg != crate::ACK {
+        tracing::error!("failed to read ack: {msg:?}");
+        return Err(eyre::anyhow!("failed to read ack: {msg:?}"));
+    }
+
+    tracing::trace!("received ack");
+    Ok((send, recv))
+}
+
 async fn accept_bi_(
     conn: &iroh::endpoint::Connection,
 ) -> eyre::Result<(
@@ -62,7 +153,7 @@ async fn accept_bi_(
     crate::Protocol,
 )> {
     tracing::trace!("accept_bi_ called");
-    let (mut send, mut recv) = conn.accept_bi().await?;
+    let (send, mut recv) = conn.accept_bi().await?;
     tracing::trace!("accept_bi_ got send and recv");
 
     let msg: crate::Protocol = next_json(&mut recv)
@@ -70,10 +161,8 @@ async fn accept_bi_(
         .inspect_err(|e| tracing::error!("failed to read next message: {e}"))?;
 
     tracing::trace!("msg: {msg:?}");
+    crate::metrics::stream(msg, true);
 
-    ack(&mut send).await?;
-
-    tracing::trace!("ack sent");
     Ok((send, recv, msg))
 }
 
@@ -129,9 +218,12 @@ pub async fn next_string(recv: &mut iroh::endpoint::RecvStream) -> eyre::Result<
     String::from_utf8(buffer).map_err(|e| eyre::anyhow!("failed to convert bytes to string: {e}"))
 }
 
+static IROH_ENDPOINT: tokio::sync::OnceCell<iroh::Endpoint> = tokio::sync::OnceCell::const_new();
+
+/// the endpoint bridges and proxies use to reach peers. it gets a new random id52 every time the
+/// process starts, unless `set_global_identity()` was called before, so peers can recognise us.
 pub async fn global_iroh_endpoint() -> iroh::Endpoint {
     async fn new_iroh_endpoint() -> iroh::Endpoint {
-        // TODO: read secret key from ENV VAR
         iroh::Endpoint::builder()
             .discovery(iroh::discovery::pkarr::PkarrPublisher::n0_dns())
             .discovery(iroh::discovery::dns::DnsDiscovery::n0_dns())
@@ -142,7 +234,14 @@ pub async fn global_iroh_endpoint() -> iroh::Endpoint {
             .expect("failed to create iroh Endpoint")
     }
 
-    static IROH_ENDPOINT: tokio::sync::OnceCell<iroh::Endpoint> =
-        tokio::sync::OnceCell::const_new();
     IROH_ENDPOINT.get_or_init(new_iroh_endpoint).await.clone()
 }
+
+/// makes `global_iroh_endpoint()` use `secret_key`, so the exposing side can allow or trust our
+/// id52. it has to be called before anything uses the endpoint.
+pub async fn set_global_identity(secret_key: kulfi_id52::SecretKey) -> eyre::Result<()> {
+    let endpoint = crate::get_endpoint(secret_key).await?;
+    IROH_ENDPOINT
+        .set(endpoint)
+        .map_err(|_| eyre::anyhow!("the global iroh endpoint is already in use"))
+}
@@ -0,0 +1,169 @@
+//! helpers to run a bridge and an exposer in the same process, talking to each other over iroh on
+//! the local machine, without relays or any other network access.
+
+#![allow(dead_code)]
+
+pub struct Peers {
+    /// the endpoint that exposes a service, what `malai http` does
+    pub exposer: iroh::Endpoint,
+    /// the endpoint that forwards requests to the exposer, what `malai http-bridge` does
+    pub bridge: iroh::Endpoint,
+    pub graceful: kulfi_utils::Graceful,
+    pub peer_connections: kulfi_utils::PeerStreamSenders,
+}
+
+impl Peers {
+    pub async fn new() -> Self {
+        let exposer = local_endpoint(None).await;
+        let bridge = local_endpoint(Some(&exposer)).await;
+
+        Self {
+            exposer,
+            bridge,
+            graceful: kulfi_utils::Graceful::new(),
+            peer_connections: Default::default(),
+        }
+    }
+
+    pub fn exposer_id52(&self) -> String {
+        data_encoding::BASE32_DNSSEC.encode(self.exposer.id().as_bytes())
+    }
+
+    /// accepts connections on the exposer and serves `Protocol::Http` streams from `upstream`
+    pub fn expose_http(&self, upstream: kulfi_utils::HttpUpstream) {
+        self.expose_http_with(upstream, Default::default())
+    }
+
+    pub fn bridge_id52(&self) -> String {
+        data_encoding::BASE32_DNSSEC.encode(self.bridge.id().as_bytes())
+    }
+
+    pub fn expose_http_with(
+        &self,
+        upstream: kulfi_utils::HttpUpstream,
+        options: kulfi_utils::PeerToHttpOptions,
+    ) {
+        let ep = self.exposer.clone();
+        let options = std::sync::Arc::new(options);
+        tokio::spawn(async move {
+            let client_pools = kulfi_utils::HttpConnectionPools::default();
+            while let Some(conn) = ep.accept().await {
+                let conn = conn.await.unwrap();
+                let upstream = upstream.clone();
+                let client_pools = client_pools.clone();
+                let options = options.clone();
+                let self_id = ep.id();
+                tokio::spawn(async move {
+                    let remote_id52 = kulfi_utils::get_remote_id52(&conn);
+                    while let Ok((mut send, recv)) =
+                        kulfi_utils::accept_bi(&conn, self_id, kulfi_utils::Protocol::Http).await
+                    {
+                        let upstream = upstream.clone();
+                        let client_pools = client_pools.clone();
+                        let remote_id52 = remote_id52.clone();
+                        let options = options.clone();
+                        tokio::spawn(async move {
+                            kulfi_utils::peer_to_http(
+                                &upstream,
+                                &remote_id52,
+                                &options,
+                                client_pools,
+                                &mut send,
+                                recv,
+                            )
+                            .await
+                            .unwrap();
+                            send.finish().unwrap();
+                        });
+                    }
+                });
+            }
+        });
+    }
+
+    /// starts an HTTP server that forwards every request to the exposer, returns its address
+    pub async fn http_bridge(&self) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let ep = self.bridge.clone();
+        let remote = self.exposer_id52();
+        let peer_connections = self.peer_connections.clone();
+        let graceful = self.graceful.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let ep = ep.clone();
+                let remote = remote.clone();
+                let peer_connections = peer_connections.clone();
+                let graceful = graceful.clone();
+
+                tokio::spawn(async move {
+                    let service = hyper::service::service_fn(|r| {
+                        let ep = ep.clone();
+                        let remote = remote.clone();
+                        let peer_connections = peer_connections.clone();
+                        let graceful = graceful.clone();
+                        async move {
+                            kulfi_utils::http_to_peer(
+                                kulfi_utils::Protocol::Http.into(),
+                                r,
+                                ep,
+                                &remote,
+                                peer_connections,
+                                graceful,
+                            )
+                            .await
+                        }
+                    });
+                    // same as `malai http-bridge`, http1 with upgrades, or http2 with prior
+                    // knowledge
+                    let _ = hyper_util::server::conn::auto::Builder::new(
+                        hyper_util::rt::TokioExecutor::new(),
+                    )
+                    .serve_connection_with_upgrades(hyper_util::rt::TokioIo::new(stream), service)
+                    .await;
+                });
+            }
+        });
+
+        addr
+    }
+}
+
+impl Peers {
+    /// opens a stream from the bridge to the exposer, like `malai tcp-bridge` does for every tcp
+    /// connection it gets
+    pub async fn stream_to_exposer(
+        &self,
+        protocol: kulfi_utils::Protocol,
+    ) -> (iroh::endpoint::SendStream, iroh::endpoint::RecvStream) {
+        kulfi_utils::get_stream(
+            self.bridge.clone(),
+            protocol.into(),
+            self.exposer_id52(),
+            self.peer_connections.clone(),
+            self.graceful.clone(),
+        )
+        .await
+        .unwrap()
+    }
+}
+
+/// an endpoint without relays or dns discovery, if `peer` is given, it can dial that peer over
+/// the local interfaces.
+async fn local_endpoint(peer: Option<&iroh::Endpoint>) -> iroh::Endpoint {
+    let discovery = iroh::discovery::static_provider::StaticProvider::new();
+    if let Some(peer) = peer {
+        discovery.add_endpoint_info(peer.addr());
+    }
+
+    iroh::Endpoint::builder()
+        .relay_mode(iroh::RelayMode::Disabled)
+        .discovery(discovery)
+        .alpns(vec![kulfi_utils::APNS_IDENTITY.into()])
+        .bind()
+        .await
+        .unwrap()
+}
@@ -0,0 +1,120 @@
+//! hop-by-hop headers should not reach the exposed service, and the `X-Forwarded-*` headers should
+//! only reach it from trusted bridges, if any are configured.
+
+mod common;
+
+#[tokio::test]
+async fn test_hop_by_hop_headers_removed() {
+    let upstream = headers_server().await;
+
+    let peers = common::Peers::new().await;
+    peers.expose_http(upstream.to_string().into());
+    let bridge = peers.http_bridge().await;
+
+    let headers = http_get(
+        bridge,
+        &[
+            ("connection", "x-secret"),
+            ("x-secret", "1"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-authorization", "Basic Zm9vOmJhcg=="),
+            ("x-forwarded-for", "10.0.0.1"),
+            ("x-other", "kept"),
+        ],
+    )
+    .await;
+
+    for name in ["x-secret", "keep-alive", "proxy-authorization"] {
+        assert!(headers.get(name).is_none(), "{name} reached the upstream");
+    }
+    assert_eq!(headers["x-other"], "kept");
+    // no trusted bridges are configured, so the headers of every peer are passed on
+    assert_eq!(headers["x-forwarded-for"], "10.0.0.1");
+}
+
+#[tokio::test]
+async fn test_trusted_bridges() {
+    let upstream = headers_server().await;
+
+    let peers = common::Peers::new().await;
+    peers.expose_http_with(
+        upstream.to_string().into(),
+        kulfi_utils::PeerToHttpOptions {
+            trusted_bridges: vec![peers.bridge_id52()],
+            ..Default::default()
+        },
+    );
+    let bridge = peers.http_bridge().await;
+    let headers = http_get(bridge, &[("x-forwarded-for", "10.0.0.1")]).await;
+    assert_eq!(headers["x-forwarded-for"], "10.0.0.1");
+
+    let peers = common::Peers::new().await;
+    peers.expose_http_with(
+        upstream.to_string().into(),
+        kulfi_utils::PeerToHttpOptions {
+            trusted_bridges: vec![peers.exposer_id52()],
+            ..Default::default()
+        },
+    );
+    let bridge = peers.http_bridge().await;
+    let headers = http_get(bridge, &[("x-forwarded-for", "10.0.0.1")]).await;
+    assert!(headers.get("x-forwarded-for").is_none());
+}
+
+/// an HTTP server that responds with the headers it got, as a JSON object
+async fn headers_server() -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(
+                    |r: hyper::Request<hyper::body::Incoming>| async move {
+                        let headers: serde_json::Map<_, _> = r
+                            .headers()
+                            .iter()
+                            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().into()))
+                            .collect();
+                        Ok::<_, std::convert::Infallible>(hyper::Response::new(
+                            http_body_util::Full::new(hyper::body::Bytes::from(
+                                serde_json::Value::Object(headers).to_string(),
+                            )),
+                        ))
+                    },
+                );
+                let _ = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    addr
+}
+
+async fn http_get(bridge: std::net::SocketAddr, headers: &[(&str, &str)]) -> serde_json::Value {
+    use http_body_util::BodyExt;
+
+    let stream = tokio::net::TcpStream::connect(bridge).await.unwrap();
+    let (mut client, conn) =
+        hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(stream))
+            .await
+            .unwrap();
+    tokio::spawn(conn);
+
+    let mut req = hyper::Request::get("/").header("host", "localhost");
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+    let req = req
+        .body(http_body_util::Empty::<hyper::body::Bytes>::new())
+        .unwrap();
+    let resp = tokio::time::timeout(std::time::Duration::from_secs(30), client.send_request(req))
+        .await
+        .expect("timed out waiting for the response")
+        .unwrap();
+
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
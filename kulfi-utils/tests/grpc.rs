@@ -0,0 +1,93 @@
+//! gRPC needs HTTP/2 to the upstream and trailers in both directions, this tests both without
+//! pulling in a gRPC implementation.
+
+mod common;
+
+#[tokio::test]
+async fn test_trailers_over_h2c() {
+    use http_body_util::BodyExt;
+
+    let upstream = h2c_echo_server().await;
+
+    let peers = common::Peers::new().await;
+    peers.expose_http(kulfi_utils::HttpUpstream {
+        h2c: true,
+        ..upstream.to_string().into()
+    });
+    let bridge = peers.http_bridge().await;
+
+    let stream = tokio::net::TcpStream::connect(bridge).await.unwrap();
+    let (mut client, conn) = hyper::client::conn::http2::handshake(
+        hyper_util::rt::TokioExecutor::new(),
+        hyper_util::rt::TokioIo::new(stream),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(conn);
+
+    let mut request_trailers = hyper::HeaderMap::new();
+    request_trailers.insert("x-checksum", "42".parse().unwrap());
+    let frames: Vec<Result<_, std::convert::Infallible>> = vec![
+        Ok(hyper::body::Frame::data(hyper::body::Bytes::from("ping"))),
+        Ok(hyper::body::Frame::trailers(request_trailers)),
+    ];
+
+    let req = hyper::Request::post(format!("http://{bridge}/echo.Echo/Say"))
+        .header("te", "trailers")
+        .header("content-type", "application/grpc")
+        .body(http_body_util::StreamBody::new(futures_util::stream::iter(
+            frames,
+        )))
+        .unwrap();
+
+    let resp = tokio::time::timeout(std::time::Duration::from_secs(30), client.send_request(req))
+        .await
+        .expect("timed out waiting for the response")
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let body = resp.into_body().collect().await.unwrap();
+    let trailers = body.trailers().cloned().expect("response has no trailers");
+    assert_eq!(trailers["grpc-status"], "0");
+    // the upstream saw the trailers we sent
+    assert_eq!(trailers["x-checksum"], "42");
+    assert_eq!(body.to_bytes(), "ping");
+}
+
+/// an h2c server that sends back the request body, and the request trailers along with
+/// `grpc-status: 0` as response trailers
+async fn h2c_echo_server() -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let service =
+                    hyper::service::service_fn(|r: hyper::Request<hyper::body::Incoming>| async {
+                        use http_body_util::BodyExt;
+
+                        assert_eq!(r.version(), hyper::Version::HTTP_2);
+                        let body = r.into_body().collect().await?;
+                        let mut trailers = body.trailers().cloned().unwrap_or_default();
+                        trailers.insert("grpc-status", "0".parse().unwrap());
+
+                        let frames: Vec<Result<_, std::convert::Infallible>> = vec![
+                            Ok(hyper::body::Frame::data(body.to_bytes())),
+                            Ok(hyper::body::Frame::trailers(trailers)),
+                        ];
+                        Ok::<_, hyper::Error>(hyper::Response::new(
+                            http_body_util::StreamBody::new(futures_util::stream::iter(frames)),
+                        ))
+                    });
+
+                let _ =
+                    hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+                        .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                        .await;
+            });
+        }
+    });
+
+    addr
+}
@@ -0,0 +1,73 @@
+//! when the peer can not give us a stream, `get_stream()` should say why, and not make every
+//! request wait for a peer that is offline.
+
+mod common;
+
+#[tokio::test]
+async fn test_peer_refused() {
+    let peers = common::Peers::new().await;
+    // serves `Protocol::Http` only
+    peers.expose_http("127.0.0.1:1".to_string().into());
+
+    let e = kulfi_utils::get_stream(
+        peers.bridge.clone(),
+        kulfi_utils::Protocol::Tcp.into(),
+        peers.exposer_id52(),
+        peers.peer_connections.clone(),
+        peers.graceful.clone(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<kulfi_utils::http::ProxyError>(),
+        Some(&kulfi_utils::http::ProxyError::PeerRefused)
+    );
+
+    // the connection is still good for the protocol the peer does serve
+    peers.stream_to_exposer(kulfi_utils::Protocol::Http).await;
+}
+
+#[tokio::test]
+async fn test_peer_unreachable_backoff() {
+    let peers = common::Peers::new().await;
+    // nobody knows how to reach this one
+    let offline = kulfi_id52::SecretKey::generate().public_key().to_string();
+
+    let get_stream = || {
+        kulfi_utils::get_stream(
+            peers.bridge.clone(),
+            kulfi_utils::Protocol::Http.into(),
+            offline.clone(),
+            peers.peer_connections.clone(),
+            peers.graceful.clone(),
+        )
+    };
+
+    let e = get_stream().await.unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<kulfi_utils::http::ProxyError>(),
+        Some(&kulfi_utils::http::ProxyError::PeerUnreachable)
+    );
+
+    // we do not try again right away
+    let e = get_stream().await.unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<kulfi_utils::http::ProxyError>(),
+        Some(&kulfi_utils::http::ProxyError::PeerUnreachable)
+    );
+    assert!(format!("{e:?}").contains("backing off"), "{e:?}");
+
+    let e = kulfi_utils::get_stream(
+        peers.bridge.clone(),
+        kulfi_utils::Protocol::Http.into(),
+        "not-an-id52".to_string(),
+        peers.peer_connections.clone(),
+        peers.graceful.clone(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<kulfi_utils::http::ProxyError>(),
+        Some(&kulfi_utils::http::ProxyError::InvalidPeerId)
+    );
+}
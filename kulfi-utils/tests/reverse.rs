@@ -0,0 +1,80 @@
+//! the side that accepted a connection can open streams on it too, this is how reverse tunnels
+//! send connections back to the peer that asked for them.
+
+mod common;
+
+#[tokio::test]
+async fn test_stream_from_accepting_side() {
+    use tokio::io::AsyncReadExt;
+
+    let peers = common::Peers::new().await;
+    let ep = peers.exposer.clone();
+    tokio::spawn(async move {
+        let conn = ep.accept().await.unwrap().await.unwrap();
+        let (mut send, mut recv) =
+            kulfi_utils::accept_bi(&conn, ep.id(), kulfi_utils::Protocol::TcpReverse)
+                .await
+                .unwrap();
+        let port: String = kulfi_utils::next_json(&mut recv).await.unwrap();
+
+        // a stream back to the peer, for every connection we get
+        let (mut back, mut back_recv) =
+            kulfi_utils::open_bi(&conn, kulfi_utils::Protocol::Tcp.into())
+                .await
+                .unwrap();
+        back.write_all(format!("connection on {port}").as_bytes())
+            .await
+            .unwrap();
+        back.finish().unwrap();
+        back_recv.read_to_end(1024).await.unwrap();
+
+        // the peer refuses what it does not serve, and the connection stays up
+        let e = kulfi_utils::open_bi(&conn, kulfi_utils::Protocol::Http.into())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<kulfi_utils::http::ProxyError>(),
+            Some(&kulfi_utils::http::ProxyError::PeerRefused)
+        );
+        send.write_all(b"done").await.unwrap();
+        send.finish().unwrap();
+        conn.closed().await;
+    });
+
+    let conn = peers
+        .bridge
+        .connect(peers.exposer.id(), kulfi_utils::APNS_IDENTITY)
+        .await
+        .unwrap();
+    let (_send, mut recv) = kulfi_utils::open_bi(
+        &conn,
+        kulfi_utils::ProtocolHeader {
+            protocol: kulfi_utils::Protocol::TcpReverse,
+            extra: Some("\"2222\"".to_string()),
+        },
+    )
+    .await
+    .unwrap();
+
+    let (mut send, mut back) =
+        kulfi_utils::accept_bi(&conn, peers.bridge.id(), kulfi_utils::Protocol::Tcp)
+            .await
+            .unwrap();
+    let mut got = String::new();
+    back.read_to_string(&mut got).await.unwrap();
+    assert_eq!(got, "connection on 2222");
+    send.finish().unwrap();
+
+    // the refused `Http` stream is answered by `accept_bi()`, which is still waiting for `Tcp`
+    let accepting = tokio::spawn({
+        let conn = conn.clone();
+        let self_id = peers.bridge.id();
+        async move { kulfi_utils::accept_bi(&conn, self_id, kulfi_utils::Protocol::Tcp).await }
+    });
+    let mut done = String::new();
+    recv.read_to_string(&mut done).await.unwrap();
+    assert_eq!(done, "done");
+
+    accepting.abort();
+    conn.close(0u32.into(), b"done");
+}
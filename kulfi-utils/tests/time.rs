@@ -0,0 +1,69 @@
+//! every server answers `Protocol::WhatTimeIsIt` with its time, signed with its key.
+
+mod common;
+
+#[tokio::test]
+async fn test_what_time_is_it() {
+    let peers = common::Peers::new().await;
+    // the exposer only serves http, the time is answered by `accept_bi()` all the same
+    peers.expose_http("127.0.0.1:1".to_string().into());
+
+    // the exposer does not know its key yet, so it does not answer
+    let e = kulfi_utils::time::ask_time(&peers.bridge, &peers.exposer_id52())
+        .await
+        .unwrap_err();
+    assert!(e.to_string().contains("did not answer"), "{e:?}");
+
+    kulfi_utils::time::add_signing_key(&kulfi_id52::SecretKey::from_bytes(
+        &peers.exposer.secret_key().to_bytes(),
+    ));
+    let sample = kulfi_utils::time::ask_time(&peers.bridge, &peers.exposer_id52())
+        .await
+        .unwrap();
+    assert_eq!(sample.id52, peers.exposer_id52());
+    // the same clock, so the offset is within the round trip
+    assert!(
+        sample.offset.unsigned_abs() as u128 <= sample.rtt.as_micros(),
+        "{sample:?}"
+    );
+
+    let consensus = kulfi_utils::time::consensus(&[sample]).unwrap();
+    assert_eq!((consensus.agreeing, consensus.peers), (1, 1));
+}
+
+#[tokio::test]
+async fn test_no_time_for_other_identities() {
+    let peers = common::Peers::new().await;
+    peers.expose_http("127.0.0.1:1".to_string().into());
+
+    // another identity run by the same process, the exposer must not sign as it
+    let other = kulfi_id52::SecretKey::generate();
+    kulfi_utils::time::add_signing_key(&other);
+
+    let conn = peers
+        .bridge
+        .connect(peers.exposer.id(), kulfi_utils::APNS_IDENTITY)
+        .await
+        .unwrap();
+    let (_send, mut recv) = kulfi_utils::open_bi(
+        &conn,
+        kulfi_utils::ProtocolHeader {
+            protocol: kulfi_utils::Protocol::WhatTimeIsIt,
+            extra: Some(
+                serde_json::to_string(&kulfi_utils::time::TimeRequest {
+                    id52: other.id52(),
+                    nonce: "0".to_string(),
+                })
+                .unwrap(),
+            ),
+        },
+    )
+    .await
+    .unwrap();
+    // the stream is finished without an answer
+    assert!(
+        kulfi_utils::next_json::<kulfi_utils::time::SignedTime>(&mut recv)
+            .await
+            .is_err()
+    );
+}
@@ -0,0 +1,81 @@
+//! UDP services can be exposed, every flow gets a socket of its own on the exposer side, and the
+//! datagrams go as QUIC datagrams once both sides know the flow.
+
+mod common;
+
+#[tokio::test]
+async fn test_udp_flows() {
+    // an echo server, that also says which address the datagram came from
+    let service = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = service.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 1024];
+        while let Ok((n, from)) = service.recv_from(&mut buf).await {
+            let reply = format!("{from} {}", String::from_utf8_lossy(&buf[..n]));
+            service.send_to(reply.as_bytes(), from).await.unwrap();
+        }
+    });
+
+    let peers = common::Peers::new().await;
+    let ep = peers.exposer.clone();
+    tokio::spawn(async move {
+        while let Some(conn) = ep.accept().await {
+            let conn = conn.await.unwrap();
+            let addr = addr.clone();
+            let self_id = ep.id();
+            tokio::spawn(async move {
+                while let Ok((send, recv)) =
+                    kulfi_utils::accept_bi(&conn, self_id, kulfi_utils::Protocol::Udp).await
+                {
+                    let addr = addr.clone();
+                    let conn = conn.clone();
+                    tokio::spawn(async move {
+                        kulfi_utils::peer_to_udp(&addr, conn, send, recv)
+                            .await
+                            .unwrap();
+                    });
+                }
+            });
+        }
+    });
+
+    let mut sources = Vec::new();
+    let mut conns = Vec::new();
+    for _ in 0..2 {
+        let (conn, send, recv) = kulfi_utils::get_stream_with_connection(
+            peers.bridge.clone(),
+            kulfi_utils::Protocol::Udp.into(),
+            peers.exposer_id52(),
+            peers.peer_connections.clone(),
+            peers.graceful.clone(),
+        )
+        .await
+        .unwrap();
+        conns.push(conn.clone());
+        let (mut send, mut recv) = kulfi_utils::udp::opened_flow(conn, send, recv);
+        for datagram in ["one", "two", "three"] {
+            send.send(datagram.as_bytes()).await.unwrap();
+            let reply = tokio::time::timeout(std::time::Duration::from_secs(10), recv.recv())
+                .await
+                .expect("timed out waiting for the reply")
+                .unwrap()
+                .unwrap();
+            let reply = String::from_utf8(reply.to_vec()).unwrap();
+            let (source, echoed) = reply.split_once(' ').unwrap();
+            assert_eq!(echoed, datagram);
+            sources.push(source.to_string());
+        }
+
+        // the exposer closes its side of the flow once we close ours
+        send.finish().unwrap();
+        assert!(recv.recv().await.unwrap().is_none());
+    }
+
+    // the datagrams of a flow come from the same address, and the two flows from different ones
+    assert_eq!(sources[0], sources[2]);
+    assert_eq!(sources[3], sources[5]);
+    assert_ne!(sources[0], sources[3]);
+
+    // the replies told us the exposer reads QUIC datagrams, so the later ones went that way
+    assert!(conns.iter().any(|c| c.stats().frame_tx.datagram > 0));
+}
@@ -0,0 +1,124 @@
+//! services listening on unix domain sockets can be exposed over HTTP and TCP.
+
+#![cfg(unix)]
+
+mod common;
+
+#[tokio::test]
+async fn test_http_over_unix_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("http.sock");
+
+    let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(|r: hyper::Request<_>| async move {
+                    let body = format!("{} {}", r.method(), r.uri());
+                    Ok::<_, std::convert::Infallible>(hyper::Response::new(
+                        http_body_util::Full::new(hyper::body::Bytes::from(body)),
+                    ))
+                });
+                let _ = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    let peers = common::Peers::new().await;
+    peers.expose_http(kulfi_utils::HttpUpstream {
+        addr: kulfi_utils::UpstreamAddr::Unix(socket),
+        tls: None,
+        h2c: false,
+        connect_timeout: kulfi_utils::DEFAULT_CONNECT_TIMEOUT,
+        read_timeout: kulfi_utils::DEFAULT_READ_TIMEOUT,
+    });
+    let bridge = peers.http_bridge().await;
+
+    // two requests, the second one reuses the pooled connection
+    for path in ["/one", "/two?x=1"] {
+        let body = http_get(bridge, path).await;
+        assert_eq!(body, format!("GET {path}"));
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_over_unix_socket() {
+    use tokio::io::AsyncReadExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("tcp.sock");
+
+    // an echo server
+    let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+
+    let peers = common::Peers::new().await;
+    let ep = peers.exposer.clone();
+    tokio::spawn(async move {
+        while let Some(conn) = ep.accept().await {
+            let conn = conn.await.unwrap();
+            let socket = socket.clone();
+            let self_id = ep.id();
+            tokio::spawn(async move {
+                while let Ok((send, recv)) =
+                    kulfi_utils::accept_bi(&conn, self_id, kulfi_utils::Protocol::Tcp).await
+                {
+                    let socket = socket.clone();
+                    tokio::spawn(async move {
+                        kulfi_utils::peer_to_unix(&socket, send, recv)
+                            .await
+                            .unwrap();
+                    });
+                }
+            });
+        }
+    });
+
+    let (mut send, mut recv) = peers.stream_to_exposer(kulfi_utils::Protocol::Tcp).await;
+    send.write_all(b"hello over a unix socket").await.unwrap();
+    send.finish().unwrap();
+
+    let mut echoed = String::new();
+    recv.read_to_string(&mut echoed).await.unwrap();
+    assert_eq!(echoed, "hello over a unix socket");
+}
+
+async fn http_get(bridge: std::net::SocketAddr, path: &str) -> String {
+    use http_body_util::BodyExt;
+
+    let stream = tokio::net::TcpStream::connect(bridge).await.unwrap();
+    let (mut client, conn) =
+        hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(stream))
+            .await
+            .unwrap();
+    tokio::spawn(conn);
+
+    let req = hyper::Request::get(path)
+        .header("host", "localhost")
+        .body(http_body_util::Empty::<hyper::body::Bytes>::new())
+        .unwrap();
+    let resp = tokio::time::timeout(std::time::Duration::from_secs(30), client.send_request(req))
+        .await
+        .expect("timed out waiting for the response")
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    String::from_utf8(
+        resp.into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+            .to_vec(),
+    )
+    .unwrap()
+}
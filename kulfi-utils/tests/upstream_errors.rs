@@ -0,0 +1,135 @@
+//! when the upstream can not be reached or does not respond, the client of the bridge should get
+//! a 502 or 504 saying so instead of a dropped connection.
+
+mod common;
+
+#[tokio::test]
+async fn test_upstream_refused() {
+    // nothing listens on this port once the listener is dropped
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+
+    let peers = common::Peers::new().await;
+    peers.expose_http(kulfi_utils::HttpUpstream {
+        connect_timeout: std::time::Duration::from_secs(1),
+        ..addr.to_string().into()
+    });
+    let bridge = peers.http_bridge().await;
+
+    let (status, headers, body) = http_get(bridge, "/", "text/html").await;
+    assert_eq!(status, 502);
+    assert_eq!(
+        headers[kulfi_utils::http::ERROR_CODE_HEADER],
+        "upstream-unreachable"
+    );
+    assert!(body.contains("<h1>502 Bad Gateway</h1>"));
+
+    let (status, headers, body) = http_get(bridge, "/", "application/json").await;
+    assert_eq!(status, 502);
+    assert_eq!(headers[hyper::header::CONTENT_TYPE], "application/json");
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error"], "upstream-unreachable");
+}
+
+#[tokio::test]
+async fn test_upstream_timeout() {
+    // accepts connections but never responds
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut streams = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+
+    let peers = common::Peers::new().await;
+    peers.expose_http(kulfi_utils::HttpUpstream {
+        read_timeout: std::time::Duration::from_secs(1),
+        ..addr.to_string().into()
+    });
+    let bridge = peers.http_bridge().await;
+
+    let (status, headers, _body) = http_get(bridge, "/", "*/*").await;
+    assert_eq!(status, 504);
+    assert_eq!(
+        headers[kulfi_utils::http::ERROR_CODE_HEADER],
+        "upstream-timeout"
+    );
+}
+
+/// a connection that timed out waiting for the response still has the request in flight, it is
+/// not used for the next request
+#[tokio::test]
+async fn test_upstream_timeout_drops_connection() {
+    // `/slow` responds after the read timeout, anything else right away
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(
+                    |r: hyper::Request<hyper::body::Incoming>| async move {
+                        if r.uri().path() == "/slow" {
+                            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                        }
+                        Ok::<_, std::convert::Infallible>(hyper::Response::new(
+                            http_body_util::Full::new(hyper::body::Bytes::from("ok")),
+                        ))
+                    },
+                );
+                let _ = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    let peers = common::Peers::new().await;
+    peers.expose_http(kulfi_utils::HttpUpstream {
+        read_timeout: std::time::Duration::from_secs(1),
+        ..addr.to_string().into()
+    });
+    let bridge = peers.http_bridge().await;
+
+    let (status, _headers, _body) = http_get(bridge, "/slow", "*/*").await;
+    assert_eq!(status, 504);
+
+    let (status, _headers, body) = http_get(bridge, "/", "*/*").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, "ok");
+}
+
+async fn http_get(
+    bridge: std::net::SocketAddr,
+    path: &str,
+    accept: &str,
+) -> (hyper::StatusCode, hyper::HeaderMap, String) {
+    use http_body_util::BodyExt;
+
+    let stream = tokio::net::TcpStream::connect(bridge).await.unwrap();
+    let (mut client, conn) =
+        hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(stream))
+            .await
+            .unwrap();
+    tokio::spawn(conn);
+
+    let req = hyper::Request::get(path)
+        .header("host", "localhost")
+        .header("accept", accept)
+        .body(http_body_util::Empty::<hyper::body::Bytes>::new())
+        .unwrap();
+    let resp = tokio::time::timeout(std::time::Duration::from_secs(30), client.send_request(req))
+        .await
+        .expect("timed out waiting for the response")
+        .unwrap();
+
+    let (parts, body) = resp.into_parts();
+    let body = body.collect().await.unwrap().to_bytes();
+    (
+        parts.status,
+        parts.headers,
+        String::from_utf8(body.to_vec()).unwrap(),
+    )
+}
@@ -0,0 +1,63 @@
+//! websocket connections made to the bridge should reach the exposed service, and stay open for
+//! messages in both directions.
+
+mod common;
+
+#[tokio::test]
+async fn test_websocket_over_http_protocol() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let echo = echo_server().await;
+
+    let peers = common::Peers::new().await;
+    peers.expose_http(echo.to_string().into());
+    let bridge = peers.http_bridge().await;
+
+    let (mut ws, resp) = tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        tokio_tungstenite::connect_async(format!("ws://{bridge}/echo")),
+    )
+    .await
+    .expect("timed out connecting to the bridge")
+    .unwrap();
+    assert_eq!(resp.status(), 101);
+
+    for msg in ["hello", "world"] {
+        ws.send(Message::text(msg)).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text(msg));
+    }
+
+    ws.send(Message::binary(vec![0u8, 1, 2, 255]))
+        .await
+        .unwrap();
+    assert_eq!(
+        ws.next().await.unwrap().unwrap(),
+        Message::binary(vec![0u8, 1, 2, 255])
+    );
+
+    ws.close(None).await.unwrap();
+}
+
+/// a websocket server that sends back every message it gets
+async fn echo_server() -> std::net::SocketAddr {
+    use futures_util::StreamExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                let (write, read) = ws.split();
+                let _ = read
+                    .filter(|m| std::future::ready(m.as_ref().is_ok_and(|m| !m.is_close())))
+                    .forward(write)
+                    .await;
+            });
+        }
+    });
+
+    addr
+}
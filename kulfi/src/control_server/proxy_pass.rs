@@ -6,13 +6,10 @@ pub async fn proxy_pass(
     use eyre::WrapErr;
     use http_body_util::BodyExt;
 
-    let mut client = match pool.get().await {
-        Ok(v) => v,
-        Err(e) => {
-            tracing::error!("proxy_pass: failed to get connection: {e:?}");
-            return Err(eyre::anyhow!("proxy_pass: failed to get connection: {e:?}"));
-        }
-    };
+    // has to be called before we take the request apart, it resolves once we have responded with
+    // `101 Switching Protocols`.
+    let on_upgrade =
+        kulfi_utils::http::is_upgrade(req.headers()).then(|| hyper::upgrade::on(&mut req));
 
     let path_query = req
         .uri()
@@ -29,10 +26,47 @@ pub async fn proxy_pass(
             .boxed()
     });
 
-    let resp = client
-        .send_request(req)
-        .await
-        .wrap_err_with(|| "failed to send request")?;
+    let resp = if on_upgrade.is_some() {
+        // an upgraded connection can not go back to the pool, so it gets a connection of its own
+        let mut client = kulfi_utils::HttpConnectionManager::new(addr)
+            .connect()
+            .await?;
+        client.send_request(req).await
+    } else {
+        let mut client = match pool.get().await {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::error!("proxy_pass: failed to get connection: {e:?}");
+                return Err(eyre::anyhow!("proxy_pass: failed to get connection: {e:?}"));
+            }
+        };
+        client.send_request(req).await
+    };
+
+    let mut resp = resp.wrap_err_with(|| "failed to send request")?;
+
+    if let Some(on_upgrade) = on_upgrade
+        && resp.status() == hyper::StatusCode::SWITCHING_PROTOCOLS
+    {
+        let upstream = hyper::upgrade::on(&mut resp);
+        tokio::spawn(async move {
+            let (client, upstream) = match tokio::try_join!(on_upgrade, upstream) {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::error!("proxy_pass: failed to upgrade connection: {e:?}");
+                    return;
+                }
+            };
+            if let Err(e) = tokio::io::copy_bidirectional(
+                &mut hyper_util::rt::TokioIo::new(client),
+                &mut hyper_util::rt::TokioIo::new(upstream),
+            )
+            .await
+            {
+                tracing::error!("proxy_pass: upgraded connection failed: {e:?}");
+            }
+        });
+    }
 
     let resp = resp.map(|b| {
         b.map_err(|e| eyre::anyhow!("failed to read request body: {e}"))
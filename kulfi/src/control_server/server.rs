@@ -17,7 +17,7 @@ pub async fn handle_connection(
     // let builder = hyper::server::conn::http2::Builder::new(hyper_util::rt::tokio::TokioExecutor::new());
     tokio::pin! {
         let conn = builder
-            .serve_connection(
+            .serve_connection_with_upgrades(
                 io,
                 // http/1.1 allows https://en.wikipedia.org/wiki/HTTP_pipelining
                 // but hyper does not, https://github.com/hyperium/hyper/discussions/2747:
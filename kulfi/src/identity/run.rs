@@ -7,6 +7,8 @@ impl kulfi::Identity {
         graceful: kulfi_utils::Graceful,
         id_map: kulfi_utils::IDMap,
         data_dir: &std::path::Path,
+        forward_peer_id52: bool,
+        sign_peer_id52: bool,
     ) -> eyre::Result<()> {
         let port = start_fastn(
             std::sync::Arc::clone(&id_map),
@@ -24,6 +26,16 @@ impl kulfi::Identity {
 
         let secret_key = kulfi_utils::get_secret_key(self.id52.as_str(), "todo")
             .wrap_err_with(|| "failed to read or secret key")?;
+        // with these fastn gets to know which peer is making the request, signed with the
+        // identity key so it can tell the header came from us.
+        let options = kulfi_utils::PeerToHttpOptions {
+            forward_peer_id52: forward_peer_id52 || sign_peer_id52,
+            signing_key: sign_peer_id52.then(|| {
+                std::sync::Arc::new(kulfi_id52::SecretKey::from_bytes(&secret_key.to_bytes()))
+            }),
+            access_log: access_log(&self.id52, data_dir),
+            ..Default::default()
+        };
         let ep = kulfi_utils::get_endpoint(secret_key)
             .await
             .wrap_err_with(|| "failed to bind to iroh network")?;
@@ -32,7 +44,32 @@ impl kulfi::Identity {
             id_map.lock().await.push((self.id52, (port, ep.clone())));
         }
 
-        kulfi::peer_server::run(ep, port, self.client_pools.clone(), graceful).await
+        kulfi::peer_server::run(
+            ep,
+            port,
+            self.client_pools.clone(),
+            options,
+            kulfi_utils::DEFAULT_MAX_CONCURRENT_REQUESTS,
+            graceful,
+        )
+        .await
+    }
+}
+
+/// every request served to peers is recorded in `identities/<id52>/access.log`, as JSON lines
+fn access_log(id52: &str, data_dir: &std::path::Path) -> kulfi_utils::AccessLog {
+    let path = data_dir.join("identities").join(id52).join("access.log");
+    match kulfi_utils::FileAccessLog::new(
+        &path,
+        kulfi_utils::AccessLogFormat::Json,
+        Some(10 * 1024 * 1024),
+        5,
+    ) {
+        Ok(v) => kulfi_utils::AccessLog::new(std::sync::Arc::new(v), kulfi_utils::Protocol::Http),
+        Err(e) => {
+            tracing::error!("failed to open access log, requests will not be recorded: {e:?}");
+            kulfi_utils::AccessLog::default()
+        }
     }
 }
 
@@ -18,6 +18,8 @@ async fn main() -> eyre::Result<()> {
             foreground,
             data_dir,
             control_port,
+            forward_peer_id52,
+            sign_peer_id52,
         }) => {
             let data_dir = match data_dir {
                 Some(dir) => dir.into(),
@@ -32,7 +34,15 @@ async fn main() -> eyre::Result<()> {
                 },
             };
 
-            kulfi::start(foreground, data_dir, control_port, graceful.clone()).await
+            kulfi::start(
+                foreground,
+                data_dir,
+                control_port,
+                forward_peer_id52,
+                sign_peer_id52,
+                graceful.clone(),
+            )
+            .await
         }
         Some(Command::Browse { url }) => {
             tracing::info!(url, verbose = ?cli.verbose, "Opening browser.");
@@ -40,7 +50,7 @@ async fn main() -> eyre::Result<()> {
         }
         None => {
             tracing::info!(verbose = ?cli.verbose, "Starting UI.");
-            kulfi::ui()
+            ui(cli.key_file).await
         }
     } {
         tracing::error!("Error: {e:?}");
@@ -49,6 +59,17 @@ async fn main() -> eyre::Result<()> {
     graceful.shutdown().await
 }
 
+/// the UI reaches peers through the global endpoint, so the identity has to be set up before it
+/// starts
+async fn ui(key_file: Option<std::path::PathBuf>) -> eyre::Result<()> {
+    if let Some(path) = key_file {
+        let (id52, secret_key) = kulfi_utils::read_key_file(&path).await?;
+        tracing::info!(id52, "browsing as");
+        kulfi_utils::set_global_identity(secret_key).await?;
+    }
+    kulfi::ui()
+}
+
 #[derive(clap::Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
@@ -58,6 +79,12 @@ pub struct Cli {
     #[command(subcommand)]
     pub command: Option<Command>,
 
+    #[arg(
+        long,
+        help = "Browse as the identity in this file, as written by `malai keygen --file`, so peers can recognise us. Without it a new id52 is used every time."
+    )]
+    key_file: Option<std::path::PathBuf>,
+
     // adding these two because when we run `cargo tauri dev,` it automatically passes these
     // arguments. need to figure out why and how to disable that, till then this is a workaround
     #[arg(default_value = "true", long, hide = true)]
@@ -76,6 +103,16 @@ pub enum Command {
         data_dir: Option<String>,
         #[arg(default_value_t = 80, long, short = 'p')]
         control_port: u16,
+        #[arg(
+            long,
+            help = "Add the X-Kulfi-Peer-Id52 header, with the id52 of the peer making the request, to every request sent to fastn."
+        )]
+        forward_peer_id52: bool,
+        #[arg(
+            long,
+            help = "Like --forward-peer-id52, but also add X-Kulfi-Peer-Timestamp and X-Kulfi-Peer-Signature headers, signed with the key of the identity."
+        )]
+        sign_peer_id52: bool,
     },
     #[clap(about = "Browse a kulfi site.")]
     Browse { url: String },
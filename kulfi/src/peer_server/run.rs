@@ -2,6 +2,8 @@ pub async fn run(
     ep: iroh::Endpoint,
     fastn_port: u16,
     client_pools: kulfi_utils::HttpConnectionPools,
+    options: kulfi_utils::PeerToHttpOptions,
+    max_concurrent_requests: usize,
     graceful: kulfi_utils::Graceful,
 ) -> eyre::Result<()> {
     loop {
@@ -13,6 +15,9 @@ pub async fn run(
             }
         };
         let client_pools = client_pools.clone();
+        let options = options.clone();
+        let graceful_for_connection = graceful.clone();
+        let self_id = ep.id();
         graceful.spawn(async move {
             let start = std::time::Instant::now();
             let conn = match conn.await {
@@ -26,7 +31,17 @@ pub async fn run(
             //     tracing::error!("failed to enqueue connection: {:?}", e);
             //     return;
             // }
-            if let Err(e) = handle_connection(conn, client_pools, fastn_port).await {
+            if let Err(e) = handle_connection(
+                conn,
+                self_id,
+                client_pools,
+                fastn_port,
+                options,
+                max_concurrent_requests,
+                graceful_for_connection,
+            )
+            .await
+            {
                 tracing::error!("connection error3: {:?}", e);
             }
             tracing::info!("connection handled in {:?}", start.elapsed());
@@ -57,30 +72,48 @@ pub async fn run(
 
 pub async fn handle_connection(
     conn: iroh::endpoint::Connection,
+    self_id: iroh::EndpointId,
     client_pools: kulfi_utils::HttpConnectionPools,
     fastn_port: u16,
+    options: kulfi_utils::PeerToHttpOptions,
+    max_concurrent_requests: usize,
+    graceful: kulfi_utils::Graceful,
 ) -> eyre::Result<()> {
     tracing::info!("got connection from: {:?}", conn.remote_id());
     let remote_id52 = kulfi_utils::get_remote_id52(&conn);
+    let upstream: kulfi_utils::HttpUpstream = format!("127.0.0.1:{fastn_port}").into();
+    // every stream is served in its own task, accept_bi() keeps answering pings meanwhile, and
+    // only `max_concurrent_requests` of them run at once
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(max_concurrent_requests));
     tracing::info!("new client: {remote_id52}, waiting for bidirectional stream");
     loop {
         let client_pools = client_pools.clone();
-        // TODO: graceful shutdown
-        let (mut send, recv) = kulfi_utils::accept_bi(&conn, kulfi_utils::Protocol::Http)
+        let (mut send, recv) = kulfi_utils::accept_bi(&conn, self_id, kulfi_utils::Protocol::Http)
             .await
             .inspect_err(|e| tracing::error!("failed to accept bidirectional stream: {e:?}"))?;
         tracing::info!("{remote_id52}");
-        if let Err(e) = kulfi_utils::peer_to_http(
-            &format!("127.0.0.1:{fastn_port}"),
-            client_pools,
-            &mut send,
-            recv,
-        )
-        .await
-        {
-            tracing::error!("failed to proxy http: {e:?}");
-        }
-        tracing::info!("closing send stream");
-        send.finish()?;
+        let upstream = upstream.clone();
+        let options = options.clone();
+        let remote_id52 = remote_id52.clone();
+        let semaphore = semaphore.clone();
+        graceful.spawn(async move {
+            let _permit = semaphore.acquire().await;
+            if let Err(e) = kulfi_utils::peer_to_http(
+                &upstream,
+                &remote_id52,
+                &options,
+                client_pools,
+                &mut send,
+                recv,
+            )
+            .await
+            {
+                tracing::error!("failed to proxy http: {e:?}");
+            }
+            tracing::info!("closing send stream");
+            if let Err(e) = send.finish() {
+                tracing::error!("failed to finish send stream: {e:?}");
+            }
+        });
     }
 }
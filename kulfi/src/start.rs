@@ -6,10 +6,15 @@
 /// identities folder, and set-up http device driver for each of them.
 ///
 /// it also has to start the device "drivers" for every device in the <identities>/devices folder.
+///
+/// `forward_peer_id52` and `sign_peer_id52` tell fastn which peer made the request, see
+/// `kulfi_utils::PeerToHttpOptions`.
 pub async fn start(
     _fg: bool,
     data_dir: std::path::PathBuf,
     control_port: u16,
+    forward_peer_id52: bool,
+    sign_peer_id52: bool,
     graceful: kulfi_utils::Graceful,
 ) -> eyre::Result<()> {
     use eyre::WrapErr;
@@ -51,7 +56,16 @@ pub async fn start(
         let data_dir = data_dir.clone();
         graceful.spawn(async move {
             let public_key = identity.public_key;
-            if let Err(e) = identity.run(graceful_for_run, id_map, &data_dir).await {
+            if let Err(e) = identity
+                .run(
+                    graceful_for_run,
+                    id_map,
+                    &data_dir,
+                    forward_peer_id52,
+                    sign_peer_id52,
+                )
+                .await
+            {
                 tracing::error!("failed to run identity: {public_key}: {e:?}");
             }
         });
@@ -40,6 +40,7 @@ pub fn ui() -> eyre::Result<()> {
 
                 let graceful = kulfi_utils::Graceful::default();
                 let peer_connections = kulfi_utils::PeerStreamSenders::default();
+                // the identity from --key-file, if main() set one up
                 let response = kulfi_utils::http_to_peer_non_streaming(
                     kulfi_utils::Protocol::Http.into(),
                     request,
@@ -0,0 +1,143 @@
+/// QUIC application error code used when we close a connection from a peer that is not allowed.
+pub const PEER_NOT_ALLOWED: u32 = 403;
+
+/// PeerAcl decides which peers can access an exposed service.
+///
+/// by default every peer is allowed, this is what `--public` means. passing `--allow` or
+/// `--allow-file` switches the service to "only these peers", and `--deny` or `--deny-file` blocks
+/// specific peers. deny always wins over allow.
+///
+/// the files contain one id52 per line, empty lines and anything after `#` are ignored. the files
+/// are re-read when they change, so a teammate can be added without restarting the share.
+#[derive(Clone, Default)]
+pub struct PeerAcl {
+    allow: Option<std::sync::Arc<IdList>>,
+    deny: Option<std::sync::Arc<IdList>>,
+}
+
+impl PeerAcl {
+    pub async fn new(
+        allow: Vec<String>,
+        allow_file: Option<String>,
+        deny: Vec<String>,
+        deny_file: Option<String>,
+    ) -> eyre::Result<Self> {
+        Ok(Self {
+            allow: IdList::new(allow, allow_file)
+                .await?
+                .map(std::sync::Arc::new),
+            deny: IdList::new(deny, deny_file).await?.map(std::sync::Arc::new),
+        })
+    }
+
+    /// true if only the peers in the allow list can access the service
+    pub fn is_restricted(&self) -> bool {
+        self.allow.is_some()
+    }
+
+    pub async fn check(&self, id52: &str) -> eyre::Result<()> {
+        if let Some(deny) = &self.deny
+            && deny.contains(id52).await
+        {
+            return Err(eyre::anyhow!("peer is in the deny list"));
+        }
+
+        if let Some(allow) = &self.allow
+            && !allow.contains(id52).await
+        {
+            return Err(eyre::anyhow!("peer is not in the allow list"));
+        }
+
+        Ok(())
+    }
+
+    /// guard() must be called for every incoming connection before accepting any bidirectional
+    /// stream on it. if the remote peer is not allowed, the connection is closed with the reason.
+    pub async fn guard(&self, conn: &iroh::endpoint::Connection) -> eyre::Result<()> {
+        let remote_id52 = kulfi_utils::get_remote_id52(conn);
+
+        if let Err(e) = self.check(&remote_id52).await {
+            tracing::warn!(remote_id52, "rejecting connection: {e}");
+            eprintln!("Rejected connection from {remote_id52}: {e}");
+            conn.close(
+                iroh::endpoint::VarInt::from_u32(PEER_NOT_ALLOWED),
+                e.to_string().as_bytes(),
+            );
+            return Err(e);
+        }
+
+        Ok(())
+    }
+}
+
+struct IdList {
+    ids: std::collections::HashSet<String>,
+    file: Option<malai::WatchedFile<std::collections::HashSet<String>>>,
+}
+
+impl IdList {
+    async fn new(ids: Vec<String>, file: Option<String>) -> eyre::Result<Option<Self>> {
+        if ids.is_empty() && file.is_none() {
+            return Ok(None);
+        }
+
+        let ids = parse_id_list(&ids.join("\n"))?;
+        let file = match file {
+            Some(path) => Some(malai::WatchedFile::new(path, parse_id_list).await?),
+            None => None,
+        };
+
+        Ok(Some(Self { ids, file }))
+    }
+
+    async fn contains(&self, id52: &str) -> bool {
+        if self.ids.contains(id52) {
+            return true;
+        }
+
+        match &self.file {
+            Some(file) => file.get().await.contains(id52),
+            None => false,
+        }
+    }
+}
+
+fn parse_id_list(content: &str) -> eyre::Result<std::collections::HashSet<String>> {
+    use std::str::FromStr;
+
+    let mut ids = std::collections::HashSet::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let id52 = line.split('#').next().unwrap_or_default().trim();
+        if id52.is_empty() {
+            continue;
+        }
+
+        kulfi_id52::PublicKey::from_str(id52)
+            .map_err(|e| eyre::anyhow!("line {}: invalid id52 {id52:?}: {e}", i + 1))?;
+        ids.insert(id52.to_string());
+    }
+
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_id_list;
+
+    #[test]
+    fn test_parse_id_list() {
+        let ids = parse_id_list(
+            "# teammates\n\
+             i66fo538lfl5ombdf6tcdbrabp4hmp9asv7nrffuc2im13ct4q60\n\
+             \n\
+             e87aeds2fajaeu10tjdio5ppcdha410n6tu4665u7el9as9b7v80 # laptop\n",
+        )
+        .unwrap();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains("e87aeds2fajaeu10tjdio5ppcdha410n6tu4665u7el9as9b7v80"));
+
+        assert!(parse_id_list("not-an-id52").is_err());
+        assert!(parse_id_list("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,1138 @@
+/// BridgeCache keeps the responses of peers, so `malai http-bridge` can answer requests for a
+/// popular share without going to the peer every time, which is often on a home connection.
+///
+/// it is a shared cache as described in RFC 9111, within reason:
+///
+/// - only `GET` requests without `Authorization` or `Range` are looked up, and a response is only
+///   stored if it says how long it is good for (`s-maxage`, `max-age` or `Expires`), or has an
+///   `ETag` or `Last-Modified` so we can ask the peer if our copy is still good. responses that are
+///   `private`, `no-store`, set cookies or vary on `*` are never stored.
+/// - responses are kept per id52, host and url, and per the values of the request headers listed in
+///   `Vary`.
+/// - a stale response is served right away if its `stale-while-revalidate` allows, and refreshed in
+///   the background. when the peer can not be reached, or the service answers with a 500, 502, 503
+///   or 504, stale responses are served for `stale_if_error` (or the response's own
+///   `stale-if-error`), unless the response says `must-revalidate`.
+/// - bodies are kept in memory, the least recently used ones move to `dir` when there is no room
+///   left, and are dropped once that is full too. the index is only in memory, so the directory is
+///   emptied on start.
+/// - `PURGE <path>` requests with the token from `--purge-token` drop the stored responses for the
+///   path, a path ending in `*` drops everything under it. successful `POST`, `PUT`, `PATCH` and
+///   `DELETE` requests drop the stored responses for their url.
+#[derive(Clone)]
+pub struct BridgeCache {
+    inner: std::sync::Arc<Inner>,
+}
+
+pub struct CacheConfig {
+    /// bytes of response bodies kept in memory
+    pub memory_size: u64,
+    /// where bodies go when there is no room left in memory
+    pub dir: Option<std::path::PathBuf>,
+    /// bytes of response bodies kept in `dir`
+    pub disk_size: u64,
+    /// larger responses are not stored
+    pub max_entry_size: u64,
+    /// how long stale responses are served for when the peer is not available
+    pub stale_if_error: std::time::Duration,
+    /// `PURGE` requests must send this as `Authorization: Bearer <token>`, without it `PURGE` is
+    /// refused
+    pub purge_token: Option<String>,
+}
+
+/// tells the client if the response came from the cache: `hit`, `stale`, `revalidated`, `miss` or
+/// `bypass`.
+pub const CACHE_STATUS_HEADER: &str = "x-kulfi-cache";
+
+/// the statuses we store without being told how long they are good for, RFC 9110 calls them
+/// "heuristically cacheable". we do not store the others at all, to keep things simple.
+const CACHEABLE_STATUSES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// the longest a response with only `Last-Modified` is considered fresh
+const MAX_HEURISTIC_LIFETIME: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+struct Inner {
+    config: CacheConfig,
+    state: std::sync::Mutex<State>,
+}
+
+/// the peer a request is for, and what we need to send it there
+pub struct Peer {
+    pub id52: String,
+    pub self_endpoint: iroh::Endpoint,
+    pub peer_connections: kulfi_utils::PeerStreamSenders,
+    pub graceful: kulfi_utils::Graceful,
+}
+
+impl Peer {
+    pub async fn forward(
+        &self,
+        r: hyper::Request<hyper::body::Incoming>,
+    ) -> kulfi_utils::http::ProxyResult<eyre::Error> {
+        let start = std::time::Instant::now();
+        let r = kulfi_utils::http_to_peer(
+            kulfi_utils::Protocol::Http.into(),
+            r,
+            self.self_endpoint.clone(),
+            &self.id52,
+            self.peer_connections.clone(),
+            self.graceful.clone(),
+        )
+        .await;
+        kulfi_utils::metrics::request_forwarded(kulfi_utils::Protocol::Http, start.elapsed());
+        r
+    }
+
+    /// used to refresh stale responses in the background, when there is no client request to pass
+    /// on. the body is streamed like for `forward()`, so we never read more of it than we store.
+    async fn fetch(
+        &self,
+        r: hyper::Request<hyper::body::Bytes>,
+    ) -> kulfi_utils::http::ProxyResult<eyre::Error> {
+        kulfi_utils::http_to_peer(
+            kulfi_utils::Protocol::Http.into(),
+            r.map(http_body_util::Full::new),
+            self.self_endpoint.clone(),
+            &self.id52,
+            self.peer_connections.clone(),
+            self.graceful.clone(),
+        )
+        .await
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// `<id52> <host> <path and query>` to the stored variants of the response
+    entries: std::collections::HashMap<String, Vec<Entry>>,
+    memory_used: u64,
+    disk_used: u64,
+    /// bumped on every lookup, for finding the least recently used entries
+    clock: u64,
+    next_id: u64,
+    /// keys being refreshed in the background, so we refresh each one once
+    refreshing: std::collections::HashSet<String>,
+}
+
+struct Entry {
+    id: u64,
+    /// the request headers named in `Vary`, and their values
+    vary: Vec<(
+        hyper::header::HeaderName,
+        Option<hyper::header::HeaderValue>,
+    )>,
+    status: hyper::StatusCode,
+    headers: hyper::HeaderMap,
+    memory: Option<hyper::body::Bytes>,
+    disk: Option<std::path::PathBuf>,
+    /// being written to disk
+    spilling: bool,
+    size: u64,
+    /// when the response was generated, we got it minus its `Age`
+    date: std::time::Instant,
+    policy: Policy,
+    last_used: u64,
+}
+
+impl Entry {
+    fn age(&self) -> std::time::Duration {
+        self.date.elapsed()
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.age() < self.policy.lifetime
+    }
+
+    /// can this be served while it is refreshed in the background
+    fn can_serve_while_revalidating(&self) -> bool {
+        self.age() < self.policy.lifetime + self.policy.stale_while_revalidate
+    }
+
+    fn can_serve_on_error(&self) -> bool {
+        self.age() < self.policy.lifetime + self.policy.stale_if_error
+    }
+
+    fn matches(&self, request: &hyper::HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request.get(name) == value.as_ref())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Policy {
+    lifetime: std::time::Duration,
+    stale_while_revalidate: std::time::Duration,
+    stale_if_error: std::time::Duration,
+}
+
+/// what we found for a request, a copy so we can let go of the lock
+struct Cached {
+    key: String,
+    id: u64,
+    status: hyper::StatusCode,
+    headers: hyper::HeaderMap,
+    body: Body,
+    age: std::time::Duration,
+    fresh: bool,
+    can_serve_while_revalidating: bool,
+    can_serve_on_error: bool,
+}
+
+enum Body {
+    Memory(hyper::body::Bytes),
+    Disk(std::path::PathBuf),
+}
+
+impl BridgeCache {
+    pub async fn new(config: CacheConfig) -> eyre::Result<Self> {
+        use eyre::WrapErr;
+
+        if let Some(dir) = &config.dir {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .wrap_err_with(|| format!("failed to create the cache dir {dir:?}"))?;
+
+            // left over from the last run, we do not know what is in them. the files are named
+            // after the entry id, we leave anything else in the directory alone.
+            let mut files = tokio::fs::read_dir(dir).await?;
+            while let Some(file) = files.next_entry().await? {
+                if file.file_type().await?.is_file()
+                    && file.file_name().to_string_lossy().parse::<u64>().is_ok()
+                {
+                    tokio::fs::remove_file(file.path())
+                        .await
+                        .wrap_err_with(|| format!("failed to empty the cache dir {dir:?}"))?;
+                }
+            }
+        }
+
+        Ok(Self {
+            inner: std::sync::Arc::new(Inner {
+                config,
+                state: Default::default(),
+            }),
+        })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        // the state is consistent between statements, at worst a size is off after a panic
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// answers `r` from the cache if we can, otherwise forwards it to the peer, and stores the
+    /// response if it can be
+    pub async fn serve(
+        &self,
+        peer: Peer,
+        mut r: hyper::Request<hyper::body::Incoming>,
+    ) -> kulfi_utils::http::ProxyResult<eyre::Error> {
+        let key = cache_key(&peer.id52, &r);
+
+        if r.method() != hyper::Method::GET {
+            let unsafe_method = !matches!(
+                *r.method(),
+                hyper::Method::HEAD | hyper::Method::OPTIONS | hyper::Method::TRACE
+            );
+            let path = r.uri().path().to_string();
+            let resp = peer.forward(r).await?;
+            if unsafe_method && (resp.status().is_success() || resp.status().is_redirection()) {
+                self.purge(&peer.id52, &path);
+            }
+            return Ok(with_status(resp, "bypass"));
+        }
+
+        let request_cc = CacheControl::parse(r.headers());
+        if request_cc.no_store
+            || r.headers().contains_key(hyper::header::AUTHORIZATION)
+            || r.headers().contains_key(hyper::header::RANGE)
+        {
+            return Ok(with_status(peer.forward(r).await?, "bypass"));
+        }
+
+        // `no-cache` from the client means it wants us to check with the peer
+        let cached = self.lookup(&key, r.headers());
+        if let Some(cached) = &cached
+            && !request_cc.no_cache
+        {
+            if cached.fresh {
+                if let Some(resp) = self.respond(cached, "hit").await {
+                    return Ok(resp);
+                }
+            } else if cached.can_serve_while_revalidating
+                && let Some(resp) = self.respond(cached, "stale").await
+            {
+                self.refresh(peer, &key, &r);
+                return Ok(resp);
+            }
+        }
+
+        // if the client has its own validators, the 304 is for the client and not for us
+        let client_conditional = r.headers().contains_key(hyper::header::IF_NONE_MATCH)
+            || r.headers().contains_key(hyper::header::IF_MODIFIED_SINCE);
+        let request_headers = r.headers().clone();
+        let revalidating = match &cached {
+            Some(cached) if !client_conditional => add_validators(r.headers_mut(), &cached.headers),
+            _ => false,
+        };
+
+        let (uri, version) = (r.uri().clone(), r.version());
+        let resp = peer.forward(r).await?;
+
+        if let Some(cached) = &cached {
+            if revalidating && resp.status() == hyper::StatusCode::NOT_MODIFIED {
+                self.revalidated(cached, resp.headers());
+                if let Some(cached) = self.lookup(&key, &request_headers)
+                    && let Some(resp) = self.respond(&cached, "revalidated").await
+                {
+                    return Ok(resp);
+                }
+
+                // our copy is gone since we asked, say it was evicted, or its file can not be
+                // read. the 304 means nothing to the client, it did not send the validators, so
+                // we ask again without them.
+                let mut request = hyper::Request::new(hyper::body::Bytes::new());
+                *request.uri_mut() = uri;
+                *request.version_mut() = version;
+                *request.headers_mut() = request_headers.clone();
+                return self.refetch(&peer, &key, request).await;
+            }
+
+            // the peer is offline, or the service is down, the errors `stale-if-error` is for
+            if matches!(resp.status().as_u16(), 500 | 502 | 503 | 504)
+                && cached.can_serve_on_error
+                && let Some(resp) = self.respond(cached, "stale").await
+            {
+                return Ok(resp);
+            }
+        }
+
+        Ok(self.store_on_end(key, &request_headers, resp))
+    }
+
+    /// true if the `Authorization` header of a `PURGE` request has our `purge_token`
+    pub fn can_purge(&self, headers: &hyper::HeaderMap) -> bool {
+        let Some(token) = &self.inner.config.purge_token else {
+            return false;
+        };
+        headers
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|v| v.trim() == token)
+    }
+
+    /// drops the stored responses of a peer for `path`, or everything under it if it ends with `*`,
+    /// and returns how many were dropped
+    pub fn purge(&self, id52: &str, path: &str) -> usize {
+        let matches = |p: &str| {
+            let p = p.split('?').next().unwrap_or_default();
+            match path.strip_suffix('*') {
+                Some(prefix) => p.starts_with(prefix),
+                None => p == path,
+            }
+        };
+
+        let mut state = self.state();
+        let keys: Vec<_> = state
+            .entries
+            .keys()
+            .filter(|key| {
+                let mut parts = key.splitn(3, ' ');
+                parts.next() == Some(id52) && parts.nth(1).is_some_and(matches)
+            })
+            .cloned()
+            .collect();
+
+        let mut removed = vec![];
+        for key in keys {
+            if let Some(entries) = state.entries.remove(&key) {
+                for entry in entries {
+                    state.forget(&entry);
+                    removed.push(entry);
+                }
+            }
+        }
+        drop(state);
+
+        let count = removed.len();
+        delete_files(removed);
+        count
+    }
+
+    fn lookup(&self, key: &str, request: &hyper::HeaderMap) -> Option<Cached> {
+        let mut state = self.state();
+        state.clock += 1;
+        let clock = state.clock;
+
+        let entry = state
+            .entries
+            .get_mut(key)?
+            .iter_mut()
+            .find(|e| e.matches(request))?;
+        entry.last_used = clock;
+
+        let body = match (&entry.memory, &entry.disk) {
+            (Some(bytes), _) => Body::Memory(bytes.clone()),
+            (None, Some(path)) => Body::Disk(path.clone()),
+            (None, None) => return None,
+        };
+
+        Some(Cached {
+            key: key.to_string(),
+            id: entry.id,
+            status: entry.status,
+            headers: entry.headers.clone(),
+            body,
+            age: entry.age(),
+            fresh: entry.is_fresh(),
+            can_serve_while_revalidating: entry.can_serve_while_revalidating(),
+            can_serve_on_error: entry.can_serve_on_error(),
+        })
+    }
+
+    async fn respond(
+        &self,
+        cached: &Cached,
+        status: &'static str,
+    ) -> Option<kulfi_utils::http::ProxyResponse<eyre::Error>> {
+        use http_body_util::BodyExt;
+
+        let body = match &cached.body {
+            Body::Memory(bytes) => bytes.clone(),
+            Body::Disk(path) => match tokio::fs::read(path).await {
+                Ok(v) => v.into(),
+                Err(e) => {
+                    tracing::error!("failed to read cached response {path:?}: {e:?}");
+                    self.remove(&cached.key, cached.id);
+                    return None;
+                }
+            },
+        };
+
+        let mut resp = hyper::Response::new(
+            http_body_util::Full::new(body)
+                .map_err(|e| match e {})
+                .boxed(),
+        );
+        *resp.status_mut() = cached.status;
+        *resp.headers_mut() = cached.headers.clone();
+        resp.headers_mut()
+            .insert(hyper::header::AGE, cached.age.as_secs().into());
+        Some(with_status(resp, status))
+    }
+
+    /// forwards a request we already forwarded once, and stores the response if it can be
+    async fn refetch(
+        &self,
+        peer: &Peer,
+        key: &str,
+        request: hyper::Request<hyper::body::Bytes>,
+    ) -> kulfi_utils::http::ProxyResult<eyre::Error> {
+        let request_headers = request.headers().clone();
+        let resp = peer.fetch(request).await?;
+        Ok(self.store_on_end(key.to_string(), &request_headers, resp))
+    }
+
+    /// asks the peer for a fresh copy of a stale response, in the background
+    fn refresh(&self, peer: Peer, key: &str, r: &hyper::Request<hyper::body::Incoming>) {
+        use http_body_util::BodyExt;
+
+        if !self.state().refreshing.insert(key.to_string()) {
+            return;
+        }
+
+        let mut request = hyper::Request::new(hyper::body::Bytes::new());
+        *request.uri_mut() = r.uri().clone();
+        *request.version_mut() = r.version();
+        *request.headers_mut() = r.headers().clone();
+
+        let cache = self.clone();
+        let key = key.to_string();
+        let graceful = peer.graceful.clone();
+        graceful.spawn(async move {
+            let request_headers = request.headers().clone();
+            if let Some(cached) = cache.lookup(&key, &request_headers) {
+                add_validators(request.headers_mut(), &cached.headers);
+            }
+
+            match peer.fetch(request).await {
+                Ok(resp) if resp.status() == hyper::StatusCode::NOT_MODIFIED => {
+                    if let Some(cached) = cache.lookup(&key, &request_headers) {
+                        cache.revalidated(&cached, resp.headers());
+                    }
+                }
+                Ok(resp)
+                    if !resp
+                        .headers()
+                        .contains_key(kulfi_utils::http::ERROR_CODE_HEADER) =>
+                {
+                    // a larger body would not be stored, so we stop reading once it is too big
+                    let (parts, body) = resp.into_parts();
+                    let max = cache.inner.config.max_entry_size as usize;
+                    match http_body_util::Limited::new(body, max).collect().await {
+                        Ok(body) => cache.store(
+                            &key,
+                            &request_headers,
+                            parts.status,
+                            parts.headers,
+                            body.to_bytes(),
+                        ),
+                        Err(e) => tracing::info!("not storing the refreshed {key}: {e:?}"),
+                    }
+                }
+                Ok(resp) => tracing::info!("failed to refresh {key}: {}", resp.status()),
+                Err(e) => tracing::info!("failed to refresh {key}: {e:?}"),
+            }
+
+            cache.state().refreshing.remove(&key);
+        });
+    }
+
+    /// the peer says our copy is still good, `headers` are from its `304 Not Modified`
+    fn revalidated(&self, cached: &Cached, headers: &hyper::HeaderMap) {
+        let mut state = self.state();
+        let Some(entry) = state
+            .entries
+            .get_mut(&cached.key)
+            .and_then(|entries| entries.iter_mut().find(|e| e.id == cached.id))
+        else {
+            return;
+        };
+
+        for (name, value) in headers {
+            if name != hyper::header::CONTENT_LENGTH {
+                entry.headers.insert(name, value.clone());
+            }
+        }
+        if let Some(policy) = policy(
+            entry.status,
+            &entry.headers,
+            &self.inner.config,
+            std::time::SystemTime::now(),
+        ) {
+            entry.policy = policy;
+            entry.date = response_date(&entry.headers);
+        }
+    }
+
+    /// passes the response on to the client, and stores it once all of it went through
+    fn store_on_end(
+        &self,
+        key: String,
+        request: &hyper::HeaderMap,
+        resp: kulfi_utils::http::ProxyResponse<eyre::Error>,
+    ) -> kulfi_utils::http::ProxyResponse<eyre::Error> {
+        use http_body_util::BodyExt;
+
+        if resp
+            .headers()
+            .contains_key(kulfi_utils::http::ERROR_CODE_HEADER)
+            || policy(
+                resp.status(),
+                resp.headers(),
+                &self.inner.config,
+                std::time::SystemTime::now(),
+            )
+            .is_none()
+        {
+            return with_status(resp, "miss");
+        }
+
+        let (parts, body) = resp.into_parts();
+        let cache = self.clone();
+        let request = request.clone();
+        let status = parts.status;
+        let headers = parts.headers.clone();
+        let body = Tee {
+            inner: body,
+            buf: Some(vec![]),
+            max: self.inner.config.max_entry_size,
+            length: parts
+                .headers
+                .get(hyper::header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok()),
+            done: Some(Box::new(move |body| {
+                cache.store(&key, &request, status, headers, body)
+            })),
+        };
+
+        with_status(hyper::Response::from_parts(parts, body.boxed()), "miss")
+    }
+
+    fn store(
+        &self,
+        key: &str,
+        request: &hyper::HeaderMap,
+        status: hyper::StatusCode,
+        headers: hyper::HeaderMap,
+        body: hyper::body::Bytes,
+    ) {
+        let config = &self.inner.config;
+        let size = body.len() as u64;
+        if size > config.max_entry_size {
+            return;
+        }
+        let Some(policy) = policy(status, &headers, config, std::time::SystemTime::now()) else {
+            return;
+        };
+
+        let vary = vary_headers(&headers)
+            .into_iter()
+            .map(|name| {
+                let value = request.get(&name).cloned();
+                (name, value)
+            })
+            .collect();
+
+        let mut state = self.state();
+        state.next_id += 1;
+        state.clock += 1;
+        let entry = Entry {
+            id: state.next_id,
+            vary,
+            status,
+            date: response_date(&headers),
+            headers,
+            memory: Some(body),
+            disk: None,
+            spilling: false,
+            size,
+            policy,
+            last_used: state.clock,
+        };
+
+        let mut removed = vec![];
+        let entries = state.entries.entry(key.to_string()).or_default();
+        if let Some(i) = entries.iter().position(|e| e.vary == entry.vary) {
+            removed.push(entries.swap_remove(i));
+        }
+        entries.push(entry);
+        for entry in &removed {
+            state.forget(entry);
+        }
+        state.memory_used += size;
+
+        // make room in memory, by moving the least recently used bodies to disk, or dropping them
+        let mut spills = vec![];
+        while state.memory_used > config.memory_size {
+            let Some((key, id)) = state.least_recently_used(|e| e.memory.is_some() && !e.spilling)
+            else {
+                break;
+            };
+            match &config.dir {
+                Some(dir) => {
+                    let entry = state.get_mut(&key, id).expect("we just found it");
+                    entry.spilling = true;
+                    let size = entry.size;
+                    let bytes = entry.memory.clone().expect("checked above");
+                    // counted on disk once it is written
+                    state.memory_used -= size;
+                    spills.push((key, id, dir.join(id.to_string()), bytes));
+                }
+                None => removed.extend(state.remove(&key, id)),
+            }
+        }
+        drop(state);
+
+        delete_files(removed);
+        for (key, id, path, bytes) in spills {
+            let cache = self.clone();
+            tokio::spawn(async move { cache.spill(key, id, path, bytes).await });
+        }
+    }
+
+    async fn spill(
+        &self,
+        key: String,
+        id: u64,
+        path: std::path::PathBuf,
+        bytes: hyper::body::Bytes,
+    ) {
+        if let Err(e) = tokio::fs::write(&path, &bytes).await {
+            tracing::error!("failed to write cached response to {path:?}: {e:?}");
+            let _ = tokio::fs::remove_file(&path).await;
+            self.remove(&key, id);
+            return;
+        }
+
+        let removed = {
+            let mut state = self.state();
+            match state.get_mut(&key, id) {
+                Some(entry) => {
+                    entry.memory = None;
+                    entry.disk = Some(path.clone());
+                    entry.spilling = false;
+                    let size = entry.size;
+                    state.disk_used += size;
+
+                    let mut removed = vec![];
+                    while state.disk_used > self.inner.config.disk_size {
+                        let Some((key, id)) = state.least_recently_used(|e| e.disk.is_some())
+                        else {
+                            break;
+                        };
+                        removed.extend(state.remove(&key, id));
+                    }
+                    Some(removed)
+                }
+                // purged or replaced while we were writing it
+                None => None,
+            }
+        };
+
+        match removed {
+            Some(removed) => delete_files(removed),
+            None => {
+                let _ = tokio::fs::remove_file(&path).await;
+            }
+        }
+    }
+
+    fn remove(&self, key: &str, id: u64) {
+        let removed = self.state().remove(key, id);
+        delete_files(removed);
+    }
+}
+
+impl State {
+    fn get_mut(&mut self, key: &str, id: u64) -> Option<&mut Entry> {
+        self.entries.get_mut(key)?.iter_mut().find(|e| e.id == id)
+    }
+
+    fn least_recently_used(&self, filter: impl Fn(&Entry) -> bool) -> Option<(String, u64)> {
+        self.entries
+            .iter()
+            .flat_map(|(key, entries)| entries.iter().map(move |e| (key, e)))
+            .filter(|(_, e)| filter(e))
+            .min_by_key(|(_, e)| e.last_used)
+            .map(|(key, e)| (key.clone(), e.id))
+    }
+
+    fn remove(&mut self, key: &str, id: u64) -> Option<Entry> {
+        let entries = self.entries.get_mut(key)?;
+        let i = entries.iter().position(|e| e.id == id)?;
+        let entry = entries.swap_remove(i);
+        if entries.is_empty() {
+            self.entries.remove(key);
+        }
+        self.forget(&entry);
+        Some(entry)
+    }
+
+    /// takes the size of a removed entry off the totals
+    fn forget(&mut self, entry: &Entry) {
+        if entry.disk.is_some() {
+            self.disk_used -= entry.size;
+        } else if !entry.spilling {
+            self.memory_used -= entry.size;
+        }
+    }
+}
+
+fn delete_files(entries: impl IntoIterator<Item = Entry>) {
+    for path in entries.into_iter().filter_map(|e| e.disk) {
+        tokio::spawn(async move {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                tracing::error!("failed to delete cached response {path:?}: {e:?}");
+            }
+        });
+    }
+}
+
+fn cache_key(id52: &str, r: &hyper::Request<hyper::body::Incoming>) -> String {
+    let host = r
+        .headers()
+        .get(hyper::header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| r.uri().host())
+        .unwrap_or_default();
+    let path = r.uri().path_and_query().map(|v| v.as_str()).unwrap_or("/");
+    format!("{id52} {} {path}", host.to_ascii_lowercase())
+}
+
+fn with_status<B>(mut resp: hyper::Response<B>, status: &'static str) -> hyper::Response<B> {
+    kulfi_utils::metrics::bridge_cache(status);
+    resp.headers_mut().insert(
+        CACHE_STATUS_HEADER,
+        hyper::header::HeaderValue::from_static(status),
+    );
+    resp
+}
+
+/// adds `If-None-Match` and `If-Modified-Since` for the response we have, returns false if it has
+/// no validators
+fn add_validators(request: &mut hyper::HeaderMap, cached: &hyper::HeaderMap) -> bool {
+    let mut added = false;
+    if let Some(etag) = cached.get(hyper::header::ETAG) {
+        request.insert(hyper::header::IF_NONE_MATCH, etag.clone());
+        added = true;
+    }
+    if let Some(last_modified) = cached.get(hyper::header::LAST_MODIFIED) {
+        request.insert(hyper::header::IF_MODIFIED_SINCE, last_modified.clone());
+        added = true;
+    }
+    added
+}
+
+fn vary_headers(headers: &hyper::HeaderMap) -> Vec<hyper::header::HeaderName> {
+    headers
+        .get_all(hyper::header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect()
+}
+
+/// when the response was generated, going by its `Age`
+fn response_date(headers: &hyper::HeaderMap) -> std::time::Instant {
+    let now = std::time::Instant::now();
+    let age = headers
+        .get(hyper::header::AGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .map(std::time::Duration::from_secs)
+        .unwrap_or_default();
+    now.checked_sub(age).unwrap_or(now)
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    must_revalidate: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    stale_while_revalidate: Option<u64>,
+    stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &hyper::HeaderMap) -> Self {
+        let mut cc = Self::default();
+        let directives = headers
+            .get_all(hyper::header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            let seconds = value.and_then(|v| v.parse().ok());
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                // `no-cache="set-cookie"` only limits what can be reused, we do not bother
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "must-revalidate" | "proxy-revalidate" => cc.must_revalidate = true,
+                // a `max-age` we can not read is treated as already stale
+                "max-age" => cc.max_age = Some(seconds.unwrap_or(0)),
+                "s-maxage" => cc.s_maxage = Some(seconds.unwrap_or(0)),
+                "stale-while-revalidate" => cc.stale_while_revalidate = seconds,
+                "stale-if-error" => cc.stale_if_error = seconds,
+                _ => {}
+            }
+        }
+
+        cc
+    }
+}
+
+/// how long a response can be served for, `None` if it can not be stored
+fn policy(
+    status: hyper::StatusCode,
+    headers: &hyper::HeaderMap,
+    config: &CacheConfig,
+    now: std::time::SystemTime,
+) -> Option<Policy> {
+    use std::time::Duration;
+
+    let cc = CacheControl::parse(headers);
+    if cc.no_store
+        || cc.private
+        || !CACHEABLE_STATUSES.contains(&status.as_u16())
+        || headers.contains_key(hyper::header::SET_COOKIE)
+        || headers.get_all(hyper::header::VARY).iter().any(|v| {
+            v.to_str()
+                .map_or(true, |v| v.split(',').any(|n| n.trim() == "*"))
+        })
+    {
+        return None;
+    }
+
+    let date = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &hyper::header::HeaderValue| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok())
+    };
+    let served_at = date(hyper::header::DATE).unwrap_or(now);
+    let last_modified = date(hyper::header::LAST_MODIFIED);
+
+    let lifetime = if let Some(seconds) = cc.s_maxage.or(cc.max_age) {
+        Some(Duration::from_secs(seconds))
+    } else if let Some(expires) = headers.get(hyper::header::EXPIRES) {
+        // an invalid `Expires`, like `0`, means it has already expired
+        Some(
+            expires
+                .to_str()
+                .ok()
+                .and_then(|v| httpdate::parse_http_date(v).ok())
+                .and_then(|expires| expires.duration_since(served_at).ok())
+                .unwrap_or_default(),
+        )
+    } else {
+        // RFC 9111 suggests a tenth of the time since it was last modified
+        last_modified
+            .and_then(|lm| served_at.duration_since(lm).ok())
+            .map(|d| (d / 10).min(MAX_HEURISTIC_LIFETIME))
+    };
+
+    let has_validators = headers.contains_key(hyper::header::ETAG) || last_modified.is_some();
+    let lifetime = if cc.no_cache {
+        Duration::ZERO
+    } else {
+        lifetime.unwrap_or_default()
+    };
+    if lifetime.is_zero() && !has_validators {
+        // we would have to ask the peer every time anyways
+        return None;
+    }
+
+    let (stale_while_revalidate, stale_if_error) = if cc.must_revalidate || cc.no_cache {
+        (Duration::ZERO, Duration::ZERO)
+    } else {
+        (
+            Duration::from_secs(cc.stale_while_revalidate.unwrap_or(0)),
+            cc.stale_if_error
+                .map(Duration::from_secs)
+                .unwrap_or(config.stale_if_error),
+        )
+    };
+
+    Some(Policy {
+        lifetime,
+        stale_while_revalidate,
+        stale_if_error,
+    })
+}
+
+/// Tee passes a response body on, and keeps a copy of it. if the whole body went through, and was
+/// not larger than `max`, `done` is called with the copy.
+struct Tee {
+    inner: http_body_util::combinators::BoxBody<hyper::body::Bytes, eyre::Error>,
+    /// `None` once we gave up on keeping the body
+    buf: Option<Vec<u8>>,
+    max: u64,
+    done: Option<Box<dyn FnOnce(hyper::body::Bytes) + Send + Sync>>,
+    /// the `Content-Length` of the response
+    length: Option<u64>,
+}
+
+impl Tee {
+    fn finish(&mut self) {
+        if let (Some(buf), Some(done)) = (self.buf.take(), self.done.take()) {
+            done(buf.into());
+        }
+    }
+}
+
+impl hyper::body::Body for Tee {
+    type Data = hyper::body::Bytes;
+    type Error = eyre::Error;
+
+    fn poll_frame(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<hyper::body::Frame<Self::Data>, Self::Error>>> {
+        let r = std::pin::Pin::new(&mut self.inner).poll_frame(cx);
+        match &r {
+            std::task::Poll::Ready(Some(Ok(frame))) => match frame.data_ref() {
+                Some(data) => {
+                    let max = self.max;
+                    if let Some(buf) = &mut self.buf {
+                        buf.extend_from_slice(data);
+                        if buf.len() as u64 > max {
+                            self.buf = None;
+                        }
+                    }
+                    // hyper stops polling once it has sent `Content-Length` bytes
+                    let received = self.buf.as_ref().map(|b| b.len() as u64);
+                    if self.inner.is_end_stream() || received.is_some() && received == self.length {
+                        self.finish();
+                    }
+                }
+                // we do not store trailers
+                None => self.buf = None,
+            },
+            std::task::Poll::Ready(Some(Err(_))) => self.buf = None,
+            std::task::Poll::Ready(None) => self.finish(),
+            std::task::Poll::Pending => {}
+        }
+        r
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    fn config() -> super::CacheConfig {
+        super::CacheConfig {
+            memory_size: 100,
+            dir: None,
+            disk_size: 0,
+            max_entry_size: 60,
+            stale_if_error: std::time::Duration::from_secs(3600),
+            purge_token: None,
+        }
+    }
+
+    fn headers(list: &[(&'static str, &'static str)]) -> hyper::HeaderMap {
+        list.iter()
+            .map(|(k, v)| {
+                (
+                    hyper::header::HeaderName::from_static(k),
+                    hyper::header::HeaderValue::from_static(v),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_policy() {
+        use std::time::Duration;
+
+        let ok = hyper::StatusCode::OK;
+        let now = httpdate::parse_http_date("Sun, 18 Oct 2026 10:00:00 GMT").unwrap();
+        let policy = |status, list: &[_]| super::policy(status, &headers(list), &config(), now);
+
+        let p = policy(
+            ok,
+            &[(
+                "cache-control",
+                "public, max-age=60, s-maxage=\"120\", stale-while-revalidate=30",
+            )],
+        )
+        .unwrap();
+        assert_eq!(p.lifetime, Duration::from_secs(120));
+        assert_eq!(p.stale_while_revalidate, Duration::from_secs(30));
+        assert_eq!(p.stale_if_error, Duration::from_secs(3600));
+
+        let p = policy(
+            ok,
+            &[
+                ("date", "Sun, 18 Oct 2026 10:00:00 GMT"),
+                ("expires", "Sun, 18 Oct 2026 10:05:00 GMT"),
+                ("cache-control", "must-revalidate"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(p.lifetime, Duration::from_secs(300));
+        assert_eq!(p.stale_if_error, Duration::ZERO);
+
+        // a tenth of the ten days since it was modified
+        let p = policy(ok, &[("last-modified", "Thu, 08 Oct 2026 10:00:00 GMT")]).unwrap();
+        assert_eq!(p.lifetime, Duration::from_secs(24 * 60 * 60));
+
+        // stored, but checked with the peer every time
+        let p = policy(ok, &[("etag", "\"v1\""), ("cache-control", "no-cache")]).unwrap();
+        assert_eq!(p.lifetime, Duration::ZERO);
+
+        for list in [
+            &[][..],
+            &[("cache-control", "no-cache")],
+            &[("cache-control", "private, max-age=60")],
+            &[("cache-control", "no-store, max-age=60")],
+            &[("cache-control", "max-age=60"), ("set-cookie", "a=b")],
+            &[("cache-control", "max-age=60"), ("vary", "*")],
+            &[("expires", "0")],
+        ] {
+            assert_eq!(policy(ok, list), None, "{list:?}");
+        }
+        assert_eq!(
+            policy(
+                hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                &[("cache-control", "max-age=60")]
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_can_purge() {
+        let cache = super::BridgeCache::new(config()).await.unwrap();
+        assert!(!cache.can_purge(&headers(&[("authorization", "Bearer secret")])));
+
+        let cache = super::BridgeCache::new(super::CacheConfig {
+            purge_token: Some("secret".to_string()),
+            ..config()
+        })
+        .await
+        .unwrap();
+        assert!(cache.can_purge(&headers(&[("authorization", "Bearer secret")])));
+        assert!(!cache.can_purge(&headers(&[("authorization", "Bearer wrong")])));
+        assert!(!cache.can_purge(&headers(&[])));
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let cache = super::BridgeCache::new(config()).await.unwrap();
+        let store = |key: &str, request: &[_], body: &'static [u8]| {
+            cache.store(
+                key,
+                &headers(request),
+                hyper::StatusCode::OK,
+                headers(&[("cache-control", "max-age=60"), ("vary", "accept-language")]),
+                hyper::body::Bytes::from_static(body),
+            )
+        };
+
+        store("a h /x", &[("accept-language", "en")], &[b'e'; 40]);
+        store("a h /x", &[("accept-language", "hi")], &[b'h'; 40]);
+        // too large
+        store("a h /y", &[], &[b'y'; 61]);
+
+        let en = cache
+            .lookup("a h /x", &headers(&[("accept-language", "en")]))
+            .unwrap();
+        assert!(en.fresh);
+        assert!(matches!(en.body, super::Body::Memory(b) if b[0] == b'e'));
+        assert!(cache.lookup("a h /x", &headers(&[])).is_none());
+        assert!(cache.lookup("a h /y", &headers(&[])).is_none());
+
+        // there is no room for a third one, and `hi` was used last a while ago
+        store("a h /z?q=1", &[], &[b'z'; 40]);
+        assert!(
+            cache
+                .lookup("a h /x", &headers(&[("accept-language", "hi")]))
+                .is_none()
+        );
+        assert!(
+            cache
+                .lookup("a h /x", &headers(&[("accept-language", "en")]))
+                .is_some()
+        );
+        assert_eq!(cache.state().memory_used, 80);
+
+        assert_eq!(cache.purge("b", "/*"), 0);
+        assert_eq!(cache.purge("a", "/z"), 1);
+        assert_eq!(cache.purge("a", "/*"), 1);
+        assert_eq!(cache.state().memory_used, 0);
+    }
+}
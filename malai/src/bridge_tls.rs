@@ -0,0 +1,111 @@
+/// BridgeTls lets `malai http-bridge` terminate TLS itself, so it can be the public edge without
+/// an nginx in front of it.
+///
+/// the certificate (usually a wildcard one, for `*.kulfi.site` style bridges) and key are read from
+/// PEM files. certificates are renewed every few months, so the files are re-read when they change,
+/// like `WatchedFile` we check the modification times when a connection comes in. `reload()` is
+/// also called on SIGHUP, for tools that expect that.
+///
+/// both HTTP/1.1 and HTTP/2 are offered with ALPN.
+pub struct BridgeTls {
+    cert: std::path::PathBuf,
+    key: std::path::PathBuf,
+    state: tokio::sync::Mutex<State>,
+}
+
+struct State {
+    modified: (Option<std::time::SystemTime>, Option<std::time::SystemTime>),
+    acceptor: tokio_rustls::TlsAcceptor,
+}
+
+impl BridgeTls {
+    /// reads the certificate and key, errors if they can not be read or do not match
+    pub async fn new(
+        cert: impl Into<std::path::PathBuf>,
+        key: impl Into<std::path::PathBuf>,
+    ) -> eyre::Result<Self> {
+        let cert = cert.into();
+        let key = key.into();
+        let state = load(&cert, &key).await?;
+
+        Ok(Self {
+            cert,
+            key,
+            state: tokio::sync::Mutex::new(state),
+        })
+    }
+
+    /// the acceptor to use for a new connection, with the latest certificate
+    pub async fn acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        let mut state = self.state.lock().await;
+
+        let modified = (modified(&self.cert).await, modified(&self.key).await);
+        if modified == state.modified {
+            return state.acceptor.clone();
+        }
+
+        // the key and certificate are often replaced one after the other, if they do not match
+        // yet we keep the old ones and try again on the next connection.
+        match load(&self.cert, &self.key).await {
+            Ok(v) => {
+                tracing::info!(cert = ?self.cert, "reloaded tls certificate");
+                *state = v;
+            }
+            Err(e) => tracing::error!(cert = ?self.cert, "failed to reload tls certificate: {e:?}"),
+        }
+
+        state.acceptor.clone()
+    }
+
+    pub async fn reload(&self) -> eyre::Result<()> {
+        let state = load(&self.cert, &self.key).await?;
+        *self.state.lock().await = state;
+        tracing::info!(cert = ?self.cert, "reloaded tls certificate");
+        Ok(())
+    }
+}
+
+async fn modified(path: &std::path::Path) -> Option<std::time::SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+async fn load(cert: &std::path::Path, key: &std::path::Path) -> eyre::Result<State> {
+    use eyre::WrapErr;
+    use rustls::pki_types::pem::PemObject;
+
+    // read the modification times first, if a file changes while we read it we load it again
+    // on the next connection.
+    let modified = (modified(cert).await, modified(key).await);
+
+    let cert_pem = tokio::fs::read(cert)
+        .await
+        .wrap_err_with(|| format!("failed to read {cert:?}"))?;
+    let key_pem = tokio::fs::read(key)
+        .await
+        .wrap_err_with(|| format!("failed to read {key:?}"))?;
+
+    let certs = rustls::pki_types::CertificateDer::pem_slice_iter(&cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err_with(|| format!("failed to parse {cert:?}"))?;
+    if certs.is_empty() {
+        return Err(eyre::anyhow!("no certificates found in {cert:?}"));
+    }
+    let key_der = rustls::pki_types::PrivateKeyDer::from_pem_slice(&key_pem)
+        .wrap_err_with(|| format!("failed to parse {key:?}"))?;
+
+    // both ring and aws-lc-rs end up in our dependency tree, so we pass the provider explicitly,
+    // same as `kulfi_utils::UpstreamTls`.
+    let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .wrap_err_with(|| "failed to create tls config")?
+        .with_no_client_auth()
+        .with_single_cert(certs, key_der)
+        .wrap_err_with(|| format!("{cert:?} and {key:?} do not make a valid certificate"))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(State {
+        modified,
+        acceptor: tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config)),
+    })
+}
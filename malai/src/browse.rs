@@ -8,10 +8,18 @@ pub async fn browse(url: String, graceful: kulfi_utils::Graceful) {
         }
     };
 
-    malai::http_bridge(0, Some(id52.to_string()), graceful, |port| {
-        let url = format!("http://127.0.0.1:{port}/{path}");
-        webbrowser::open(&url).map_err(Into::into)
-    })
+    malai::http_bridge(
+        malai::Listen::local(0),
+        malai::HttpBridgeOptions {
+            proxy_target: Some(id52.to_string()),
+            ..Default::default()
+        },
+        graceful,
+        |port| {
+            let url = format!("http://127.0.0.1:{port}/{path}");
+            webbrowser::open(&url).map_err(Into::into)
+        },
+    )
     .await
 }
 
@@ -0,0 +1,72 @@
+/// `malai connect` pipes stdin and stdout to a TCP service shared with `malai tcp`, instead of
+/// listening on a local port like `malai tcp-bridge` does. this is what ssh wants from a
+/// `ProxyCommand`, and `rsync -e` and scripts get a stream without picking a free port:
+///
+/// ```sh
+/// ssh -o ProxyCommand="malai connect %h" <id52>
+/// ```
+///
+/// stdout carries the stream, so our messages go to stderr. if the peer can not be reached, we
+/// exit with a non zero status, so the program running us knows.
+pub async fn connect(
+    proxy_target: String,
+    service: Option<String>,
+    graceful: kulfi_utils::Graceful,
+) {
+    // ssh hosts like `<id52>.kulfi`, which a `Host *.kulfi` block can match
+    let proxy_target = proxy_target
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    let header = match malai::TcpTarget::header(service.clone()) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Failed to create the protocol header: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    let self_endpoint = kulfi_utils::global_iroh_endpoint().await;
+    let (send, recv) = match kulfi_utils::get_stream(
+        self_endpoint.clone(),
+        header,
+        proxy_target.clone(),
+        Default::default(),
+        graceful,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            match e.downcast_ref::<kulfi_utils::http::ProxyError>() {
+                // the message of `PeerRefused` is about HTTP, we asked for TCP
+                Some(kulfi_utils::http::ProxyError::PeerRefused) => match &service {
+                    Some(service) => eprintln!(
+                        "Failed to connect to {proxy_target}: the peer does not share services by name, try without --service {service}."
+                    ),
+                    None => eprintln!(
+                        "Failed to connect to {proxy_target}: the peer does not share a TCP service, or shares several, pick one with --service <name>."
+                    ),
+                },
+                Some(reason) => eprintln!("Failed to connect to {proxy_target}: {reason}"),
+                None => eprintln!("Failed to connect to {proxy_target}: {e:?}"),
+            }
+            tracing::info!("failed to get stream: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    tracing::info!("connected to {proxy_target}");
+    if let Err(e) =
+        kulfi_utils::pipe_tcp_stream_over_iroh(tokio::io::stdin(), tokio::io::stdout(), send, recv)
+            .await
+    {
+        eprintln!("Connection to {proxy_target} failed: {e:?}");
+        std::process::exit(1);
+    }
+
+    // lets the peer know we are done, instead of it finding out when the connection times out
+    self_endpoint.close().await;
+}
@@ -0,0 +1,179 @@
+/// DomainMap lets `malai http-bridge` serve a peer on a custom domain, say `docs.ourteam.dev`,
+/// instead of only on `<id52>.<bridge domain>`.
+///
+/// the file has one mapping per line: the hostname, the id52, and optionally a path prefix that is
+/// added to the path of every request. a hostname starting with `*.` matches all its subdomains,
+/// exact hostnames win over patterns, and longer patterns over shorter ones. empty lines and
+/// anything after `#` are ignored:
+///
+/// ```text
+/// docs.ourteam.dev       i66fo538lfl5ombdf6tcdbrabp4hmp9asv7nrffuc2im13ct4q60  /docs
+/// *.preview.ourteam.dev  e87aeds2fajaeu10tjdio5ppcdha410n6tu4665u7el9as9b7v80
+/// ```
+///
+/// like the ACL files, the file is re-read when it changes.
+pub struct DomainMap {
+    file: malai::WatchedFile<Domains>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DomainTarget {
+    pub id52: String,
+    /// starts with a `/` and does not end with one
+    pub path_prefix: Option<String>,
+}
+
+impl DomainMap {
+    pub async fn new(path: impl Into<std::path::PathBuf>) -> eyre::Result<Self> {
+        Ok(Self {
+            file: malai::WatchedFile::new(path, parse_domains).await?,
+        })
+    }
+
+    /// the peer for the `Host` of a request, if the host is in the file
+    pub async fn lookup(&self, host: &str) -> Option<DomainTarget> {
+        self.file.get().await.lookup(host)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Domains {
+    exact: std::collections::HashMap<String, DomainTarget>,
+    /// the `*.` patterns, stored as the suffix with the leading dot, longest first
+    wildcard: Vec<(String, DomainTarget)>,
+}
+
+impl Domains {
+    fn lookup(&self, host: &str) -> Option<DomainTarget> {
+        // the Host header may have a port, and DNS names can end with a dot
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+            _ => host,
+        };
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+        if let Some(target) = self.exact.get(&host) {
+            return Some(target.clone());
+        }
+
+        self.wildcard
+            .iter()
+            .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+            .map(|(_, target)| target.clone())
+    }
+}
+
+fn parse_domains(content: &str) -> eyre::Result<Domains> {
+    use std::str::FromStr;
+
+    let mut domains = Domains::default();
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let parts: Vec<_> = line.split_whitespace().collect();
+        let (host, id52, path_prefix) = match parts.as_slice() {
+            [] => continue,
+            [host, id52] => (host, id52, None),
+            [host, id52, prefix] => (host, id52, Some(prefix)),
+            _ => {
+                return Err(eyre::anyhow!(
+                    "line {}: expected `<hostname> <id52> [path prefix]`",
+                    i + 1
+                ));
+            }
+        };
+
+        kulfi_id52::PublicKey::from_str(id52)
+            .map_err(|e| eyre::anyhow!("line {}: invalid id52 {id52:?}: {e}", i + 1))?;
+
+        let path_prefix = match path_prefix {
+            Some(p) if !p.starts_with('/') => {
+                return Err(eyre::anyhow!(
+                    "line {}: path prefix {p:?} must start with a /",
+                    i + 1
+                ));
+            }
+            Some(p) => Some(p.trim_end_matches('/'))
+                .filter(|p| !p.is_empty())
+                .map(str::to_string),
+            None => None,
+        };
+
+        let target = DomainTarget {
+            id52: id52.to_string(),
+            path_prefix,
+        };
+
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        match host.strip_prefix("*.") {
+            Some(rest) if !rest.is_empty() && !rest.contains('*') => {
+                domains.wildcard.push((format!(".{rest}"), target));
+            }
+            _ if host.contains('*') => {
+                return Err(eyre::anyhow!(
+                    "line {}: only `*.` patterns are supported, found {host:?}",
+                    i + 1
+                ));
+            }
+            _ => {
+                domains.exact.insert(host, target);
+            }
+        }
+    }
+
+    domains
+        .wildcard
+        .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+
+    Ok(domains)
+}
+
+/// `/docs` and `/a?b=1` become `/docs/a?b=1`
+pub fn add_path_prefix(uri: &hyper::Uri, prefix: &str) -> eyre::Result<hyper::Uri> {
+    let path_and_query = uri.path_and_query().map(|v| v.as_str()).unwrap_or("/");
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(format!("{prefix}{path_and_query}").parse()?);
+    Ok(hyper::Uri::from_parts(parts)?)
+}
+
+#[cfg(test)]
+mod test {
+    const DOCS: &str = "i66fo538lfl5ombdf6tcdbrabp4hmp9asv7nrffuc2im13ct4q60";
+    const PREVIEW: &str = "e87aeds2fajaeu10tjdio5ppcdha410n6tu4665u7el9as9b7v80";
+
+    #[test]
+    fn test_lookup() {
+        let domains = super::parse_domains(&format!(
+            "# our team\n\
+             docs.ourteam.dev {DOCS} /docs/\n\
+             *.ourteam.dev {PREVIEW}\n\
+             *.preview.ourteam.dev {DOCS} # more specific\n"
+        ))
+        .unwrap();
+
+        let docs = domains.lookup("Docs.OurTeam.dev:8080").unwrap();
+        assert_eq!(docs.id52, DOCS);
+        assert_eq!(docs.path_prefix.as_deref(), Some("/docs"));
+
+        assert_eq!(domains.lookup("blog.ourteam.dev").unwrap().id52, PREVIEW);
+        assert_eq!(
+            domains.lookup("pr-1.preview.ourteam.dev").unwrap().id52,
+            DOCS
+        );
+        assert_eq!(domains.lookup("ourteam.dev"), None);
+        assert_eq!(domains.lookup("example.com"), None);
+
+        assert!(super::parse_domains(&format!("a.dev {DOCS} docs")).is_err());
+        assert!(super::parse_domains("a.dev not-an-id52").is_err());
+        assert!(super::parse_domains(&format!("a*.dev {DOCS}")).is_err());
+    }
+
+    #[test]
+    fn test_add_path_prefix() {
+        let uri: hyper::Uri = "/a?b=1".parse().unwrap();
+        assert_eq!(
+            super::add_path_prefix(&uri, "/docs").unwrap(),
+            "/docs/a?b=1"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+/// ErrorPages replaces the HTML error pages of `malai http-bridge`, say to match the look of your
+/// site, or to say who to contact when a share is down.
+///
+/// the directory has one HTML file per error, named after the `x-kulfi-error` code (say
+/// `peer-unreachable.html`) or the status (`502.html`), with `error.html` used for the rest. the
+/// pages can use `{{status}}`, `{{reason}}`, `{{message}}` and `{{code}}`. errors without a page
+/// get the built in one, and clients asking for JSON always get JSON.
+///
+/// the files are read on start, and again on SIGHUP.
+pub struct ErrorPages {
+    dir: std::path::PathBuf,
+    pages: std::sync::RwLock<std::collections::HashMap<String, String>>,
+}
+
+impl ErrorPages {
+    pub async fn new(dir: impl Into<std::path::PathBuf>) -> eyre::Result<Self> {
+        let dir = dir.into();
+        let pages = load(&dir).await?;
+        Ok(Self {
+            dir,
+            pages: std::sync::RwLock::new(pages),
+        })
+    }
+
+    pub async fn reload(&self) -> eyre::Result<()> {
+        let pages = load(&self.dir).await?;
+        *self
+            .pages
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = pages;
+        Ok(())
+    }
+
+    /// `resp` with our page, if it is an error page generated by kulfi and we have one for it
+    pub fn apply(
+        &self,
+        resp: kulfi_utils::http::ProxyResponse<eyre::Error>,
+        accept: Option<&hyper::header::HeaderValue>,
+    ) -> kulfi_utils::http::ProxyResponse<eyre::Error> {
+        if kulfi_utils::http::wants_json(accept.map(|v| v.as_bytes())) {
+            return resp;
+        }
+
+        let Some(error) = resp
+            .headers()
+            .get(kulfi_utils::http::ERROR_CODE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(kulfi_utils::http::ProxyError::from_code)
+        else {
+            return resp;
+        };
+
+        let body = {
+            let pages = self
+                .pages
+                .read()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            let status = error.status();
+            let Some(page) = [error.code(), status.as_str(), "error"]
+                .into_iter()
+                .find_map(|name| pages.get(name))
+            else {
+                return resp;
+            };
+
+            page.replace("{{status}}", status.as_str())
+                .replace("{{reason}}", status.canonical_reason().unwrap_or_default())
+                .replace("{{message}}", error.message())
+                .replace("{{code}}", error.code())
+        };
+
+        let (mut parts, _) = resp.into_parts();
+        parts.headers.remove(hyper::header::CONTENT_LENGTH);
+        parts.headers.insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        let mut resp = kulfi_utils::http::bytes_to_resp(body.into_bytes(), parts.status);
+        for (name, value) in &parts.headers {
+            resp.headers_mut().insert(name, value.clone());
+        }
+        resp
+    }
+}
+
+/// the `.html` files in `dir`, by their name without the extension
+async fn load(dir: &std::path::Path) -> eyre::Result<std::collections::HashMap<String, String>> {
+    use eyre::WrapErr;
+
+    let mut pages = std::collections::HashMap::new();
+    let mut files = tokio::fs::read_dir(dir)
+        .await
+        .wrap_err_with(|| format!("failed to read the error pages in {dir:?}"))?;
+    while let Some(file) = files.next_entry().await? {
+        let path = file.path();
+        if path.extension().is_none_or(|e| e != "html") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let page = tokio::fs::read_to_string(&path)
+            .await
+            .wrap_err_with(|| format!("failed to read {path:?}"))?;
+        pages.insert(name.to_string(), page);
+    }
+
+    Ok(pages)
+}
+
+#[cfg(test)]
+mod test {
+    #[tokio::test]
+    async fn test_apply() {
+        use http_body_util::BodyExt;
+
+        let dir = std::env::temp_dir().join(format!("malai-error-pages-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("peer-unreachable.html"),
+            "<h1>{{status}} {{reason}}</h1><p>{{message}}</p>",
+        )
+        .unwrap();
+        std::fs::write(dir.join("error.html"), "oops: {{code}}").unwrap();
+        let pages = super::ErrorPages::new(&dir).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let body = |error: kulfi_utils::http::ProxyError, accept: &'static str| {
+            let accept = hyper::header::HeaderValue::from_static(accept);
+            let resp = pages.apply(error.response(Some(&accept)), Some(&accept));
+            assert_eq!(resp.status(), error.status());
+            async move {
+                let body = resp.into_body().collect().await.unwrap().to_bytes();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        assert_eq!(
+            body(kulfi_utils::http::ProxyError::PeerUnreachable, "text/html").await,
+            "<h1>502 Bad Gateway</h1><p>The peer sharing this service is offline, or can not be reached.</p>"
+        );
+        assert_eq!(
+            body(kulfi_utils::http::ProxyError::PeerTimeout, "*/*").await,
+            "oops: peer-timeout"
+        );
+        assert!(
+            body(
+                kulfi_utils::http::ProxyError::PeerTimeout,
+                "application/json"
+            )
+            .await
+            .starts_with('{')
+        );
+    }
+}
@@ -1,4 +1,12 @@
-pub async fn expose_http(host: String, port: u16, bridge: String, graceful: kulfi_utils::Graceful) {
+pub async fn expose_http(
+    upstream: kulfi_utils::HttpUpstream,
+    bridge: String,
+    acl: malai::PeerAcl,
+    mut options: kulfi_utils::PeerToHttpOptions,
+    sign_peer_id52: bool,
+    max_concurrent_requests: usize,
+    graceful: kulfi_utils::Graceful,
+) {
     let (id52, secret_key) = match kulfi_utils::read_or_create_key().await {
         Ok(v) => v,
         Err(e) => {
@@ -7,6 +15,13 @@ pub async fn expose_http(host: String, port: u16, bridge: String, graceful: kulf
         }
     };
 
+    if sign_peer_id52 {
+        // get_endpoint() takes the key, so we keep a copy for signing
+        options.signing_key = Some(std::sync::Arc::new(kulfi_id52::SecretKey::from_bytes(
+            &secret_key.to_bytes(),
+        )));
+    }
+
     let ep = match kulfi_utils::get_endpoint(secret_key).await {
         Ok(v) => v,
         Err(e) => {
@@ -16,7 +31,7 @@ pub async fn expose_http(host: String, port: u16, bridge: String, graceful: kulf
         }
     };
 
-    InfoMode::Startup.print(&host, port, &id52, &bridge);
+    InfoMode::Startup.print(&upstream, &id52, &bridge);
 
     let client_pools = kulfi_utils::HttpConnectionPools::default();
 
@@ -25,7 +40,7 @@ pub async fn expose_http(host: String, port: u16, bridge: String, graceful: kulf
     loop {
         tokio::select! {
             _ = graceful_mut.show_info() => {
-                InfoMode::OnExit.print(&host, port, &id52, &bridge);
+                InfoMode::OnExit.print(&upstream, &id52, &bridge);
             }
             _ = graceful.cancelled() => {
                 tracing::info!("Stopping control server.");
@@ -41,7 +56,11 @@ pub async fn expose_http(host: String, port: u16, bridge: String, graceful: kulf
                 };
 
                 let client_pools = client_pools.clone();
-                let host = host.clone();
+                let upstream = upstream.clone();
+                let acl = acl.clone();
+                let options = options.clone();
+                let graceful_for_connection = graceful.clone();
+                let self_id = ep.id();
 
                 graceful.spawn(async move {
                     let start = std::time::Instant::now();
@@ -52,7 +71,18 @@ pub async fn expose_http(host: String, port: u16, bridge: String, graceful: kulf
                             return;
                         }
                     };
-                    if let Err(e) = handle_connection(conn, client_pools, host, port).await {
+                    if let Err(e) = handle_connection(
+                        conn,
+                        self_id,
+                        client_pools,
+                        upstream,
+                        acl,
+                        options,
+                        max_concurrent_requests,
+                        graceful_for_connection,
+                    )
+                    .await
+                    {
                         tracing::error!("connection error3: {:?}", e);
                     }
                     tracing::info!("connection handled in {:?}", start.elapsed());
@@ -64,29 +94,57 @@ pub async fn expose_http(host: String, port: u16, bridge: String, graceful: kulf
     ep.close().await;
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_connection(
     conn: iroh::endpoint::Connection,
+    self_id: iroh::EndpointId,
     client_pools: kulfi_utils::HttpConnectionPools,
-    host: String,
-    port: u16,
+    upstream: kulfi_utils::HttpUpstream,
+    acl: malai::PeerAcl,
+    options: kulfi_utils::PeerToHttpOptions,
+    max_concurrent_requests: usize,
+    graceful: kulfi_utils::Graceful,
 ) -> eyre::Result<()> {
+    acl.guard(&conn).await?;
     let remote_id52 = kulfi_utils::get_remote_id52(&conn);
+    let _active = kulfi_utils::metrics::ActivePeer::accepted(&remote_id52);
+
+    // every stream is served in its own task, so a slow request does not hold up the rest. we keep
+    // calling accept_bi() (which also answers pings) and only limit how many requests run at once,
+    // the tasks waiting for a permit are bounded by how many streams quic lets the peer open.
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(max_concurrent_requests));
 
     tracing::info!("new client: {remote_id52}, waiting for bidirectional stream");
     loop {
-        let (mut send, recv) = kulfi_utils::accept_bi(&conn, kulfi_utils::Protocol::Http)
+        let (mut send, recv) = kulfi_utils::accept_bi(&conn, self_id, kulfi_utils::Protocol::Http)
             .await
             .inspect_err(|e| tracing::error!("failed to accept bidirectional stream: {e:?}"))?;
         tracing::info!("{remote_id52}");
         let client_pools = client_pools.clone();
-        if let Err(e) =
-            kulfi_utils::peer_to_http(&format!("{host}:{port}"), client_pools, &mut send, recv)
-                .await
-        {
-            tracing::error!("failed to proxy http: {e:?}");
-        }
-        tracing::info!("closing send stream");
-        send.finish()?;
+        let upstream = upstream.clone();
+        let options = options.clone();
+        let remote_id52 = remote_id52.clone();
+        let semaphore = semaphore.clone();
+
+        graceful.spawn(async move {
+            let _permit = semaphore.acquire().await;
+            if let Err(e) = kulfi_utils::peer_to_http(
+                &upstream,
+                &remote_id52,
+                &options,
+                client_pools,
+                &mut send,
+                recv,
+            )
+            .await
+            {
+                tracing::error!("failed to proxy http: {e:?}");
+            }
+            tracing::info!("closing send stream");
+            if let Err(e) = send.finish() {
+                tracing::error!("failed to finish send stream: {e:?}");
+            }
+        });
     }
 }
 
@@ -97,7 +155,7 @@ enum InfoMode {
 }
 
 impl InfoMode {
-    fn print(&self, host: &str, port: u16, id52: &str, bridge: &str) {
+    fn print(&self, upstream: &kulfi_utils::HttpUpstream, id52: &str, bridge: &str) {
         use colored::Colorize;
 
         // Malai: Sharing http://127.0.0.1:3000 at
@@ -112,7 +170,7 @@ impl InfoMode {
         println!(
             "{}: Sharing {} at",
             "Malai".on_green().black(),
-            format!("http://{host}:{port}").yellow()
+            upstream.to_string().yellow()
         );
 
         println!("{}", format!("https://{id52}.{bridge}").yellow(),);
@@ -131,3 +189,66 @@ impl InfoMode {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    /// an endpoint on the local machine only, that can dial `peer`
+    async fn local_endpoint(peer: Option<&iroh::Endpoint>) -> iroh::Endpoint {
+        let discovery = iroh::discovery::static_provider::StaticProvider::new();
+        if let Some(peer) = peer {
+            discovery.add_endpoint_info(peer.addr());
+        }
+        iroh::Endpoint::builder()
+            .relay_mode(iroh::RelayMode::Disabled)
+            .discovery(discovery)
+            .alpns(vec![kulfi_utils::APNS_IDENTITY.into()])
+            .bind()
+            .await
+            .unwrap()
+    }
+
+    /// a connection with all its permits taken, and more requests waiting, still answers pings
+    #[tokio::test]
+    async fn test_saturated_connection_answers_ping() {
+        let exposer = local_endpoint(None).await;
+        let bridge = local_endpoint(Some(&exposer)).await;
+        let graceful = kulfi_utils::Graceful::new();
+
+        let ep = exposer.clone();
+        tokio::spawn(async move {
+            let conn = ep.accept().await.unwrap().await.unwrap();
+            let _ = super::handle_connection(
+                conn,
+                ep.id(),
+                Default::default(),
+                "127.0.0.1:9".into(),
+                Default::default(),
+                Default::default(),
+                1,
+                graceful,
+            )
+            .await;
+        });
+
+        let conn = bridge
+            .connect(exposer.addr(), kulfi_utils::APNS_IDENTITY)
+            .await
+            .unwrap();
+
+        // the requests never send their header, so the first one keeps the only permit, and the
+        // second one waits for it
+        let mut streams = vec![];
+        for _ in 0..2 {
+            streams.push(
+                kulfi_utils::open_bi(&conn, kulfi_utils::Protocol::Http.into())
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), kulfi_utils::ping(&conn))
+            .await
+            .expect("ping timed out")
+            .unwrap();
+    }
+}
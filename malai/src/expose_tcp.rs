@@ -1,4 +1,130 @@
-pub async fn expose_tcp(host: String, port: u16, graceful: kulfi_utils::Graceful) {
+/// what `malai tcp` shares: a single service, or several, that bridges pick by name
+#[derive(Clone, Debug)]
+pub enum TcpServices {
+    Single(kulfi_utils::UpstreamAddr),
+    Named(std::sync::Arc<std::collections::BTreeMap<String, kulfi_utils::UpstreamAddr>>),
+}
+
+/// sent by `malai tcp-bridge --service` after the `Protocol::TcpService` header, to pick one of
+/// the named services
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct TcpTarget {
+    pub service: String,
+}
+
+impl TcpTarget {
+    /// the protocol header for a stream to `malai tcp`, with the service to connect to, if the
+    /// peer shares several
+    pub fn header(service: Option<String>) -> eyre::Result<kulfi_utils::ProtocolHeader> {
+        Ok(match service {
+            Some(service) => kulfi_utils::ProtocolHeader {
+                protocol: kulfi_utils::Protocol::TcpService,
+                extra: Some(serde_json::to_string(&TcpTarget { service })?),
+            },
+            None => kulfi_utils::Protocol::Tcp.into(),
+        })
+    }
+}
+
+/// how long we wait for the `TcpTarget` of a stream. it is sent along with the protocol header, so
+/// this only runs out for bridges that are broken, or stuck.
+const TARGET_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+impl TcpServices {
+    pub fn named(services: Vec<(String, kulfi_utils::UpstreamAddr)>) -> eyre::Result<Self> {
+        let mut named = std::collections::BTreeMap::new();
+        for (name, addr) in services {
+            if named.insert(name.clone(), addr).is_some() {
+                return Err(eyre::anyhow!("service {name:?} is given more than once"));
+            }
+        }
+        Ok(TcpServices::Named(std::sync::Arc::new(named)))
+    }
+
+    /// parses a `name=host:port` (or `name=unix:/path`) mapping
+    pub fn parse_service(s: &str) -> Result<(String, kulfi_utils::UpstreamAddr), String> {
+        let (name, addr) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <name>=<host>:<port>, got {s:?}"))?;
+        if name.is_empty() {
+            return Err(format!("the service name is missing in {s:?}"));
+        }
+
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return Ok((
+                name.to_string(),
+                kulfi_utils::UpstreamAddr::Unix(path.into()),
+            ));
+        }
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok((
+                name.to_string(),
+                kulfi_utils::UpstreamAddr::Tcp(addr.to_string()),
+            )),
+            _ => Err(format!(
+                "expected <host>:<port> or unix:<path> for {name:?}, got {addr:?}"
+            )),
+        }
+    }
+
+    /// the protocol of the streams we accept, a peer that shares one service refuses streams for a
+    /// named one, and the other way round
+    fn protocol(&self) -> kulfi_utils::Protocol {
+        match self {
+            TcpServices::Single(_) => kulfi_utils::Protocol::Tcp,
+            TcpServices::Named(_) => kulfi_utils::Protocol::TcpService,
+        }
+    }
+
+    /// the service the stream is for. for named services the bridge tells which one, if it names
+    /// one we do not share, it is told so on the stream, and we give up on it.
+    async fn pick(
+        &self,
+        send: &mut iroh::endpoint::SendStream,
+        recv: &mut iroh::endpoint::RecvStream,
+    ) -> eyre::Result<kulfi_utils::UpstreamAddr> {
+        let services = match self {
+            TcpServices::Single(addr) => return Ok(addr.clone()),
+            TcpServices::Named(services) => services,
+        };
+
+        let names = services.keys().cloned().collect::<Vec<_>>().join(", ");
+        let (error, msg) =
+            match tokio::time::timeout(TARGET_TIMEOUT, kulfi_utils::next_json::<TcpTarget>(recv))
+                .await
+            {
+                Ok(Ok(target)) => match services.get(&target.service) {
+                    Some(addr) => return Ok(addr.clone()),
+                    None => (
+                        eyre::anyhow!("unknown service {:?}", target.service),
+                        format!(
+                            "this peer does not share a service named {:?}, it shares: {names}",
+                            target.service
+                        ),
+                    ),
+                },
+                Ok(Err(e)) => (
+                    e.wrap_err("failed to read the service name"),
+                    format!("failed to read the service name, this peer shares: {names}"),
+                ),
+                Err(_) => (
+                    eyre::anyhow!("timed out waiting for the service name"),
+                    format!("did not get the service name in time, this peer shares: {names}"),
+                ),
+            };
+
+        send.write_all(format!("{msg}\n").as_bytes()).await?;
+        send.finish()?;
+        Err(error)
+    }
+}
+
+pub async fn expose_tcp(
+    services: TcpServices,
+    acl: malai::PeerAcl,
+    access_log: kulfi_utils::AccessLog,
+    graceful: kulfi_utils::Graceful,
+) {
     let (id52, secret_key) = match kulfi_utils::read_or_create_key().await {
         Ok(v) => v,
         Err(e) => {
@@ -16,7 +142,7 @@ pub async fn expose_tcp(host: String, port: u16, graceful: kulfi_utils::Graceful
         }
     };
 
-    InfoMode::Startup.print(port, &id52);
+    InfoMode::Startup.print(&services, &id52);
 
     let mut graceful_mut = graceful.clone();
     loop {
@@ -24,7 +150,7 @@ pub async fn expose_tcp(host: String, port: u16, graceful: kulfi_utils::Graceful
 
         tokio::select! {
             _ = graceful_mut.show_info() => {
-                InfoMode::OnExit.print(port, &id52);
+                InfoMode::OnExit.print(&services, &id52);
             }
             _ = graceful.cancelled() => {
                 tracing::info!("Stopping control server.");
@@ -38,7 +164,10 @@ pub async fn expose_tcp(host: String, port: u16, graceful: kulfi_utils::Graceful
                         break;
                     }
                 };
-                let host = host.clone();
+                let services = services.clone();
+                let acl = acl.clone();
+                let self_id = ep.id();
+                let access_log = access_log.clone();
 
                 graceful.spawn(async move {
                     let start = std::time::Instant::now();
@@ -49,7 +178,7 @@ pub async fn expose_tcp(host: String, port: u16, graceful: kulfi_utils::Graceful
                             return;
                         }
                     };
-                    if let Err(e) = handle_connection(conn, host, port, graceful_for_handle_connection).await {
+                    if let Err(e) = handle_connection(conn, self_id, services, acl, access_log, graceful_for_handle_connection).await {
                         tracing::error!("connection error3: {:?}", e);
                     }
                     tracing::info!("connection handled in {:?}", start.elapsed());
@@ -63,23 +192,54 @@ pub async fn expose_tcp(host: String, port: u16, graceful: kulfi_utils::Graceful
 
 async fn handle_connection(
     conn: iroh::endpoint::Connection,
-    host: String,
-    port: u16,
+    self_id: iroh::EndpointId,
+    services: TcpServices,
+    acl: malai::PeerAcl,
+    access_log: kulfi_utils::AccessLog,
     graceful: kulfi_utils::Graceful,
 ) -> eyre::Result<()> {
+    acl.guard(&conn).await?;
     let remote_id52 = kulfi_utils::get_remote_id52(&conn);
+    let _active = kulfi_utils::metrics::ActivePeer::accepted(&remote_id52);
 
     tracing::info!("new client: {remote_id52}, waiting for bidirectional stream");
     loop {
-        let (send, recv) = kulfi_utils::accept_bi(&conn, kulfi_utils::Protocol::Tcp)
+        let (mut send, mut recv) = kulfi_utils::accept_bi(&conn, self_id, services.protocol())
             .await
             .inspect_err(|e| tracing::error!("failed to accept bidirectional stream: {e:?}"))?;
         tracing::info!("{remote_id52}");
-        let addr = format!("{host}:{port}");
+        let services = services.clone();
+        let access_log = access_log.clone();
+        let remote_id52 = remote_id52.clone();
         graceful.spawn(async move {
-            if let Err(e) = kulfi_utils::peer_to_tcp(&addr, send, recv).await {
-                tracing::error!("failed to proxy tcp: {e:?}");
+            let addr = match services.pick(&mut send, &mut recv).await {
+                Ok(addr) => addr,
+                Err(e) => {
+                    tracing::info!("rejecting stream from {remote_id52}: {e:?}");
+                    return;
+                }
+            };
+            let mut entry = access_log.entry(&remote_id52, addr.to_string());
+            match match &addr {
+                kulfi_utils::UpstreamAddr::Tcp(addr) => {
+                    kulfi_utils::peer_to_tcp(addr, send, recv).await
+                }
+                #[cfg(unix)]
+                kulfi_utils::UpstreamAddr::Unix(path) => {
+                    kulfi_utils::peer_to_unix(path, send, recv).await
+                }
+                #[cfg(not(unix))]
+                kulfi_utils::UpstreamAddr::Unix(_) => Err(eyre::anyhow!(
+                    "unix sockets are not supported on this platform"
+                )),
+            } {
+                Ok((bytes_in, bytes_out)) => {
+                    entry.bytes_in = bytes_in;
+                    entry.bytes_out = bytes_out;
+                }
+                Err(e) => tracing::error!("failed to proxy tcp: {e:?}"),
             }
+            access_log.log(entry);
             tracing::info!("closing send stream");
         });
     }
@@ -92,10 +252,10 @@ enum InfoMode {
 }
 
 impl InfoMode {
-    fn print(&self, port: u16, id52: &str) {
+    fn print(&self, services: &TcpServices, id52: &str) {
         use colored::Colorize;
 
-        // Malai: Sharing port <port>
+        // Malai: Sharing <host>:<port>
         // Run malai tcp-bridge <id52> <some-port> to connect to it from any machine.
         // Press ctrl+c again to exit.
 
@@ -104,12 +264,29 @@ impl InfoMode {
         }
 
         if self == &InfoMode::Startup {
-            println!("{}: Sharing port {port}", "Malai".on_green().black(),);
+            match services {
+                TcpServices::Single(kulfi_utils::UpstreamAddr::Tcp(addr)) => {
+                    println!("{}: Sharing {addr}", "Malai".on_green().black())
+                }
+                TcpServices::Single(kulfi_utils::UpstreamAddr::Unix(path)) => {
+                    println!("{}: Sharing {}", "Malai".on_green().black(), path.display())
+                }
+                TcpServices::Named(services) => {
+                    println!("{}: Sharing", "Malai".on_green().black());
+                    for (name, addr) in services.iter() {
+                        println!("  {name}: {addr}");
+                    }
+                }
+            }
         }
 
+        let service = match services {
+            TcpServices::Single(_) => "",
+            TcpServices::Named(_) => " --service <name>",
+        };
         println!(
             "Run {}",
-            format!("malai tcp-bridge {id52} <some-port>").yellow()
+            format!("malai tcp-bridge {id52} <some-port>{service}").yellow()
         );
         println!("to connect to it from any machine.");
 
@@ -118,3 +295,113 @@ impl InfoMode {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_parse_service() {
+        use kulfi_utils::UpstreamAddr;
+
+        let parse = super::TcpServices::parse_service;
+        assert_eq!(
+            parse("db=127.0.0.1:5432"),
+            Ok(("db".to_string(), UpstreamAddr::Tcp("127.0.0.1:5432".into())))
+        );
+        assert_eq!(
+            parse("v6=[::1]:22"),
+            Ok(("v6".to_string(), UpstreamAddr::Tcp("[::1]:22".into())))
+        );
+        assert_eq!(
+            parse("sock=unix:/run/app.sock"),
+            Ok((
+                "sock".to_string(),
+                UpstreamAddr::Unix("/run/app.sock".into())
+            ))
+        );
+        assert!(parse("127.0.0.1:5432").is_err());
+        assert!(parse("=127.0.0.1:5432").is_err());
+        assert!(parse("db=5432").is_err());
+        assert!(parse("db=localhost:postgres").is_err());
+
+        assert!(
+            super::TcpServices::named(vec![
+                parse("db=127.0.0.1:5432").unwrap(),
+                parse("db=127.0.0.1:5433").unwrap(),
+            ])
+            .is_err()
+        );
+    }
+
+    /// an endpoint on the local machine only, that can dial `peer`
+    async fn local_endpoint(peer: Option<&iroh::Endpoint>) -> iroh::Endpoint {
+        let discovery = iroh::discovery::static_provider::StaticProvider::new();
+        if let Some(peer) = peer {
+            discovery.add_endpoint_info(peer.addr());
+        }
+        iroh::Endpoint::builder()
+            .relay_mode(iroh::RelayMode::Disabled)
+            .discovery(discovery)
+            .alpns(vec![kulfi_utils::APNS_IDENTITY.into()])
+            .bind()
+            .await
+            .unwrap()
+    }
+
+    /// a bridge asking for a named service, of a peer sharing a single one, and the other way
+    /// round, is refused, and nothing reaches the service
+    #[tokio::test]
+    async fn test_mismatched_bridge() {
+        let service = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = kulfi_utils::UpstreamAddr::Tcp(service.local_addr().unwrap().to_string());
+
+        for (services, target) in [
+            (super::TcpServices::Single(addr.clone()), Some("db")),
+            (
+                super::TcpServices::named(vec![("db".to_string(), addr.clone())]).unwrap(),
+                None,
+            ),
+        ] {
+            let exposer = local_endpoint(None).await;
+            let bridge = local_endpoint(Some(&exposer)).await;
+            let graceful = kulfi_utils::Graceful::new();
+
+            let ep = exposer.clone();
+            let graceful_for_exposer = graceful.clone();
+            tokio::spawn(async move {
+                let conn = ep.accept().await.unwrap().await.unwrap();
+                let _ = super::handle_connection(
+                    conn,
+                    ep.id(),
+                    services,
+                    Default::default(),
+                    kulfi_utils::AccessLog::disabled(kulfi_utils::Protocol::Tcp),
+                    graceful_for_exposer,
+                )
+                .await;
+            });
+
+            let e = kulfi_utils::get_stream(
+                bridge,
+                super::TcpTarget::header(target.map(str::to_string)).unwrap(),
+                kulfi_id52::PublicKey::from_bytes(exposer.id().as_bytes())
+                    .unwrap()
+                    .to_string(),
+                Default::default(),
+                graceful,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(
+                e.downcast_ref::<kulfi_utils::http::ProxyError>(),
+                Some(&kulfi_utils::http::ProxyError::PeerRefused),
+                "{target:?}: {e:?}"
+            );
+        }
+
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), service.accept())
+                .await
+                .is_err()
+        );
+    }
+}
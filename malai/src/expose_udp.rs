@@ -0,0 +1,140 @@
+pub async fn expose_udp(
+    addr: String,
+    acl: malai::PeerAcl,
+    access_log: kulfi_utils::AccessLog,
+    graceful: kulfi_utils::Graceful,
+) {
+    let (id52, secret_key) = match kulfi_utils::read_or_create_key().await {
+        Ok(v) => v,
+        Err(e) => {
+            malai::identity_read_err_msg(e);
+            std::process::exit(1);
+        }
+    };
+
+    let ep = match kulfi_utils::get_endpoint(secret_key).await {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Failed to bind to iroh network:");
+            eprintln!("{e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    InfoMode::Startup.print(&addr, &id52);
+
+    let mut graceful_mut = graceful.clone();
+    loop {
+        let graceful_for_handle_connection = graceful.clone();
+
+        tokio::select! {
+            _ = graceful_mut.show_info() => {
+                InfoMode::OnExit.print(&addr, &id52);
+            }
+            _ = graceful.cancelled() => {
+                tracing::info!("Stopping control server.");
+                break;
+            }
+            conn = ep.accept() => {
+                let conn = match conn {
+                    Some(conn) => conn,
+                    None => {
+                        tracing::info!("no connection");
+                        break;
+                    }
+                };
+                let addr = addr.clone();
+                let acl = acl.clone();
+                let self_id = ep.id();
+                let access_log = access_log.clone();
+
+                graceful.spawn(async move {
+                    let start = std::time::Instant::now();
+                    let conn = match conn.await {
+                        Ok(c) => c,
+                        Err(e) => {
+                            tracing::error!("failed to convert incoming to connection: {:?}", e);
+                            return;
+                        }
+                    };
+                    if let Err(e) = handle_connection(conn, self_id, addr, acl, access_log, graceful_for_handle_connection).await {
+                        tracing::error!("connection error3: {:?}", e);
+                    }
+                    tracing::info!("connection handled in {:?}", start.elapsed());
+                });
+            }
+        }
+    }
+
+    ep.close().await;
+}
+
+async fn handle_connection(
+    conn: iroh::endpoint::Connection,
+    self_id: iroh::EndpointId,
+    addr: String,
+    acl: malai::PeerAcl,
+    access_log: kulfi_utils::AccessLog,
+    graceful: kulfi_utils::Graceful,
+) -> eyre::Result<()> {
+    acl.guard(&conn).await?;
+    let remote_id52 = kulfi_utils::get_remote_id52(&conn);
+    let _active = kulfi_utils::metrics::ActivePeer::accepted(&remote_id52);
+
+    tracing::info!("new client: {remote_id52}, waiting for bidirectional stream");
+    loop {
+        let (send, recv) = kulfi_utils::accept_bi(&conn, self_id, kulfi_utils::Protocol::Udp)
+            .await
+            .inspect_err(|e| tracing::error!("failed to accept bidirectional stream: {e:?}"))?;
+        tracing::info!("{remote_id52}");
+        let addr = addr.clone();
+        let access_log = access_log.clone();
+        let mut entry = access_log.entry(&remote_id52, addr.as_str());
+        let conn = conn.clone();
+        graceful.spawn(async move {
+            match kulfi_utils::peer_to_udp(&addr, conn, send, recv).await {
+                Ok((bytes_in, bytes_out)) => {
+                    entry.bytes_in = bytes_in;
+                    entry.bytes_out = bytes_out;
+                }
+                Err(e) => tracing::error!("failed to proxy udp: {e:?}"),
+            }
+            access_log.log(entry);
+            tracing::info!("closing send stream");
+        });
+    }
+}
+
+#[derive(PartialEq, Debug)]
+enum InfoMode {
+    Startup,
+    OnExit,
+}
+
+impl InfoMode {
+    fn print(&self, addr: &str, id52: &str) {
+        use colored::Colorize;
+
+        // Malai: Sharing <host>:<port>
+        // Run malai udp-bridge <id52> <some-port> to connect to it from any machine.
+        // Press ctrl+c again to exit.
+
+        if self == &InfoMode::OnExit {
+            println!();
+        }
+
+        if self == &InfoMode::Startup {
+            println!("{}: Sharing {addr} (UDP)", "Malai".on_green().black());
+        }
+
+        println!(
+            "Run {}",
+            format!("malai udp-bridge {id52} <some-port>").yellow()
+        );
+        println!("to connect to it from any machine.");
+
+        if self == &InfoMode::OnExit {
+            println!("Press ctrl+c again to exit.");
+        }
+    }
+}
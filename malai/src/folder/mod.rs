@@ -29,7 +29,14 @@ use render_folder::render_folder;
 ///
 /// having said all that, the first version of malai browsing will be a simple HTML page, and we
 /// will compile `folder.html` template as part of the build process.
-pub async fn folder(path: String, bridge: String, graceful: kulfi_utils::Graceful) {
+pub async fn folder(
+    path: String,
+    bridge: String,
+    acl: malai::PeerAcl,
+    max_concurrent_requests: usize,
+    access_log: kulfi_utils::AccessLog,
+    graceful: kulfi_utils::Graceful,
+) {
     let path = match validate_path(&path) {
         Ok(p) => p,
         Err(e) => {
@@ -59,9 +66,15 @@ pub async fn folder(path: String, bridge: String, graceful: kulfi_utils::Gracefu
 
     graceful.spawn(async move {
         malai::expose_http(
-            "127.0.0.1".to_string(),
-            port,
+            format!("127.0.0.1:{port}").into(),
             bridge,
+            acl,
+            kulfi_utils::PeerToHttpOptions {
+                access_log,
+                ..Default::default()
+            },
+            false,
+            max_concurrent_requests,
             graceful_for_expose_http,
         )
         .await
@@ -1,35 +1,59 @@
+/// HttpBridgeOptions controls which peers the bridge serves, and how it finds them.
+#[derive(Default)]
+pub struct HttpBridgeOptions {
+    /// forward every request to this id52, requests for other id52s are rejected
+    pub proxy_target: Option<String>,
+    /// which peers the bridge serves
+    pub acl: malai::PeerAcl,
+    /// custom domains, checked before the `<id52>.<bridge domain>` rule
+    pub domains: Option<malai::DomainMap>,
+    /// serve HTTPS instead of plain HTTP
+    pub tls: Option<malai::BridgeTls>,
+    /// keep the responses of peers that allow it
+    pub cache: Option<malai::BridgeCache>,
+    /// our own HTML for the error pages
+    pub error_pages: Option<malai::ErrorPages>,
+    /// also serve peers at `/p/<id52>/`, see `malai::path_routing`
+    pub path_routing: bool,
+}
+
 #[tracing::instrument(skip_all)]
 pub async fn http_bridge(
-    port: u16,
-    proxy_target: Option<String>,
+    listen: malai::Listen,
+    options: HttpBridgeOptions,
     graceful: kulfi_utils::Graceful,
     post_start: impl FnOnce(u16) -> eyre::Result<()>,
 ) {
-    use eyre::WrapErr;
-
-    let listener = match tokio::net::TcpListener::bind(format!("127.0.0.1:{port}"))
-        .await
-        .wrap_err_with(|| {
-            format!("can not listen on port {port}, is it busy, or you do not have root access?")
-        }) {
+    let listener = match listen.bind().await {
         Ok(listener) => listener,
         Err(e) => {
-            eprintln!("Failed to bind to port {port}: {e:?}");
+            eprintln!("Failed to bind to {}: {e:?}", listen.addr);
             std::process::exit(1);
         }
     };
 
     // because the caller can pass the port as 0 if they want to bind to a random port
-    let port = listener.local_addr().unwrap().port();
+    let addr = listener.local_addr().unwrap();
 
-    match post_start(port) {
+    match post_start(addr.port()) {
         Ok(_) => {}
         Err(e) => {
             eprintln!("Failed to run post start function: {e:?}");
         }
     }
 
-    println!("Listening on http://127.0.0.1:{port}");
+    let options = std::sync::Arc::new(options);
+    let scheme = if options.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    println!("Listening on {scheme}://{addr}");
+
+    #[cfg(unix)]
+    if options.tls.is_some() || options.error_pages.is_some() {
+        reload_on_sighup(options.clone(), graceful.clone());
+    }
 
     let peer_connections = kulfi_utils::PeerStreamSenders::default();
 
@@ -43,7 +67,7 @@ pub async fn http_bridge(
             r = graceful_mut.show_info() => {
                 match r {
                     Ok(_) => {
-                        println!("Listening on http://127.0.0.1:{port}");
+                        println!("Listening on {scheme}://{addr}");
                         println!("Press ctrl+c again to exit.");
                     }
                     Err(e) => {
@@ -53,21 +77,54 @@ pub async fn http_bridge(
             }
             r = listener.accept() => {
                 match r {
-                    Ok((stream, _addr)) => {
-                        tracing::info!("got connection");
+                    Ok((mut stream, peer)) => {
                         let graceful_for_handle_connection = graceful.clone();
                         let peer_connections = peer_connections.clone();
-                        let proxy_target = proxy_target.clone();
+                        let options = options.clone();
+                        let listen = listen.clone();
                         graceful.spawn(async move {
+                            // the PROXY protocol header comes before the tls handshake
+                            let client_addr = match listen.client_addr(&mut stream, peer).await {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    tracing::info!(%peer, "rejecting connection: {e:?}");
+                                    return;
+                                }
+                            };
+                            tracing::info!(%client_addr, "got connection");
+                            let stream = kulfi_utils::metrics::ClientConnection::new(stream);
                             let self_endpoint = kulfi_utils::global_iroh_endpoint().await;
-                            handle_connection(
-                                self_endpoint,
-                                stream,
-                                graceful_for_handle_connection,
-                                peer_connections,
-                                proxy_target,
-                            )
-                            .await
+                            match &options.tls {
+                                Some(tls) => {
+                                    let stream = match tls_handshake(tls, stream).await {
+                                        Ok(v) => v,
+                                        Err(e) => {
+                                            tracing::info!("tls handshake failed: {e:?}");
+                                            return;
+                                        }
+                                    };
+                                    handle_connection(
+                                        self_endpoint,
+                                        stream,
+                                        client_addr,
+                                        graceful_for_handle_connection,
+                                        peer_connections,
+                                        options.clone(),
+                                    )
+                                    .await
+                                }
+                                None => {
+                                    handle_connection(
+                                        self_endpoint,
+                                        stream,
+                                        client_addr,
+                                        graceful_for_handle_connection,
+                                        peer_connections,
+                                        options.clone(),
+                                    )
+                                    .await
+                                }
+                            }
                         });
                     }
                     Err(e) => {
@@ -80,13 +137,58 @@ pub async fn http_bridge(
     }
 }
 
-#[tracing::instrument(skip_all)]
+async fn tls_handshake<S>(
+    tls: &malai::BridgeTls,
+    stream: S,
+) -> eyre::Result<tokio_rustls::server::TlsStream<S>>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    // a client that connects and never finishes the handshake should not hold on to a task
+    let handshake = tls.acceptor().await.accept(stream);
+    Ok(tokio::time::timeout(std::time::Duration::from_secs(10), handshake).await??)
+}
+
+#[cfg(unix)]
+fn reload_on_sighup(options: std::sync::Arc<HttpBridgeOptions>, graceful: kulfi_utils::Graceful) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("failed to listen for SIGHUP: {e:?}");
+            return;
+        }
+    };
+
+    let graceful_for_task = graceful.clone();
+    graceful.spawn(async move {
+        loop {
+            tokio::select! {
+                _ = graceful_for_task.cancelled() => break,
+                _ = hangup.recv() => {
+                    if let Some(tls) = &options.tls
+                        && let Err(e) = tls.reload().await
+                    {
+                        eprintln!("Failed to reload the TLS certificate: {e:?}");
+                    }
+                    if let Some(pages) = &options.error_pages
+                        && let Err(e) = pages.reload().await
+                    {
+                        eprintln!("Failed to reload the error pages: {e:?}");
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[tracing::instrument(skip_all, fields(client = %client_addr))]
 pub async fn handle_connection(
     self_endpoint: iroh::Endpoint,
-    stream: tokio::net::TcpStream,
+    stream: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    client_addr: std::net::SocketAddr,
     graceful: kulfi_utils::Graceful,
     peer_connections: kulfi_utils::PeerStreamSenders,
-    proxy_target: Option<String>,
+    options: std::sync::Arc<HttpBridgeOptions>,
 ) {
     let io = hyper_util::rt::TokioIo::new(stream);
 
@@ -97,9 +199,9 @@ pub async fn handle_connection(
     // let builder = hyper::server::conn::http2::Builder::new(hyper_util::rt::tokio::TokioExecutor::new());
     tokio::pin! {
         let conn = builder
-            .serve_connection(
+            .serve_connection_with_upgrades(
                 io,
-                hyper::service::service_fn(|r| handle_request(r, self_endpoint.clone(), peer_connections.clone(), proxy_target.clone(), graceful.clone())),
+                hyper::service::service_fn(|r| handle_request(r, self_endpoint.clone(), client_addr, peer_connections.clone(), options.clone(), graceful.clone())),
             );
     }
 
@@ -118,34 +220,212 @@ pub async fn handle_connection(
 async fn handle_request(
     r: hyper::Request<hyper::body::Incoming>,
     self_endpoint: iroh::Endpoint,
+    client_addr: std::net::SocketAddr,
     peer_connections: kulfi_utils::PeerStreamSenders,
-    proxy_target: Option<String>,
+    options: std::sync::Arc<HttpBridgeOptions>,
     graceful: kulfi_utils::Graceful,
 ) -> kulfi_utils::http::ProxyResult<eyre::Error> {
-    let peer_id = match get_peer_id52_from_host(
-        r.headers().get("Host").and_then(|h| h.to_str().ok()),
-        proxy_target,
-    ) {
-        Ok(peer_id) => peer_id,
-        Err(e) => {
-            tracing::error!("failed to get peer id from request: {e:?}");
-            return Ok(kulfi_utils::bad_request!(
-                "failed to get peer id from request"
-            ));
+    let accept = r.headers().get(hyper::header::ACCEPT).cloned();
+    let resp = handle_request_(
+        r,
+        self_endpoint,
+        client_addr,
+        peer_connections,
+        options.clone(),
+        graceful,
+    )
+    .await?;
+
+    Ok(match &options.error_pages {
+        Some(pages) => pages.apply(resp, accept.as_ref()),
+        None => resp,
+    })
+}
+
+async fn handle_request_(
+    mut r: hyper::Request<hyper::body::Incoming>,
+    self_endpoint: iroh::Endpoint,
+    client_addr: std::net::SocketAddr,
+    peer_connections: kulfi_utils::PeerStreamSenders,
+    options: std::sync::Arc<HttpBridgeOptions>,
+    graceful: kulfi_utils::Graceful,
+) -> kulfi_utils::http::ProxyResult<eyre::Error> {
+    use std::str::FromStr;
+
+    // HTTP/2 clients send the host as part of the uri (the `:authority` pseudo header)
+    let host = r
+        .headers()
+        .get("Host")
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| r.uri().host())
+        .map(str::to_string);
+
+    let mapped = match (&options.domains, &host) {
+        (Some(domains), Some(host)) => domains.lookup(host).await,
+        _ => None,
+    };
+
+    let routed = match (&mapped, options.path_routing) {
+        (None, true) => r
+            .uri()
+            .path_and_query()
+            .and_then(|p| malai::path_routing::route(p.as_str())),
+        _ => None,
+    };
+
+    // the path prefix, if the request was routed by path
+    let mut path_prefix = None;
+    let peer_id = match (mapped, routed) {
+        (None, Some(malai::path_routing::Route::AddSlash(location))) => {
+            let mut resp =
+                kulfi_utils::http::bytes_to_resp(vec![], hyper::StatusCode::PERMANENT_REDIRECT);
+            if let Ok(location) = hyper::header::HeaderValue::from_str(&location) {
+                resp.headers_mut().insert(hyper::header::LOCATION, location);
+            }
+            return Ok(resp);
+        }
+        (
+            None,
+            Some(malai::path_routing::Route::Peer {
+                id52,
+                prefix,
+                path_and_query,
+            }),
+        ) => {
+            if options.proxy_target.as_ref().is_some_and(|t| *t != id52) {
+                tracing::info!(id52, "request for a peer other than the proxy target");
+                return Ok(kulfi_utils::http::ProxyError::PeerNotAllowed
+                    .response(r.headers().get(hyper::header::ACCEPT)));
+            }
+            match malai::path_routing::set_path_and_query(r.uri(), &path_and_query) {
+                Ok(uri) => *r.uri_mut() = uri,
+                Err(e) => {
+                    tracing::error!("failed to remove path prefix: {e:?}");
+                    return Ok(kulfi_utils::bad_request!("invalid request path"));
+                }
+            }
+            path_prefix = Some(prefix);
+            id52
+        }
+        (Some(target), _) => {
+            if let Some(prefix) = target.path_prefix {
+                match malai::domain_map::add_path_prefix(r.uri(), &prefix) {
+                    Ok(uri) => *r.uri_mut() = uri,
+                    Err(e) => {
+                        tracing::error!("failed to add path prefix: {e:?}");
+                        return Ok(kulfi_utils::bad_request!("invalid request path"));
+                    }
+                }
+            }
+            target.id52
+        }
+        (None, None) => {
+            match get_peer_id52_from_host(host.as_deref(), options.proxy_target.clone()) {
+                Ok(peer_id) => peer_id,
+                Err(e) if host.is_none() => {
+                    tracing::error!("failed to get peer id from request: {e:?}");
+                    return Ok(kulfi_utils::bad_request!(
+                        "failed to get peer id from request"
+                    ));
+                }
+                Err(e) => {
+                    tracing::info!("failed to get peer id from request: {e:?}");
+                    return Ok(kulfi_utils::http::ProxyError::InvalidPeerId
+                        .response(r.headers().get(hyper::header::ACCEPT)));
+                }
+            }
         }
     };
 
+    // no point dialing, or asking the ACL, for something that is not an id52
+    if kulfi_id52::PublicKey::from_str(&peer_id).is_err() {
+        tracing::info!(peer_id, "request for an invalid id52");
+        return Ok(kulfi_utils::http::ProxyError::InvalidPeerId
+            .response(r.headers().get(hyper::header::ACCEPT)));
+    }
+
+    // purging is up to whoever runs the bridge, so it does not go through the ACL
+    if r.method().as_str() == "PURGE" {
+        return Ok(purge(&r, &peer_id, client_addr, options.cache.as_ref()));
+    }
+
+    if let Err(e) = options.acl.check(&peer_id).await {
+        tracing::info!(peer_id, "refusing request: {e}");
+        return Ok(kulfi_utils::http::ProxyError::PeerNotAllowed
+            .response(r.headers().get(hyper::header::ACCEPT)));
+    }
+
     tracing::info!("got request for {peer_id}");
 
-    kulfi_utils::http_to_peer(
-        kulfi_utils::Protocol::Http.into(),
-        r,
+    // the upstream sees the original client and url, not the bridge
+    let version = r.version();
+    let proto = if options.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let headers = r.headers_mut();
+    kulfi_utils::http::remove_hop_by_hop_headers(headers);
+    kulfi_utils::http::set_forwarded_headers(
+        headers,
+        version,
+        client_addr.ip(),
+        proto,
+        host.as_deref(),
+    );
+    if let Some(prefix) = &path_prefix
+        && let Ok(v) = hyper::header::HeaderValue::from_str(prefix)
+    {
+        headers.insert(malai::path_routing::FORWARDED_PREFIX_HEADER, v);
+    }
+
+    let peer = malai::bridge_cache::Peer {
+        id52: peer_id,
         self_endpoint,
-        &peer_id,
         peer_connections,
         graceful,
+    };
+    let mut resp = match &options.cache {
+        Some(cache) => cache.serve(peer, r).await?,
+        None => peer.forward(r).await?,
+    };
+
+    if let Some(prefix) = &path_prefix {
+        malai::path_routing::rewrite_response(resp.headers_mut(), prefix, host.as_deref());
+    }
+    Ok(resp)
+}
+
+fn purge<B>(
+    r: &hyper::Request<B>,
+    peer_id: &str,
+    client_addr: std::net::SocketAddr,
+    cache: Option<&malai::BridgeCache>,
+) -> kulfi_utils::http::ProxyResponse<eyre::Error> {
+    let Some(cache) = cache else {
+        return kulfi_utils::http::bytes_to_resp(
+            b"the cache is not enabled, see --cache\n".to_vec(),
+            hyper::StatusCode::NOT_FOUND,
+        );
+    };
+
+    // the client address does not tell us who sent it, behind nginx on the same machine every
+    // client is on localhost
+    if !cache.can_purge(r.headers()) {
+        tracing::info!("refusing PURGE from {client_addr}");
+        return kulfi_utils::http::bytes_to_resp(
+            b"PURGE needs the token from --purge-token, as Authorization: Bearer <token>\n"
+                .to_vec(),
+            hyper::StatusCode::FORBIDDEN,
+        );
+    }
+
+    let count = cache.purge(peer_id, r.uri().path());
+    tracing::info!(peer_id, path = r.uri().path(), count, "purged");
+    kulfi_utils::http::bytes_to_resp(
+        format!("purged {count} responses\n").into_bytes(),
+        hyper::StatusCode::OK,
     )
-    .await
 }
 
 fn get_peer_id52_from_host(
@@ -1,36 +1,30 @@
 pub async fn http_proxy(
-    port: u16,
+    listen: malai::Listen,
     remote: String,
     graceful: kulfi_utils::Graceful,
     post_start: impl FnOnce(u16) -> eyre::Result<()>,
 ) {
-    use eyre::WrapErr;
-
-    let listener = match tokio::net::TcpListener::bind(format!("127.0.0.1:{port}"))
-        .await
-        .wrap_err_with(|| {
-            format!("can not listen on port {port}, is it busy, or you do not have root access?")
-        }) {
+    let listener = match listen.bind().await {
         Ok(l) => l,
         Err(e) => {
-            eprintln!("Failed to bind to port {port}: {e:?}");
+            eprintln!("Failed to bind to {}: {e:?}", listen.addr);
             std::process::exit(1);
         }
     };
 
-    let port = match listener.local_addr() {
-        Ok(addr) => addr.port(),
+    let addr = match listener.local_addr() {
+        Ok(addr) => addr,
         Err(e) => {
             eprintln!("Failed to get local address: {e:?}");
             std::process::exit(1);
         }
     };
 
-    if let Err(e) = post_start(port) {
+    if let Err(e) = post_start(addr.port()) {
         eprintln!("Failed to run post start function: {e:?}");
     }
 
-    println!("Listening on http://127.0.0.1:{port}");
+    println!("Listening on http://{addr}");
 
     let peer_connections = kulfi_utils::PeerStreamSenders::default();
 
@@ -44,7 +38,7 @@ pub async fn http_proxy(
             r = graceful_mut.show_info() => {
                 match r {
                     Ok(_) => {
-                        println!("Listening on http://127.0.0.1:{port}");
+                        println!("Listening on http://{addr}");
                         println!("Press ctrl+c again to exit.");
                     }
                     Err(e) => {
@@ -54,16 +48,26 @@ pub async fn http_proxy(
             }
             r = listener.accept() => {
                 match r {
-                    Ok((stream, _addr)) => {
-                        tracing::info!("got connection");
+                    Ok((mut stream, peer)) => {
                         let graceful_for_handle_connection = graceful.clone();
                         let peer_connections = peer_connections.clone();
                         let remote = remote.clone();
+                        let listen = listen.clone();
                         graceful.spawn(async move {
+                            let client_addr = match listen.client_addr(&mut stream, peer).await {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    tracing::info!(%peer, "rejecting connection: {e:?}");
+                                    return;
+                                }
+                            };
+                            tracing::info!(%client_addr, "got connection");
+                            let stream = kulfi_utils::metrics::ClientConnection::new(stream);
                             let self_endpoint = kulfi_utils::global_iroh_endpoint().await;
                             handle_connection(
                                 self_endpoint,
                                 stream,
+                                client_addr,
                                 graceful_for_handle_connection,
                                 peer_connections,
                                 remote,
@@ -87,9 +91,11 @@ pub enum ProxyData {
     Http { addr: String },
 }
 
+#[tracing::instrument(skip_all, fields(client = %client_addr))]
 pub async fn handle_connection(
     self_endpoint: iroh::Endpoint,
-    stream: tokio::net::TcpStream,
+    stream: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    client_addr: std::net::SocketAddr,
     graceful: kulfi_utils::Graceful,
     peer_connections: kulfi_utils::PeerStreamSenders,
     remote: String,
@@ -178,8 +184,9 @@ async fn handle_request(
         Ok(res)
     } else {
         tracing::trace!("regular (non upgrade) http request");
-        r.headers_mut().remove(hyper::header::CONNECTION);
-        kulfi_utils::http_to_peer(
+        kulfi_utils::http::remove_hop_by_hop_headers(r.headers_mut());
+        let start = std::time::Instant::now();
+        let r = kulfi_utils::http_to_peer(
             kulfi_utils::ProtocolHeader {
                 protocol: kulfi_utils::Protocol::HttpProxy,
                 extra: Some(serde_json::to_string(&ProxyData::Http {
@@ -192,7 +199,9 @@ async fn handle_request(
             peer_connections,
             graceful,
         )
-        .await
+        .await;
+        kulfi_utils::metrics::request_forwarded(kulfi_utils::Protocol::HttpProxy, start.elapsed());
+        r
     }
 }
 
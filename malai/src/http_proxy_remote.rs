@@ -1,4 +1,8 @@
-pub async fn http_proxy_remote(graceful: kulfi_utils::Graceful) {
+pub async fn http_proxy_remote(
+    acl: malai::PeerAcl,
+    access_log: kulfi_utils::AccessLog,
+    graceful: kulfi_utils::Graceful,
+) {
     let (id52, secret_key) = match kulfi_utils::read_or_create_key().await {
         Ok(v) => v,
         Err(e) => {
@@ -39,6 +43,9 @@ pub async fn http_proxy_remote(graceful: kulfi_utils::Graceful) {
 
                 let graceful_for_handle_connection = graceful.clone();
                 let http_connection_pools = http_connection_pools.clone();
+                let acl = acl.clone();
+                let self_id = ep.id();
+                let access_log = access_log.clone();
                 graceful.spawn(async move {
                     let start = std::time::Instant::now();
                     let conn = match conn.await {
@@ -48,7 +55,7 @@ pub async fn http_proxy_remote(graceful: kulfi_utils::Graceful) {
                             return;
                         }
                     };
-                    if let Err(e) = handle_connection(conn, http_connection_pools, graceful_for_handle_connection).await {
+                    if let Err(e) = handle_connection(conn, self_id, http_connection_pools, acl, access_log, graceful_for_handle_connection).await {
                         tracing::error!("connection error3: {e:?}");
                     }
                     tracing::info!("connection handled in {:?}", start.elapsed());
@@ -62,27 +69,48 @@ pub async fn http_proxy_remote(graceful: kulfi_utils::Graceful) {
 
 async fn handle_connection(
     conn: iroh::endpoint::Connection,
+    self_id: iroh::EndpointId,
     http_connection_pools: kulfi_utils::HttpConnectionPools,
+    acl: malai::PeerAcl,
+    access_log: kulfi_utils::AccessLog,
     graceful: kulfi_utils::Graceful,
 ) -> eyre::Result<()> {
+    acl.guard(&conn).await?;
     let remote_id52 = kulfi_utils::get_remote_id52(&conn);
+    let _active = kulfi_utils::metrics::ActivePeer::accepted(&remote_id52);
+    let options = kulfi_utils::PeerToHttpOptions {
+        access_log: access_log.clone(),
+        ..Default::default()
+    };
 
     tracing::info!("new client: {remote_id52}, waiting for bidirectional stream");
     loop {
         let (extra, mut send, recv): (malai::ProxyData, _, _) =
-            kulfi_utils::accept_bi_with(&conn, kulfi_utils::Protocol::HttpProxy)
+            kulfi_utils::accept_bi_with(&conn, self_id, kulfi_utils::Protocol::HttpProxy)
                 .await
                 .inspect_err(|e| tracing::error!("failed to accept bidirectional stream: {e:?}"))?;
         tracing::info!("got connection from {remote_id52}, extra: {extra:?}");
 
         let http_connection_pools = http_connection_pools.clone();
+        let remote_id52 = remote_id52.clone();
+        let access_log = access_log.clone();
+        let options = options.clone();
         graceful.spawn(async move {
             if let Err(e) = match extra {
                 malai::ProxyData::Connect { addr } => {
-                    kulfi_utils::peer_to_tcp(&addr, send, recv).await
+                    let stream = proxy_dial(&addr).await;
+                    proxy_pipe(&addr, &remote_id52, &access_log, stream, send, recv).await
                 }
                 malai::ProxyData::Http { addr } => {
-                    kulfi_utils::peer_to_http(&addr, http_connection_pools, &mut send, recv).await
+                    kulfi_utils::peer_to_http(
+                        &addr.into(),
+                        &remote_id52,
+                        &options,
+                        http_connection_pools,
+                        &mut send,
+                        recv,
+                    )
+                    .await
                 }
             } {
                 tracing::error!("failed to proxy tcp: {e:?}");
@@ -92,6 +120,68 @@ async fn handle_connection(
     }
 }
 
+/// connects to `addr` for a CONNECT request to `malai http-proxy`, or a CONNECT command to
+/// `malai socks5`. if we could not, the error comes with what the app should be told about why.
+pub async fn proxy_dial(
+    addr: &str,
+) -> Result<tokio::net::TcpStream, (std::io::Error, malai::ConnectResult)> {
+    // a name that does not resolve does not have an `ErrorKind` of its own
+    let addrs: Vec<_> = match tokio::net::lookup_host(addr).await {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => return Err((e, malai::ConnectResult::HostUnreachable)),
+    };
+    if addrs.is_empty() {
+        return Err((
+            std::io::Error::other(format!("{addr} did not resolve to any address")),
+            malai::ConnectResult::HostUnreachable,
+        ));
+    }
+
+    tokio::net::TcpStream::connect(addrs.as_slice())
+        .await
+        .map_err(|e| {
+            let result = match e.kind() {
+                std::io::ErrorKind::ConnectionRefused => malai::ConnectResult::ConnectionRefused,
+                std::io::ErrorKind::HostUnreachable
+                | std::io::ErrorKind::NetworkUnreachable
+                | std::io::ErrorKind::TimedOut => malai::ConnectResult::HostUnreachable,
+                _ => malai::ConnectResult::Failed,
+            };
+            (e, result)
+        })
+}
+
+/// pipes the connection `proxy_dial()` made to the peer's stream, or finishes the stream if there
+/// is none, and records the CONNECT in the access log
+pub async fn proxy_pipe(
+    addr: &str,
+    remote_id52: &str,
+    access_log: &kulfi_utils::AccessLog,
+    stream: Result<tokio::net::TcpStream, (std::io::Error, malai::ConnectResult)>,
+    mut send: iroh::endpoint::SendStream,
+    recv: iroh::endpoint::RecvStream,
+) -> eyre::Result<()> {
+    let mut entry = access_log.entry(remote_id52, addr);
+    entry.method = Some("CONNECT".to_string());
+
+    let r = match stream {
+        Ok(stream) => {
+            let (tcp_recv, tcp_send) = tokio::io::split(stream);
+            kulfi_utils::pipe_tcp_stream_over_iroh(tcp_recv, tcp_send, send, recv).await
+        }
+        Err((e, _)) => {
+            send.finish()?;
+            Err(eyre::Report::new(e).wrap_err(format!("failed to connect to {addr}")))
+        }
+    };
+    if let Ok((bytes_in, bytes_out)) = r {
+        entry.bytes_in = bytes_in;
+        entry.bytes_out = bytes_out;
+    }
+    access_log.log(entry);
+    r.map(|_| ())
+}
+
 #[derive(PartialEq, Debug)]
 enum InfoMode {
     Startup,
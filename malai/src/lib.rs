@@ -8,27 +8,62 @@ use clap as _;
 use clap_verbosity_flag as _;
 use tracing_subscriber as _;
 
+mod acl;
+pub mod bridge_cache;
+mod bridge_tls;
 mod browse;
+mod connect;
+mod domain_map;
+mod error_pages;
 mod expose_http;
 mod expose_tcp;
+mod expose_udp;
 mod folder;
 mod http_bridge;
 mod http_proxy;
 mod http_proxy_remote;
 mod keygen;
+mod listen;
+mod metrics;
+pub mod path_routing;
+pub mod proxy_protocol;
 mod run;
+pub mod socks;
+mod socks5;
+mod socks5_remote;
 mod tcp_bridge;
+mod tcp_reverse;
+mod tcp_reverse_remote;
+mod time;
+mod udp_bridge;
+mod watched_file;
 
+pub use acl::{PEER_NOT_ALLOWED, PeerAcl};
+pub use bridge_cache::{BridgeCache, CacheConfig};
+pub use bridge_tls::BridgeTls;
 pub use browse::browse;
+pub use connect::connect;
+pub use domain_map::{DomainMap, DomainTarget};
+pub use error_pages::ErrorPages;
 pub use expose_http::expose_http;
-pub use expose_tcp::expose_tcp;
+pub use expose_tcp::{TcpServices, TcpTarget, expose_tcp};
+pub use expose_udp::expose_udp;
 pub use folder::folder;
-pub use http_bridge::http_bridge;
+pub use http_bridge::{HttpBridgeOptions, http_bridge};
 pub use http_proxy::{ProxyData, http_proxy};
-pub use http_proxy_remote::http_proxy_remote;
+pub use http_proxy_remote::{http_proxy_remote, proxy_dial, proxy_pipe};
 pub use keygen::keygen;
+pub use listen::Listen;
+pub use metrics::serve_metrics;
 pub use run::run;
+pub use socks5::{ConnectResult, Socks5Data, socks5};
+pub use socks5_remote::socks5_remote;
 pub use tcp_bridge::tcp_bridge;
+pub use tcp_reverse::tcp_reverse;
+pub use tcp_reverse_remote::{ReverseReply, ReverseRequest, tcp_reverse_remote};
+pub use time::time;
+pub use udp_bridge::udp_bridge;
+pub use watched_file::WatchedFile;
 
 #[cfg(feature = "ui")]
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -41,21 +76,27 @@ pub fn ui() -> eyre::Result<()> {
     Ok(())
 }
 
-pub fn public_check(public: bool, service: &str, cmd: &str) -> bool {
+pub fn public_check(public: bool, acl: &malai::PeerAcl, service: &str, cmd: &str) -> bool {
     use colored::Colorize;
 
-    if !public {
-        tracing::info!("--public not passed. Quitting!");
+    // an allow list is an explicit choice of who can access the service, so --public is not
+    // needed in that case.
+    if !public && !acl.is_restricted() {
+        tracing::info!("--public or --allow not passed. Quitting!");
         eprintln!(
             "You need to pass --public to expose the {service}. \
                     This is a security feature to prevent exposing your service \
                     to the public without your knowledge."
         );
         eprintln!("Instead, run: {}", cmd.yellow());
-        eprintln!("In future, we will add a way to add access control.");
+        eprintln!(
+            "Or use {} to only allow specific peers.",
+            "--allow <id52> / --allow-file <file>".yellow()
+        );
+        return false;
     }
 
-    public
+    true
 }
 
 pub fn identity_read_err_msg(e: eyre::Report) {
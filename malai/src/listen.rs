@@ -0,0 +1,55 @@
+/// Listen is where a bridge or proxy accepts connections.
+///
+/// by default we only listen on localhost. when running on a server, say behind a load balancer,
+/// `--bind` can be any IPv4 or IPv6 address, and with `--proxy-protocol` every connection must
+/// start with a PROXY protocol header, so we know the address of the real client.
+#[derive(Clone, Debug)]
+pub struct Listen {
+    pub addr: std::net::SocketAddr,
+    pub proxy_protocol: bool,
+}
+
+impl Listen {
+    /// listen on localhost, without the PROXY protocol. pass 0 to bind to a random port.
+    pub fn local(port: u16) -> Self {
+        Self {
+            addr: (std::net::Ipv4Addr::LOCALHOST, port).into(),
+            proxy_protocol: false,
+        }
+    }
+
+    pub async fn bind(&self) -> eyre::Result<tokio::net::TcpListener> {
+        use eyre::WrapErr;
+
+        tokio::net::TcpListener::bind(self.addr)
+            .await
+            .wrap_err_with(|| {
+                format!(
+                    "can not listen on {}, is it busy, or you do not have root access?",
+                    self.addr
+                )
+            })
+    }
+
+    /// the address of the client for a connection we just accepted from `peer`. with the PROXY
+    /// protocol this reads the header, and errors if it is missing.
+    pub async fn client_addr(
+        &self,
+        stream: &mut tokio::net::TcpStream,
+        peer: std::net::SocketAddr,
+    ) -> eyre::Result<std::net::SocketAddr> {
+        if !self.proxy_protocol {
+            return Ok(peer);
+        }
+
+        // the load balancer sends the header right away
+        let header = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            malai::proxy_protocol::read_header(stream),
+        )
+        .await
+        .map_err(|_| eyre::anyhow!("timed out reading the PROXY protocol header"))??;
+
+        Ok(header.unwrap_or(peer))
+    }
+}
@@ -8,62 +8,294 @@
 async fn main() -> eyre::Result<()> {
     use clap::Parser;
 
-    // run with RUST_LOG="malai=trace,kulfi_utils=trace" to see logs
-    tracing_subscriber::fmt::init();
-
     let cli = Cli::parse();
 
+    // run with RUST_LOG="malai=trace,kulfi_utils=trace" to see logs
+    if matches!(cli.command, Some(Command::Connect { .. })) {
+        // stdout carries the stream
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .with_writer(std::io::stderr)
+            .init();
+    } else {
+        tracing_subscriber::fmt::init();
+    }
+
     let graceful = kulfi_utils::Graceful::default();
 
     match cli.command {
         Some(Command::Http {
             port,
             host,
+            unix,
             bridge,
             public,
-            // secure,
+            acl,
+            access_log,
+            secure,
+            ca_bundle,
+            sni,
+            accept_invalid_certs,
+            h2c,
+            connect_timeout,
+            read_timeout,
+            forward_peer_id52,
+            sign_peer_id52,
+            trusted_bridge,
+            max_concurrent_requests,
             // what_to_do,
+            metrics,
         }) => {
+            metrics.serve(&graceful);
+            let (addr, service) = upstream_addr(host, port, unix);
+            let acl = acl.into_acl().await;
             if !malai::public_check(
                 public,
+                &acl,
                 "HTTP service",
-                &format!("malai http {port} --public"),
+                &format!("malai http {service} --public"),
             ) {
                 return Ok(());
             }
 
-            tracing::info!(port, host, verbose = ?cli.verbose, "Exposing HTTP service on kulfi.");
+            let tls = if secure {
+                if accept_invalid_certs {
+                    eprintln!(
+                        "Warning: --accept-invalid-certs is set, the certificate of the HTTPS \
+                        service will not be verified."
+                    );
+                }
+                match kulfi_utils::UpstreamTls::new(ca_bundle.as_deref(), sni, accept_invalid_certs)
+                {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        eprintln!("Failed to set up TLS for the HTTPS service: {e:?}");
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+            let options = kulfi_utils::PeerToHttpOptions {
+                forward_peer_id52: forward_peer_id52 || sign_peer_id52,
+                // expose_http() adds the key, it is read there
+                signing_key: None,
+                access_log: access_log.into_access_log(kulfi_utils::Protocol::Http),
+                trusted_bridges: trusted_bridge.iter().map(ToString::to_string).collect(),
+            };
+            let upstream = kulfi_utils::HttpUpstream {
+                addr,
+                tls,
+                h2c,
+                connect_timeout: std::time::Duration::from_secs(connect_timeout),
+                read_timeout: std::time::Duration::from_secs(read_timeout),
+            };
+
+            tracing::info!(%upstream, secure, h2c, verbose = ?cli.verbose, "Exposing HTTP service on kulfi.");
             let graceful_for_export_http = graceful.clone();
             graceful.spawn(async move {
-                malai::expose_http(host, port, bridge, graceful_for_export_http).await
+                malai::expose_http(
+                    upstream,
+                    bridge,
+                    acl,
+                    options,
+                    sign_peer_id52,
+                    max_concurrent_requests,
+                    graceful_for_export_http,
+                )
+                .await
             });
         }
-        Some(Command::HttpBridge { proxy_target, port }) => {
-            tracing::info!(port, proxy_target, verbose = ?cli.verbose, "Starting HTTP bridge.");
+        Some(Command::HttpBridge {
+            proxy_target,
+            port,
+            tls_cert,
+            tls_key,
+            domains,
+            listen,
+            acl,
+            cache,
+            error_pages,
+            path_routing,
+            identity,
+            metrics,
+        }) => {
+            identity.init().await;
+            metrics.serve(&graceful);
+            let acl = acl.into_acl().await;
+            if path_routing && proxy_target.is_none() && !acl.is_restricted() {
+                eprintln!(
+                    "--path-routing serves every peer from the same origin, so any share could \
+                     read the cookies and storage of the others. Pass --allow, --allow-file or \
+                     --proxy-target to limit it to peers that trust each other."
+                );
+                std::process::exit(1);
+            }
+            let tls = match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => match malai::BridgeTls::new(cert, key).await {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        eprintln!("Failed to load the TLS certificate: {e:?}");
+                        std::process::exit(1);
+                    }
+                },
+                _ => None,
+            };
+            let domains = match domains {
+                Some(path) => match malai::DomainMap::new(&path).await {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        eprintln!("Failed to read domains file {path:?}: {e:?}");
+                        std::process::exit(1);
+                    }
+                },
+                None => None,
+            };
+            let cache = cache.into_cache().await;
+            let error_pages = match error_pages {
+                Some(dir) => match malai::ErrorPages::new(&dir).await {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        eprintln!("Failed to read error pages from {dir:?}: {e:?}");
+                        std::process::exit(1);
+                    }
+                },
+                None => None,
+            };
+
+            let listen = listen.into_listen(port);
+            tracing::info!(addr = %listen.addr, proxy_target, tls = tls.is_some(), verbose = ?cli.verbose, "Starting HTTP bridge.");
             let graceful_for_http_bridge = graceful.clone();
             graceful.spawn(async move {
-                malai::http_bridge(port, proxy_target, graceful_for_http_bridge, |_| Ok(())).await
+                malai::http_bridge(
+                    listen,
+                    malai::HttpBridgeOptions {
+                        proxy_target,
+                        acl,
+                        domains,
+                        tls,
+                        cache,
+                        error_pages,
+                        path_routing,
+                    },
+                    graceful_for_http_bridge,
+                    |_| Ok(()),
+                )
+                .await
             });
         }
-        Some(Command::Tcp { port, host, public }) => {
+        Some(Command::Tcp {
+            port,
+            host,
+            unix,
+            service,
+            public,
+            acl,
+            access_log,
+            metrics,
+        }) => {
+            metrics.serve(&graceful);
+            let (services, service) = if service.is_empty() {
+                let (addr, service) = upstream_addr(host, port, unix);
+                (malai::TcpServices::Single(addr), service)
+            } else {
+                let arg = service
+                    .iter()
+                    .map(|(name, addr)| format!("{name}={addr}"))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                match malai::TcpServices::named(service) {
+                    Ok(v) => (v, format!("--service {arg}")),
+                    Err(e) => {
+                        eprintln!("{e}");
+                        std::process::exit(1);
+                    }
+                }
+            };
+            let acl = acl.into_acl().await;
             if !malai::public_check(
                 public,
-                "HTTP service",
-                &format!("malai http {port} --public"),
+                &acl,
+                "TCP service",
+                &format!("malai tcp {service} --public"),
             ) {
                 return Ok(());
             }
 
-            tracing::info!(port, host, verbose = ?cli.verbose, "Exposing TCP service on kulfi.");
+            let access_log = access_log.into_access_log(kulfi_utils::Protocol::Tcp);
+
+            tracing::info!(?services, verbose = ?cli.verbose, "Exposing TCP service on kulfi.");
             let graceful_for_expose_tcp = graceful.clone();
-            graceful
-                .spawn(async move { malai::expose_tcp(host, port, graceful_for_expose_tcp).await });
+            graceful.spawn(async move {
+                malai::expose_tcp(services, acl, access_log, graceful_for_expose_tcp).await
+            });
         }
-        Some(Command::TcpBridge { proxy_target, port }) => {
-            tracing::info!(port, proxy_target, verbose = ?cli.verbose, "Starting TCP bridge.");
+        Some(Command::TcpBridge {
+            proxy_target,
+            port,
+            service,
+            listen,
+            identity,
+            metrics,
+        }) => {
+            identity.init().await;
+            metrics.serve(&graceful);
+            let listen = listen.into_listen(port);
+            tracing::info!(addr = %listen.addr, proxy_target, service, verbose = ?cli.verbose, "Starting TCP bridge.");
             let graceful_for_tcp_bridge = graceful.clone();
             graceful.spawn(async move {
-                malai::tcp_bridge(port, proxy_target, graceful_for_tcp_bridge).await
+                malai::tcp_bridge(listen, proxy_target, service, graceful_for_tcp_bridge).await
+            });
+        }
+        Some(Command::Udp {
+            port,
+            host,
+            public,
+            acl,
+            access_log,
+            metrics,
+        }) => {
+            metrics.serve(&graceful);
+            let acl = acl.into_acl().await;
+            if !malai::public_check(
+                public,
+                &acl,
+                "UDP service",
+                &format!("malai udp {port} --public"),
+            ) {
+                return Ok(());
+            }
+
+            let access_log = access_log.into_access_log(kulfi_utils::Protocol::Udp);
+
+            let addr = format!("{host}:{port}");
+            tracing::info!(%addr, verbose = ?cli.verbose, "Exposing UDP service on kulfi.");
+            let graceful_for_expose_udp = graceful.clone();
+            graceful.spawn(async move {
+                malai::expose_udp(addr, acl, access_log, graceful_for_expose_udp).await
+            });
+        }
+        Some(Command::UdpBridge {
+            proxy_target,
+            port,
+            bind,
+            idle_timeout,
+            identity,
+            metrics,
+        }) => {
+            identity.init().await;
+            metrics.serve(&graceful);
+            let addr = std::net::SocketAddr::new(bind, port);
+            tracing::info!(%addr, proxy_target, verbose = ?cli.verbose, "Starting UDP bridge.");
+            let graceful_for_udp_bridge = graceful.clone();
+            graceful.spawn(async move {
+                malai::udp_bridge(
+                    addr,
+                    proxy_target,
+                    std::time::Duration::from_secs(idle_timeout),
+                    graceful_for_udp_bridge,
+                )
+                .await
             });
         }
         Some(Command::Browse { url }) => {
@@ -75,39 +307,183 @@ async fn main() -> eyre::Result<()> {
             path,
             bridge,
             public,
+            acl,
+            access_log,
+            max_concurrent_requests,
+            metrics,
         }) => {
-            if !malai::public_check(public, "folder", &format!("malai folder --public {path}")) {
+            metrics.serve(&graceful);
+            let acl = acl.into_acl().await;
+            if !malai::public_check(
+                public,
+                &acl,
+                "folder",
+                &format!("malai folder --public {path}"),
+            ) {
                 return Ok(());
             }
 
+            let access_log = access_log.into_access_log(kulfi_utils::Protocol::Http);
+
             tracing::info!(path, verbose = ?cli.verbose, "Exposing folder to kulfi network.");
             let graceful_for_folder = graceful.clone();
-            graceful.spawn(async move { malai::folder(path, bridge, graceful_for_folder).await });
+            graceful.spawn(async move {
+                malai::folder(
+                    path,
+                    bridge,
+                    acl,
+                    max_concurrent_requests,
+                    access_log,
+                    graceful_for_folder,
+                )
+                .await
+            });
         }
         Some(Command::Run { home }) => {
             tracing::info!(verbose = ?cli.verbose, "Running all services.");
             let graceful_for_run = graceful.clone();
             graceful.spawn(async move { malai::run(home, graceful_for_run).await });
         }
-        Some(Command::HttpProxyRemote { public }) => {
+        Some(Command::HttpProxyRemote {
+            public,
+            acl,
+            access_log,
+            metrics,
+        }) => {
+            metrics.serve(&graceful);
+            let acl = acl.into_acl().await;
             if !malai::public_check(
                 public,
+                &acl,
                 "http-proxy-remote",
                 "malai http-proxy-remote --public",
             ) {
                 return Ok(());
             }
+            let access_log = access_log.into_access_log(kulfi_utils::Protocol::HttpProxy);
+
             tracing::info!(verbose = ?cli.verbose, "Running HTTP Proxy Remote.");
             let graceful_for_run = graceful.clone();
-            graceful.spawn(async move { malai::http_proxy_remote(graceful_for_run).await });
+            graceful.spawn(async move {
+                malai::http_proxy_remote(acl, access_log, graceful_for_run).await
+            });
         }
-        Some(Command::HttpProxy { remote, port }) => {
-            tracing::info!(port, remote, verbose = ?cli.verbose, "Starting HTTP Proxy.");
+        Some(Command::HttpProxy {
+            remote,
+            port,
+            listen,
+            identity,
+            metrics,
+        }) => {
+            identity.init().await;
+            metrics.serve(&graceful);
+            let listen = listen.into_listen(port);
+            tracing::info!(addr = %listen.addr, remote, verbose = ?cli.verbose, "Starting HTTP Proxy.");
             let graceful_for_tcp_bridge = graceful.clone();
             graceful.spawn(async move {
-                malai::http_proxy(port, remote, graceful_for_tcp_bridge, |_| Ok(())).await
+                malai::http_proxy(listen, remote, graceful_for_tcp_bridge, |_| Ok(())).await
+            });
+        }
+        Some(Command::Socks5Remote {
+            public,
+            acl,
+            access_log,
+            metrics,
+        }) => {
+            metrics.serve(&graceful);
+            let acl = acl.into_acl().await;
+            if !malai::public_check(
+                public,
+                &acl,
+                "socks5-remote",
+                "malai socks5-remote --public",
+            ) {
+                return Ok(());
+            }
+            let access_log = access_log.into_access_log(kulfi_utils::Protocol::Socks5);
+
+            tracing::info!(verbose = ?cli.verbose, "Running SOCKS5 Remote.");
+            let graceful_for_run = graceful.clone();
+            graceful.spawn(
+                async move { malai::socks5_remote(acl, access_log, graceful_for_run).await },
+            );
+        }
+        Some(Command::Socks5 {
+            remote,
+            port,
+            listen,
+            username,
+            password,
+            identity,
+            metrics,
+        }) => {
+            identity.init().await;
+            metrics.serve(&graceful);
+            let listen = listen.into_listen(port);
+            let credentials = username
+                .zip(password)
+                .map(|(username, password)| malai::socks::Credentials { username, password });
+            tracing::info!(addr = %listen.addr, remote, verbose = ?cli.verbose, "Starting SOCKS5 Proxy.");
+            let graceful_for_socks5 = graceful.clone();
+            graceful.spawn(async move {
+                malai::socks5(listen, remote, credentials, graceful_for_socks5).await
+            });
+        }
+        Some(Command::TcpReverse {
+            remote,
+            remote_port,
+            port,
+            host,
+            unix,
+        }) => {
+            let (addr, _) = upstream_addr(host, port, unix);
+            tracing::info!(remote, remote_port, %addr, verbose = ?cli.verbose, "Opening reverse tunnel.");
+            let graceful_for_tcp_reverse = graceful.clone();
+            graceful.spawn(async move {
+                malai::tcp_reverse(remote, remote_port, addr, graceful_for_tcp_reverse).await
+            });
+        }
+        Some(Command::TcpReverseRemote {
+            bind,
+            public,
+            acl,
+            access_log,
+            metrics,
+        }) => {
+            metrics.serve(&graceful);
+            let acl = acl.into_acl().await;
+            if !malai::public_check(
+                public,
+                &acl,
+                "reverse tunnel service",
+                "malai tcp-reverse-remote --public",
+            ) {
+                return Ok(());
+            }
+
+            let access_log = access_log.into_access_log(kulfi_utils::Protocol::TcpReverse);
+
+            tracing::info!(%bind, verbose = ?cli.verbose, "Accepting reverse tunnels.");
+            let graceful_for_tcp_reverse_remote = graceful.clone();
+            graceful.spawn(async move {
+                malai::tcp_reverse_remote(bind, acl, access_log, graceful_for_tcp_reverse_remote)
+                    .await
             });
         }
+        Some(Command::Connect {
+            proxy_target,
+            service,
+            identity,
+        }) => {
+            identity.init_quietly().await;
+            malai::connect(proxy_target, service, graceful).await;
+            return Ok(());
+        }
+        Some(Command::Time { peers, identity }) => {
+            identity.init().await;
+            malai::time(peers).await;
+            return Ok(());
+        }
         Some(Command::Keygen { file }) => {
             tracing::info!(verbose = ?cli.verbose, "Generating new identity.");
             malai::keygen(file);
@@ -154,13 +530,21 @@ pub enum Command {
     // argument to specify a What To Do service that can be used to add access control."
     #[clap(about = "Expose HTTP Service on kulfi, connect using kulfi or browser")]
     Http {
-        port: u16,
+        #[arg(required_unless_present = "unix")]
+        port: Option<u16>,
         #[arg(
             long,
             default_value = "127.0.0.1",
             help = "Host serving the http service."
         )]
         host: String,
+        #[arg(
+            long,
+            value_name = "PATH",
+            conflicts_with_all = ["port", "host"],
+            help = "Unix socket the http service listens on, instead of host and port."
+        )]
+        unix: Option<std::path::PathBuf>,
         #[arg(
             long,
             default_value = "kulfi.site",
@@ -173,18 +557,86 @@ pub enum Command {
             help = "Make the exposed service public. Anyone will be able to access."
         )]
         public: bool,
-        // #[arg(
-        //     long,
-        //     default_value_t = false,
-        //     help = "Use this if the service is HTTPS"
-        // )]
-        // secure: bool,
+        #[command(flatten)]
+        acl: AclArgs,
+        #[command(flatten)]
+        access_log: AccessLogArgs,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Use this if the service is HTTPS"
+        )]
+        secure: bool,
+        #[arg(
+            long,
+            value_name = "FILE",
+            requires = "secure",
+            help = "PEM file with the CA certificates to verify the HTTPS service with, instead of the bundled web roots."
+        )]
+        ca_bundle: Option<std::path::PathBuf>,
+        #[arg(
+            long,
+            requires = "secure",
+            help = "Server name to send in the TLS handshake and verify the certificate against. Defaults to --host."
+        )]
+        sni: Option<String>,
+        #[arg(
+            long,
+            requires = "secure",
+            help = "Do not verify the certificate of the HTTPS service. Only use this for local development."
+        )]
+        accept_invalid_certs: bool,
+        #[arg(
+            long,
+            conflicts_with = "secure",
+            help = "Talk HTTP/2 without TLS to the service (h2c prior knowledge). Use this for gRPC services."
+        )]
+        h2c: bool,
+        #[arg(
+            long,
+            default_value_t = kulfi_utils::DEFAULT_CONNECT_TIMEOUT.as_secs(),
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Seconds to wait for a connection to the HTTP service. Peers get a 504 if it takes longer."
+        )]
+        connect_timeout: u64,
+        #[arg(
+            long,
+            default_value_t = kulfi_utils::DEFAULT_READ_TIMEOUT.as_secs(),
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Seconds to wait for the HTTP service to start responding to a request. Peers get a 504 if it takes longer."
+        )]
+        read_timeout: u64,
+        #[arg(
+            long,
+            help = "Add the X-Kulfi-Peer-Id52 header, with the id52 of the peer making the request, to every request sent to the HTTP service."
+        )]
+        forward_peer_id52: bool,
+        #[arg(
+            long,
+            help = "Like --forward-peer-id52, but also add X-Kulfi-Peer-Timestamp and X-Kulfi-Peer-Signature headers, signed with the key of this malai instance."
+        )]
+        sign_peer_id52: bool,
+        #[arg(
+            long,
+            value_name = "ID52",
+            help = "Only pass the Forwarded and X-Forwarded-* headers to the HTTP service for requests coming through this bridge. Can be passed multiple times. By default they are passed for every peer."
+        )]
+        trusted_bridge: Vec<kulfi_id52::PublicKey>,
+        #[arg(
+            long,
+            default_value_t = kulfi_utils::DEFAULT_MAX_CONCURRENT_REQUESTS,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+            help = "How many requests from a single peer are handled at the same time, the rest wait."
+        )]
+        max_concurrent_requests: usize,
         // #[arg(
         //     long,
         //     help = "The What To Do Service that can be used to add access control."
         // )]
         // this will be the id52 of the identity server that should be consulted
         // what_to_do: Option<String>,
+        #[command(flatten)]
+        metrics: MetricsArgs,
     },
     #[clap(about = "Browse a kulfi site.")]
     Browse {
@@ -193,18 +645,62 @@ pub enum Command {
     },
     #[clap(about = "Expose TCP Service on kulfi.")]
     Tcp {
-        port: u16,
+        #[arg(required_unless_present_any = ["unix", "service"])]
+        port: Option<u16>,
         #[arg(
             long,
             default_value = "127.0.0.1",
             help = "Host serving the TCP service."
         )]
         host: String,
+        #[arg(
+            long,
+            value_name = "PATH",
+            conflicts_with_all = ["port", "host"],
+            help = "Unix socket the TCP service listens on, instead of host and port."
+        )]
+        unix: Option<std::path::PathBuf>,
+        #[arg(
+            long,
+            value_name = "NAME=ADDR",
+            value_delimiter = ',',
+            value_parser = malai::TcpServices::parse_service,
+            conflicts_with_all = ["port", "host", "unix"],
+            help = "Share several services, say `db=127.0.0.1:5432,redis=127.0.0.1:6379`, or `name=unix:/path` for a unix socket. Bridges pick one with `malai tcp-bridge --service <name>`."
+        )]
+        service: Vec<(String, kulfi_utils::UpstreamAddr)>,
+        #[arg(
+            long,
+            help = "Make the exposed service public. Anyone will be able to access."
+        )]
+        public: bool,
+        #[command(flatten)]
+        acl: AclArgs,
+        #[command(flatten)]
+        access_log: AccessLogArgs,
+        #[command(flatten)]
+        metrics: MetricsArgs,
+    },
+    #[clap(about = "Expose UDP Service on kulfi.")]
+    Udp {
+        port: u16,
+        #[arg(
+            long,
+            default_value = "127.0.0.1",
+            help = "Host serving the UDP service."
+        )]
+        host: String,
         #[arg(
             long,
             help = "Make the exposed service public. Anyone will be able to access."
         )]
         public: bool,
+        #[command(flatten)]
+        acl: AclArgs,
+        #[command(flatten)]
+        access_log: AccessLogArgs,
+        #[command(flatten)]
+        metrics: MetricsArgs,
     },
     #[clap(
         about = "Run an http server that forwards requests to the given id52 taken from the HOST header"
@@ -223,6 +719,47 @@ pub enum Command {
             default_value = "0"
         )]
         port: u16,
+        #[arg(
+            long,
+            value_name = "FILE",
+            requires = "tls_key",
+            help = "Serve HTTPS with this PEM certificate chain, say a wildcard certificate for the bridge domain. Re-read when it changes or on SIGHUP."
+        )]
+        tls_cert: Option<std::path::PathBuf>,
+        #[arg(
+            long,
+            value_name = "FILE",
+            requires = "tls_cert",
+            help = "The PEM private key for --tls-cert."
+        )]
+        tls_key: Option<std::path::PathBuf>,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Serve peers on custom domains. One `<hostname> <id52> [path prefix]` per line, `*.example.com` matches all subdomains. The file is re-read when it changes."
+        )]
+        domains: Option<std::path::PathBuf>,
+        #[arg(
+            long,
+            value_name = "DIR",
+            help = "Use the HTML files in this directory for error pages, named after the x-kulfi-error code (peer-unreachable.html) or status (502.html), with error.html for the rest. They can use {{status}}, {{reason}}, {{message}} and {{code}}. Re-read on SIGHUP."
+        )]
+        error_pages: Option<std::path::PathBuf>,
+        #[arg(
+            long,
+            help = "Also serve peers at /p/<id52>/ on any host, for when wildcard DNS is not an option. The prefix is removed before forwarding, sent in X-Forwarded-Prefix, and added back to Location headers and cookie paths. WARNING: every peer served this way shares one origin, so a page from one share can read the cookies and storage of the others. Needs --allow, --allow-file or --proxy-target, for peers that trust each other."
+        )]
+        path_routing: bool,
+        #[command(flatten)]
+        listen: ListenArgs,
+        #[command(flatten)]
+        acl: AclArgs,
+        #[command(flatten)]
+        cache: CacheArgs,
+        #[command(flatten)]
+        identity: IdentityArgs,
+        #[command(flatten)]
+        metrics: MetricsArgs,
     },
     #[clap(about = "Run a TCP server that forwards incoming requests to the given id52.")]
     TcpBridge {
@@ -233,6 +770,115 @@ pub enum Command {
             default_value = "0"
         )]
         port: u16,
+        #[arg(
+            long,
+            help = "The service to connect to, when the peer shares several with `malai tcp --service`."
+        )]
+        service: Option<String>,
+        #[command(flatten)]
+        listen: ListenArgs,
+        #[command(flatten)]
+        identity: IdentityArgs,
+        #[command(flatten)]
+        metrics: MetricsArgs,
+    },
+    #[clap(about = "Run a UDP server that forwards incoming datagrams to the given id52.")]
+    UdpBridge {
+        #[arg(help = "The id52 to which this bridge will forward incoming UDP datagrams.")]
+        proxy_target: String,
+        #[arg(
+            help = "The port on which this bridge will listen for incoming UDP datagrams. If you pass 0, it will bind to a random port.",
+            default_value = "0"
+        )]
+        port: u16,
+        #[arg(
+            long,
+            default_value = "127.0.0.1",
+            help = "The IPv4 or IPv6 address to listen on, say 0.0.0.0 or :: to accept datagrams from other machines."
+        )]
+        bind: std::net::IpAddr,
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            default_value = "60",
+            help = "Close the flow of a client that has not sent or received a datagram for this long."
+        )]
+        idle_timeout: u64,
+        #[command(flatten)]
+        identity: IdentityArgs,
+        #[command(flatten)]
+        metrics: MetricsArgs,
+    },
+    #[clap(
+        about = "Share a TCP service through a peer running `malai tcp-reverse-remote`, for when others can not connect to this machine."
+    )]
+    TcpReverse {
+        #[arg(help = "The id52 of the peer running `malai tcp-reverse-remote`.")]
+        remote: String,
+        #[arg(
+            help = "The port the peer listens on for this service. If you pass 0, it will pick a random port."
+        )]
+        remote_port: u16,
+        #[arg(required_unless_present = "unix")]
+        port: Option<u16>,
+        #[arg(
+            long,
+            default_value = "127.0.0.1",
+            help = "Host serving the TCP service."
+        )]
+        host: String,
+        #[arg(
+            long,
+            value_name = "PATH",
+            conflicts_with_all = ["port", "host"],
+            help = "Unix socket the TCP service listens on, instead of host and port."
+        )]
+        unix: Option<std::path::PathBuf>,
+    },
+    #[clap(
+        about = "Listen on ports for peers running `malai tcp-reverse`, and forward the connections to them."
+    )]
+    TcpReverseRemote {
+        #[arg(
+            long,
+            default_value = "127.0.0.1",
+            help = "The IPv4 or IPv6 address to listen on, say 0.0.0.0 or :: to accept connections from other machines."
+        )]
+        bind: std::net::IpAddr,
+        #[arg(
+            long,
+            help = "Let any peer open a port. Use --allow instead to only let the given peers."
+        )]
+        public: bool,
+        #[command(flatten)]
+        acl: AclArgs,
+        #[command(flatten)]
+        access_log: AccessLogArgs,
+        #[command(flatten)]
+        metrics: MetricsArgs,
+    },
+    #[clap(
+        about = "Pipe stdin and stdout to a TCP service shared by the given id52, say as an ssh ProxyCommand."
+    )]
+    Connect {
+        #[arg(
+            help = "The id52 sharing the TCP service. Anything after the first dot is ignored, so ssh's %h works for hosts like <id52>.kulfi."
+        )]
+        proxy_target: String,
+        #[arg(
+            long,
+            help = "The service to connect to, when the peer shares several with `malai tcp --service`."
+        )]
+        service: Option<String>,
+        #[command(flatten)]
+        identity: IdentityArgs,
+    },
+    #[clap(about = "Ask peers for the time, and check our clock against what most of them say.")]
+    Time {
+        #[arg(required = true, help = "The id52s of the peers to ask.")]
+        peers: Vec<String>,
+        #[command(flatten)]
+        identity: IdentityArgs,
     },
     #[clap(about = "Expose a folder to kulfi network")]
     Folder {
@@ -247,6 +893,19 @@ pub enum Command {
         bridge: String,
         #[arg(long, help = "Make the folder public. Anyone will be able to access.")]
         public: bool,
+        #[command(flatten)]
+        acl: AclArgs,
+        #[command(flatten)]
+        access_log: AccessLogArgs,
+        #[arg(
+            long,
+            default_value_t = kulfi_utils::DEFAULT_MAX_CONCURRENT_REQUESTS,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+            help = "How many requests from a single peer are handled at the same time, the rest wait."
+        )]
+        max_concurrent_requests: usize,
+        #[command(flatten)]
+        metrics: MetricsArgs,
     },
     #[clap(about = "Run all the services")]
     Run {
@@ -257,6 +916,12 @@ pub enum Command {
     HttpProxyRemote {
         #[arg(long, help = "Make the proxy public. Anyone will be able to access.")]
         public: bool,
+        #[command(flatten)]
+        acl: AclArgs,
+        #[command(flatten)]
+        access_log: AccessLogArgs,
+        #[command(flatten)]
+        metrics: MetricsArgs,
     },
     #[clap(about = "Run a http proxy server that forwards incoming requests to http-proxy-remote.")]
     HttpProxy {
@@ -267,6 +932,54 @@ pub enum Command {
             default_value = "0"
         )]
         port: u16,
+        #[command(flatten)]
+        listen: ListenArgs,
+        #[command(flatten)]
+        identity: IdentityArgs,
+        #[command(flatten)]
+        metrics: MetricsArgs,
+    },
+    #[clap(about = "Run an iroh remote server that handles requests from malai socks5.")]
+    Socks5Remote {
+        #[arg(long, help = "Make the proxy public. Anyone will be able to access.")]
+        public: bool,
+        #[command(flatten)]
+        acl: AclArgs,
+        #[command(flatten)]
+        access_log: AccessLogArgs,
+        #[command(flatten)]
+        metrics: MetricsArgs,
+    },
+    #[clap(
+        about = "Run a SOCKS5 server that forwards connections and UDP datagrams to socks5-remote."
+    )]
+    Socks5 {
+        #[arg(help = "The id52 of remote to which this SOCKS5 proxy will forward requests to.")]
+        remote: String,
+        #[arg(
+            help = "The port on which this proxy will listen for incoming TCP requests. If you pass 0, it will bind to a random port.",
+            default_value = "0"
+        )]
+        port: u16,
+        #[command(flatten)]
+        listen: ListenArgs,
+        #[arg(
+            long,
+            requires = "password",
+            help = "Only serve apps that send this username, and the --password."
+        )]
+        username: Option<String>,
+        #[arg(
+            long,
+            env = "MALAI_SOCKS5_PASSWORD",
+            requires = "username",
+            help = "The password apps must send with the --username."
+        )]
+        password: Option<String>,
+        #[command(flatten)]
+        identity: IdentityArgs,
+        #[command(flatten)]
+        metrics: MetricsArgs,
     },
     #[clap(about = "Generate a new identity.")]
     Keygen {
@@ -280,3 +993,291 @@ pub enum Command {
         file: Option<String>,
     },
 }
+
+#[derive(clap::Args, Debug)]
+pub struct AclArgs {
+    #[arg(
+        long,
+        value_name = "ID52",
+        help = "Only allow this peer to access. Can be passed multiple times."
+    )]
+    allow: Vec<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Only allow the peers listed in this file, one id52 per line. The file is re-read when it changes."
+    )]
+    allow_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "ID52",
+        help = "Do not allow this peer to access. Can be passed multiple times."
+    )]
+    deny: Vec<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Do not allow the peers listed in this file, one id52 per line. The file is re-read when it changes."
+    )]
+    deny_file: Option<String>,
+}
+
+impl AclArgs {
+    async fn into_acl(self) -> malai::PeerAcl {
+        match malai::PeerAcl::new(self.allow, self.allow_file, self.deny, self.deny_file).await {
+            Ok(acl) => acl,
+            Err(e) => {
+                eprintln!("Failed to read access control list: {e:?}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AccessLogArgs {
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Record every request (or TCP connection) served to peers in this file."
+    )]
+    access_log: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        default_value = "json",
+        help = "json: one JSON object per line. combined: the Apache/nginx combined log format, with the peer id52 as the client address."
+    )]
+    access_log_format: kulfi_utils::AccessLogFormat,
+    #[arg(
+        long,
+        value_name = "MB",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Rotate the access log once it grows past this size."
+    )]
+    access_log_max_size: Option<u64>,
+    #[arg(
+        long,
+        default_value_t = 5,
+        help = "How many rotated access log files to keep."
+    )]
+    access_log_keep: usize,
+}
+
+impl AccessLogArgs {
+    fn into_access_log(self, protocol: kulfi_utils::Protocol) -> kulfi_utils::AccessLog {
+        let path = match self.access_log {
+            Some(v) => v,
+            None => return kulfi_utils::AccessLog::disabled(protocol),
+        };
+
+        match kulfi_utils::FileAccessLog::new(
+            &path,
+            self.access_log_format,
+            self.access_log_max_size.map(|mb| mb * 1024 * 1024),
+            self.access_log_keep,
+        ) {
+            Ok(v) => kulfi_utils::AccessLog::new(std::sync::Arc::new(v), protocol),
+            Err(e) => {
+                eprintln!("Failed to open access log {path:?}: {e:?}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct IdentityArgs {
+    #[arg(
+        long,
+        help = "Connect to peers as your identity, the one `malai http` uses (from $KULFI_SECRET_KEY, .malai.secret-key or the keyring), instead of a new random id52 every run. Lets the peers allow or trust this id52."
+    )]
+    identity: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "identity",
+        help = "Connect to peers as the identity in this file, as written by `malai keygen --file`."
+    )]
+    key_file: Option<std::path::PathBuf>,
+}
+
+impl IdentityArgs {
+    /// sets up the identity, has to be called before anything connects to a peer
+    async fn init(self) {
+        if let Some(id52) = self.init_quietly().await {
+            println!("Connecting to peers as {id52}");
+        }
+    }
+
+    /// same as `init()`, without printing the id52 of the identity, which is returned instead
+    async fn init_quietly(self) -> Option<String> {
+        let (id52, secret_key) = if let Some(path) = self.key_file {
+            match kulfi_utils::read_key_file(&path).await {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("Failed to read the key file: {e:?}");
+                    std::process::exit(1);
+                }
+            }
+        } else if self.identity {
+            match kulfi_utils::read_or_create_key().await {
+                Ok(v) => v,
+                Err(e) => {
+                    malai::identity_read_err_msg(e);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            return None;
+        };
+
+        if let Err(e) = kulfi_utils::set_global_identity(secret_key).await {
+            eprintln!("Failed to set up the identity: {e:?}");
+            std::process::exit(1);
+        }
+        Some(id52)
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct MetricsArgs {
+    #[arg(
+        long,
+        value_name = "ADDR",
+        help = "Serve prometheus metrics at http://<ADDR>/metrics, say 127.0.0.1:9090."
+    )]
+    metrics_addr: Option<std::net::SocketAddr>,
+}
+
+impl MetricsArgs {
+    fn serve(self, graceful: &kulfi_utils::Graceful) {
+        if let Some(addr) = self.metrics_addr {
+            graceful.spawn(malai::serve_metrics(addr, graceful.clone()));
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheArgs {
+    #[arg(
+        long,
+        help = "Cache the responses of peers that allow it, going by Cache-Control, Expires, ETag, Last-Modified and Vary. Send `PURGE <path>` with the --purge-token to drop a path, or everything under `<path>*`."
+    )]
+    cache: bool,
+    #[arg(
+        long,
+        value_name = "MB",
+        default_value = "64",
+        requires = "cache",
+        help = "How much of the cached responses to keep in memory."
+    )]
+    cache_memory: u64,
+    #[arg(
+        long,
+        value_name = "DIR",
+        requires = "cache",
+        help = "Keep the cached responses that do not fit in memory in this directory. It is emptied on start."
+    )]
+    cache_dir: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_name = "MB",
+        default_value = "1024",
+        requires = "cache_dir",
+        help = "How much of the cached responses to keep in --cache-dir."
+    )]
+    cache_disk: u64,
+    #[arg(
+        long,
+        value_name = "MB",
+        default_value = "8",
+        requires = "cache",
+        help = "Do not cache responses larger than this."
+    )]
+    cache_max_entry: u64,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "3600",
+        requires = "cache",
+        help = "Serve stale responses for this long when the peer is offline, unless the response says must-revalidate or has its own stale-if-error."
+    )]
+    cache_stale_if_error: u64,
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        env = "MALAI_PURGE_TOKEN",
+        requires = "cache",
+        help = "Allow PURGE requests that send `Authorization: Bearer <TOKEN>`. Without it PURGE is refused."
+    )]
+    purge_token: Option<String>,
+}
+
+impl CacheArgs {
+    async fn into_cache(self) -> Option<malai::BridgeCache> {
+        if !self.cache {
+            return None;
+        }
+
+        const MB: u64 = 1024 * 1024;
+        let config = malai::CacheConfig {
+            memory_size: self.cache_memory * MB,
+            dir: self.cache_dir,
+            disk_size: self.cache_disk * MB,
+            max_entry_size: self.cache_max_entry * MB,
+            stale_if_error: std::time::Duration::from_secs(self.cache_stale_if_error),
+            purge_token: self.purge_token,
+        };
+        match malai::BridgeCache::new(config).await {
+            Ok(v) => Some(v),
+            Err(e) => {
+                eprintln!("Failed to set up the cache: {e:?}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ListenArgs {
+    #[arg(
+        long,
+        default_value = "127.0.0.1",
+        help = "The IPv4 or IPv6 address to listen on, say 0.0.0.0 or :: to accept connections from other machines."
+    )]
+    bind: std::net::IpAddr,
+    #[arg(
+        long,
+        help = "Expect a PROXY protocol (v1 or v2) header on every connection, as sent by HAProxy or a cloud load balancer, and use the client address from it. Connections without one are rejected."
+    )]
+    proxy_protocol: bool,
+}
+
+impl ListenArgs {
+    fn into_listen(self, port: u16) -> malai::Listen {
+        malai::Listen {
+            addr: std::net::SocketAddr::new(self.bind, port),
+            proxy_protocol: self.proxy_protocol,
+        }
+    }
+}
+
+/// the service is either at `host:port` or, with `--unix`, at a unix socket. also returns how the
+/// service was passed on the command line, for the messages we print.
+fn upstream_addr(
+    host: String,
+    port: Option<u16>,
+    unix: Option<std::path::PathBuf>,
+) -> (kulfi_utils::UpstreamAddr, String) {
+    match (unix, port) {
+        (Some(path), _) => {
+            let service = format!("--unix {}", path.display());
+            (kulfi_utils::UpstreamAddr::Unix(path), service)
+        }
+        (None, Some(port)) => (
+            kulfi_utils::UpstreamAddr::Tcp(format!("{host}:{port}")),
+            port.to_string(),
+        ),
+        (None, None) => unreachable!("clap requires either the port or --unix"),
+    }
+}
@@ -0,0 +1,59 @@
+/// serves `GET /metrics` in the prometheus text format, see `kulfi_utils::metrics` for what we
+/// record. the address is usually kept private, say `127.0.0.1:9090`, the numbers include the
+/// id52s of the peers.
+pub async fn serve_metrics(addr: std::net::SocketAddr, graceful: kulfi_utils::Graceful) {
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Failed to bind metrics server to {addr}: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    println!("Serving metrics on http://{addr}/metrics");
+
+    loop {
+        tokio::select! {
+            _ = graceful.cancelled() => break,
+            r = listener.accept() => {
+                let stream = match r {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::error!("failed to accept metrics connection: {e:?}");
+                        continue;
+                    }
+                };
+                graceful.spawn(async move {
+                    let service = hyper::service::service_fn(|r| async move { handle_request(r) });
+                    if let Err(e) = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                        .await
+                    {
+                        tracing::info!("metrics connection error: {e:?}");
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn handle_request(
+    r: hyper::Request<hyper::body::Incoming>,
+) -> kulfi_utils::http::ProxyResult<std::convert::Infallible> {
+    if r.uri().path() != "/metrics" {
+        return Ok(kulfi_utils::http::bytes_to_resp(
+            b"not found, try /metrics\n".to_vec(),
+            hyper::StatusCode::NOT_FOUND,
+        ));
+    }
+
+    let mut resp = kulfi_utils::http::bytes_to_resp(
+        kulfi_utils::metrics::render().into_bytes(),
+        hyper::StatusCode::OK,
+    );
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+    );
+    Ok(resp)
+}
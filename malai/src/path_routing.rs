@@ -0,0 +1,186 @@
+//! with `malai http-bridge --path-routing`, the bridge also serves peers at `/p/<id52>/...`, so it
+//! can run on a single hostname, without wildcard DNS or certificates.
+//!
+//! the `/p/<id52>` prefix is removed before the request is forwarded, and sent to the service in
+//! `X-Forwarded-Prefix`, which many frameworks use to generate links. for the ones that do not, the
+//! `Location` and `Content-Location` headers, and the cookie paths, of the response are rewritten
+//! to include the prefix. links in the body are not, so services that link to absolute paths
+//! work best with a domain of their own.
+//!
+//! **all the peers served this way share one origin.** the browser sees `bridge.example/p/<a>/`
+//! and `bridge.example/p/<b>/` as the same site, so a script from one share can read the cookies,
+//! local storage and pages of every other share, say by loading `/p/<b>/` in an iframe. rewriting
+//! the cookie `Path` only keeps cookies apart for well behaved services, it is not a security
+//! boundary. so the bridge refuses `--path-routing` unless `--allow`, `--allow-file` or
+//! `--proxy-target` limits it to peers that trust each other; shares that do not should get a
+//! hostname of their own.
+
+/// the prefix a request came in with, for services that want to generate links with it
+pub const FORWARDED_PREFIX_HEADER: &str = "x-forwarded-prefix";
+
+/// the route for a request path
+#[derive(Debug, PartialEq, Eq)]
+pub enum Route {
+    /// the id52, the prefix (`/p/<id52>`) and the path and query to forward
+    Peer {
+        id52: String,
+        prefix: String,
+        path_and_query: String,
+    },
+    /// `/p/<id52>` without the trailing slash, the browser has to come back with one, or relative
+    /// links would resolve outside the prefix
+    AddSlash(String),
+}
+
+/// the route for `/p/<id52>/...` paths, `None` for the rest
+pub fn route(path_and_query: &str) -> Option<Route> {
+    let rest = path_and_query.strip_prefix("/p/")?;
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (id52, rest) = rest.split_at(end);
+    if id52.is_empty() {
+        return None;
+    }
+
+    let prefix = format!("/p/{id52}");
+    if !rest.starts_with('/') {
+        return Some(Route::AddSlash(format!("{prefix}/{rest}")));
+    }
+
+    Some(Route::Peer {
+        id52: id52.to_string(),
+        prefix,
+        path_and_query: rest.to_string(),
+    })
+}
+
+/// `uri` with its path and query replaced
+pub fn set_path_and_query(uri: &hyper::Uri, path_and_query: &str) -> eyre::Result<hyper::Uri> {
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse()?);
+    Ok(hyper::Uri::from_parts(parts)?)
+}
+
+/// adds the prefix back to the paths in the response headers. `host` is the host the client used,
+/// urls for other hosts are left alone.
+pub fn rewrite_response(headers: &mut hyper::HeaderMap, prefix: &str, host: Option<&str>) {
+    for name in [hyper::header::LOCATION, hyper::header::CONTENT_LOCATION] {
+        let Some(value) = headers.get(&name).and_then(|v| v.to_str().ok()) else {
+            continue;
+        };
+        if let Some(v) = rewrite_location(value, prefix, host)
+            && let Ok(v) = hyper::header::HeaderValue::from_str(&v)
+        {
+            headers.insert(name, v);
+        }
+    }
+
+    let cookies: Vec<_> = headers
+        .get_all(hyper::header::SET_COOKIE)
+        .iter()
+        .map(|v| match v.to_str() {
+            Ok(cookie) => {
+                hyper::header::HeaderValue::from_str(&rewrite_cookie_path(cookie, prefix))
+                    .unwrap_or_else(|_| v.clone())
+            }
+            Err(_) => v.clone(),
+        })
+        .collect();
+    if !cookies.is_empty() {
+        headers.remove(hyper::header::SET_COOKIE);
+        for cookie in cookies {
+            headers.append(hyper::header::SET_COOKIE, cookie);
+        }
+    }
+}
+
+fn rewrite_location(location: &str, prefix: &str, host: Option<&str>) -> Option<String> {
+    // `//host/path` is a url without the scheme
+    if location.starts_with('/') && !location.starts_with("//") {
+        return Some(format!("{prefix}{location}"));
+    }
+
+    let uri: hyper::Uri = location.parse().ok()?;
+    let authority = uri.authority()?;
+    if host.is_none_or(|h| !h.eq_ignore_ascii_case(authority.as_str())) {
+        return None;
+    }
+
+    let path_and_query = uri.path_and_query().map(|v| v.as_str()).unwrap_or("/");
+    let scheme = uri
+        .scheme_str()
+        .map(|s| format!("{s}:"))
+        .unwrap_or_default();
+    Some(format!("{scheme}//{authority}{prefix}{path_and_query}"))
+}
+
+/// `a=b; Path=/x` becomes `a=b; Path=<prefix>/x`
+fn rewrite_cookie_path(cookie: &str, prefix: &str) -> String {
+    cookie
+        .split(';')
+        .map(|part| {
+            let trimmed = part.trim_start();
+            match trimmed.split_once('=') {
+                Some((name, path))
+                    if name.trim().eq_ignore_ascii_case("path") && path.starts_with('/') =>
+                {
+                    let indent = &part[..part.len() - trimmed.len()];
+                    format!("{indent}{name}={prefix}{path}")
+                }
+                _ => part.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+#[cfg(test)]
+mod test {
+    const ID52: &str = "i66fo538lfl5ombdf6tcdbrabp4hmp9asv7nrffuc2im13ct4q60";
+
+    #[test]
+    fn test_route() {
+        assert_eq!(
+            super::route(&format!("/p/{ID52}/a/b?c=1")),
+            Some(super::Route::Peer {
+                id52: ID52.to_string(),
+                prefix: format!("/p/{ID52}"),
+                path_and_query: "/a/b?c=1".to_string(),
+            })
+        );
+        assert_eq!(
+            super::route(&format!("/p/{ID52}?c=1")),
+            Some(super::Route::AddSlash(format!("/p/{ID52}/?c=1")))
+        );
+        assert_eq!(super::route("/p/"), None);
+        assert_eq!(super::route("/a/b"), None);
+    }
+
+    #[test]
+    fn test_rewrite_response() {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("location", "/login?next=/".parse().unwrap());
+        headers.insert(
+            "content-location",
+            "https://bridge.example/x".parse().unwrap(),
+        );
+        headers.append("set-cookie", "a=1; Path=/; HttpOnly".parse().unwrap());
+        headers.append("set-cookie", "b=2; path=/app".parse().unwrap());
+        headers.append("set-cookie", "c=3".parse().unwrap());
+
+        super::rewrite_response(&mut headers, "/p/x", Some("bridge.example"));
+
+        assert_eq!(headers["location"], "/p/x/login?next=/");
+        assert_eq!(headers["content-location"], "https://bridge.example/p/x/x");
+        let cookies: Vec<_> = headers.get_all("set-cookie").iter().collect();
+        assert_eq!(
+            cookies,
+            ["a=1; Path=/p/x/; HttpOnly", "b=2; path=/p/x/app", "c=3"]
+        );
+
+        // other hosts are left alone
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("location", "https://example.com/".parse().unwrap());
+        super::rewrite_response(&mut headers, "/p/x", Some("bridge.example"));
+        assert_eq!(headers["location"], "https://example.com/");
+    }
+}
@@ -0,0 +1,159 @@
+//! the HAProxy PROXY protocol, v1 (text) and v2 (binary). load balancers send this header at the
+//! start of every connection they forward, so we know the address of the real client.
+//!
+//! spec: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// the longest v1 header, including the trailing `\r\n`
+const V1_MAX_LEN: usize = 107;
+
+/// reads the PROXY protocol header from the start of the connection, and returns the address of
+/// the client. `None` means the load balancer did not pass one, say for its own health checks.
+///
+/// we only read the header, the rest of the stream is left as is.
+pub async fn read_header<R>(stream: &mut R) -> eyre::Result<Option<std::net::SocketAddr>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    // the shortest header, `PROXY UNKNOWN\r\n`, is longer than the v2 signature
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await?;
+        let mut body = vec![0u8; u16::from_be_bytes([head[2], head[3]]) as usize];
+        stream.read_exact(&mut body).await?;
+        return parse_v2(head[0], head[1], &body);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(eyre::anyhow!(
+            "connection did not start with a PROXY protocol header"
+        ));
+    }
+
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(eyre::anyhow!("PROXY protocol header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    parse_v1(&line[..line.len() - 2])
+}
+
+/// `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443`, without the `\r\n`
+fn parse_v1(line: &[u8]) -> eyre::Result<Option<std::net::SocketAddr>> {
+    let line = std::str::from_utf8(line)?;
+
+    match line.split(' ').collect::<Vec<_>>().as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: std::net::IpAddr = src
+                .parse()
+                .map_err(|e| eyre::anyhow!("invalid address {src:?}: {e}"))?;
+            let port: u16 = src_port
+                .parse()
+                .map_err(|e| eyre::anyhow!("invalid port {src_port:?}: {e}"))?;
+            Ok(Some(std::net::SocketAddr::new(ip, port)))
+        }
+        _ => Err(eyre::anyhow!("invalid PROXY protocol header: {line:?}")),
+    }
+}
+
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    body: &[u8],
+) -> eyre::Result<Option<std::net::SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(eyre::anyhow!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        ));
+    }
+
+    match version_command & 0x0f {
+        // LOCAL, the connection was made by the load balancer itself
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        c => return Err(eyre::anyhow!("unknown PROXY protocol command {c}")),
+    }
+
+    // the addresses are followed by optional TLVs, which we ignore
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    Ok(match family >> 4 {
+        // AF_INET: source address, destination address, source port, destination port
+        1 if body.len() >= 12 => {
+            let ip: [u8; 4] = body[..4].try_into()?;
+            Some(std::net::SocketAddr::new(ip.into(), port(8)))
+        }
+        // AF_INET6
+        2 if body.len() >= 36 => {
+            let ip: [u8; 16] = body[..16].try_into()?;
+            Some(std::net::SocketAddr::new(ip.into(), port(32)))
+        }
+        1 | 2 => return Err(eyre::anyhow!("PROXY protocol header is too short")),
+        // AF_UNSPEC or AF_UNIX, there is no address we can use
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    async fn read(mut input: &[u8]) -> eyre::Result<Option<std::net::SocketAddr>> {
+        let r = super::read_header(&mut input).await;
+        // whatever follows the header is left for the caller
+        assert!(r.is_err() || input == b"GET /");
+        r
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET /")
+                .await
+                .unwrap(),
+            Some("192.168.0.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\nGET /")
+                .await
+                .unwrap(),
+            Some("[2001:db8::1]:4000".parse().unwrap())
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\nGET /").await.unwrap(), None);
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        assert!(
+            read(b"PROXY TCP4 nope 192.168.0.11 1 2\r\nGET /")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut header = super::V2_SIGNATURE.to_vec();
+        // PROXY command, AF_INET over STREAM, 12 bytes of addresses
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        header.extend_from_slice(&4000u16.to_be_bytes());
+        header.extend_from_slice(&443u16.to_be_bytes());
+        header.extend_from_slice(b"GET /");
+        assert_eq!(
+            read(&header).await.unwrap(),
+            Some("10.0.0.1:4000".parse().unwrap())
+        );
+
+        // LOCAL command, used for health checks
+        let mut header = super::V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        header.extend_from_slice(b"GET /");
+        assert_eq!(read(&header).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,294 @@
+//! the parts of SOCKS5 `malai socks5` speaks to apps: the method negotiation, the username and
+//! password authentication, and the CONNECT and UDP ASSOCIATE commands.
+//!
+//! spec: https://www.rfc-editor.org/rfc/rfc1928 and https://www.rfc-editor.org/rfc/rfc1929
+
+const VERSION: u8 = 0x05;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+/// the version of the username and password sub-negotiation, not of SOCKS
+const AUTH_VERSION: u8 = 0x01;
+
+const CONNECT: u8 = 0x01;
+const UDP_ASSOCIATE: u8 = 0x03;
+
+const IPV4: u8 = 0x01;
+const DOMAIN_NAME: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+/// the REP field of our reply to a request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+/// the username and password apps must send, when `malai socks5` is not only used by us
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    /// a TCP connection to `host:port`
+    Connect(String),
+    /// the app wants to send UDP datagrams through us, its TCP connection stays open for as long
+    /// as it does
+    UdpAssociate,
+}
+
+/// reads what an app sends before it can use the connection, till the request. when the app
+/// can not go on, say it does not send the credentials we want, it is told so here, the caller
+/// only has to `reply()` to the request we return.
+pub async fn accept<S>(stream: &mut S, credentials: Option<&Credentials>) -> eyre::Result<Request>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // VER, NMETHODS, METHODS
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        return Err(eyre::anyhow!("not a SOCKS5 client, version {}", head[0]));
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = match credentials {
+        Some(_) => USERNAME_PASSWORD,
+        None => NO_AUTHENTICATION,
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(eyre::anyhow!(
+            "client does not support authentication method {method}, it offered {methods:?}"
+        ));
+    }
+    stream.write_all(&[VERSION, method]).await?;
+
+    if let Some(credentials) = credentials {
+        // VER, ULEN, UNAME, PLEN, PASSWD
+        stream.read_exact(&mut head).await?;
+        if head[0] != AUTH_VERSION {
+            return Err(eyre::anyhow!("unknown authentication version {}", head[0]));
+        }
+        let mut username = vec![0u8; head[1] as usize];
+        stream.read_exact(&mut username).await?;
+        let mut password = vec![0u8; stream.read_u8().await? as usize];
+        stream.read_exact(&mut password).await?;
+
+        let ok = username == credentials.username.as_bytes()
+            && password == credentials.password.as_bytes();
+        stream
+            .write_all(&[AUTH_VERSION, if ok { 0x00 } else { 0x01 }])
+            .await?;
+        if !ok {
+            return Err(eyre::anyhow!(
+                "wrong username or password, for user {:?}",
+                String::from_utf8_lossy(&username)
+            ));
+        }
+    }
+
+    // VER, CMD, RSV, ATYP, DST.ADDR, DST.PORT
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        return Err(eyre::anyhow!("not a SOCKS5 request, version {}", head[0]));
+    }
+
+    let mut addr = vec![head[3]];
+    let len = match head[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => {
+            let len = stream.read_u8().await?;
+            addr.push(len);
+            len as usize
+        }
+        atyp => {
+            reply(stream, Reply::AddressTypeNotSupported).await?;
+            return Err(eyre::anyhow!("unknown address type {atyp}"));
+        }
+    };
+    let start = addr.len();
+    addr.resize(start + len + 2, 0);
+    stream.read_exact(&mut addr[start..]).await?;
+
+    let addr = match split_addr(&addr) {
+        Some((addr, _)) => addr,
+        None => {
+            reply(stream, Reply::GeneralFailure).await?;
+            return Err(eyre::anyhow!("domain name is not valid utf-8"));
+        }
+    };
+
+    match head[1] {
+        CONNECT => Ok(Request::Connect(addr)),
+        UDP_ASSOCIATE => Ok(Request::UdpAssociate),
+        cmd => {
+            reply(stream, Reply::CommandNotSupported).await?;
+            Err(eyre::anyhow!("unsupported command {cmd}"))
+        }
+    }
+}
+
+/// replies to the request, without the address we are bound to, which apps ignore for CONNECT
+pub async fn reply<S>(stream: &mut S, reply: Reply) -> eyre::Result<()>
+where
+    S: tokio::io::AsyncWrite + Unpin,
+{
+    reply_bound(stream, reply, (std::net::Ipv4Addr::UNSPECIFIED, 0).into()).await
+}
+
+/// replies to the request, for UDP ASSOCIATE `bound` is where the app sends its datagrams
+pub async fn reply_bound<S>(
+    stream: &mut S,
+    reply: Reply,
+    bound: std::net::SocketAddr,
+) -> eyre::Result<()>
+where
+    S: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+
+    let mut buf = vec![VERSION, reply as u8, 0x00];
+    buf.extend(encode_addr(bound));
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// the ATYP, ADDR and PORT fields for `addr`
+pub fn encode_addr(addr: std::net::SocketAddr) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(19);
+    match addr.ip() {
+        std::net::IpAddr::V4(ip) => {
+            buf.push(IPV4);
+            buf.extend(ip.octets());
+        }
+        std::net::IpAddr::V6(ip) => {
+            buf.push(IPV6);
+            buf.extend(ip.octets());
+        }
+    }
+    buf.extend(addr.port().to_be_bytes());
+    buf
+}
+
+/// splits the ATYP, ADDR and PORT fields off the front of `buf`, and returns the address as
+/// `host:port`, with what comes after it. `None` if `buf` is too short, or is not an address.
+pub fn split_addr(buf: &[u8]) -> Option<(String, &[u8])> {
+    let (host, rest) = match *buf.first()? {
+        IPV4 => {
+            let ip: [u8; 4] = buf.get(1..5)?.try_into().ok()?;
+            (std::net::Ipv4Addr::from(ip).to_string(), &buf[5..])
+        }
+        IPV6 => {
+            let ip: [u8; 16] = buf.get(1..17)?.try_into().ok()?;
+            (format!("[{}]", std::net::Ipv6Addr::from(ip)), &buf[17..])
+        }
+        DOMAIN_NAME => {
+            let len = *buf.get(1)? as usize;
+            let name = std::str::from_utf8(buf.get(2..2 + len)?).ok()?;
+            (name.to_string(), &buf[2 + len..])
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+    Some((format!("{host}:{port}"), &rest[2..]))
+}
+
+#[cfg(test)]
+mod test {
+    /// runs `accept()` on what the client sends, and returns the result and our replies
+    async fn accept(
+        client: &[u8],
+        credentials: Option<&super::Credentials>,
+    ) -> (eyre::Result<super::Request>, Vec<u8>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut ours, mut theirs) = tokio::io::duplex(1024);
+        theirs.write_all(client).await.unwrap();
+        let r = super::accept(&mut ours, credentials).await;
+        drop(ours);
+
+        let mut replies = Vec::new();
+        theirs.read_to_end(&mut replies).await.unwrap();
+        (r, replies)
+    }
+
+    #[tokio::test]
+    async fn test_accept() {
+        // no authentication, CONNECT to a domain name
+        let (r, replies) =
+            accept(b"\x05\x01\x00\x05\x01\x00\x03\x0bexample.com\x01\xbb", None).await;
+        assert_eq!(
+            r.unwrap(),
+            super::Request::Connect("example.com:443".to_string())
+        );
+        assert_eq!(replies, b"\x05\x00");
+
+        // UDP ASSOCIATE, with the address the app will send from
+        let (r, _) = accept(b"\x05\x01\x00\x05\x03\x00\x01\0\0\0\0\0\0", None).await;
+        assert_eq!(r.unwrap(), super::Request::UdpAssociate);
+
+        // BIND is not supported
+        let (r, replies) = accept(b"\x05\x01\x00\x05\x02\x00\x01\x7f\0\0\x01\0\x50", None).await;
+        assert!(r.is_err());
+        assert_eq!(replies, b"\x05\x00\x05\x07\x00\x01\0\0\0\0\0\0");
+
+        let credentials = super::Credentials {
+            username: "amitu".to_string(),
+            password: "secret".to_string(),
+        };
+
+        // we want a password, the app does not offer one
+        let (r, replies) = accept(b"\x05\x01\x00", Some(&credentials)).await;
+        assert!(r.is_err());
+        assert_eq!(replies, b"\x05\xff");
+
+        let (r, replies) = accept(
+            b"\x05\x02\x00\x02\x01\x05amitu\x06secret\x05\x01\x00\x01\x7f\0\0\x01\0\x50",
+            Some(&credentials),
+        )
+        .await;
+        assert_eq!(
+            r.unwrap(),
+            super::Request::Connect("127.0.0.1:80".to_string())
+        );
+        assert_eq!(replies, b"\x05\x02\x01\x00");
+
+        let (r, replies) = accept(b"\x05\x01\x02\x01\x05amitu\x05wrong", Some(&credentials)).await;
+        assert!(r.is_err());
+        assert_eq!(replies, b"\x05\x02\x01\x01");
+    }
+
+    #[test]
+    fn test_addr() {
+        for addr in ["1.2.3.4:5", "[::1]:53"] {
+            let mut buf = super::encode_addr(addr.parse().unwrap());
+            buf.extend(b"data");
+            assert_eq!(
+                super::split_addr(&buf),
+                Some((addr.to_string(), &b"data"[..]))
+            );
+        }
+
+        assert_eq!(
+            super::split_addr(b"\x03\x09localhost\x00\x35"),
+            Some(("localhost:53".to_string(), &b""[..]))
+        );
+        assert_eq!(super::split_addr(b"\x03\x09localhost\x00"), None);
+        assert_eq!(super::split_addr(b"\x01\x7f\0\0"), None);
+        assert_eq!(super::split_addr(b"\x02"), None);
+        assert_eq!(super::split_addr(b""), None);
+    }
+}
@@ -0,0 +1,253 @@
+/// `malai socks5` is a SOCKS5 server for apps that speak SOCKS but not HTTP CONNECT. it does not
+/// connect anywhere itself, every request goes to the peer running `malai socks5-remote`, so the
+/// peer is the exit node for the apps pointed at us, and only for them.
+///
+/// CONNECT and UDP ASSOCIATE are supported, BIND is not. with `credentials` apps have to send the
+/// username and password, say when we listen on an address other machines can reach.
+pub async fn socks5(
+    listen: malai::Listen,
+    remote: String,
+    credentials: Option<malai::socks::Credentials>,
+    graceful: kulfi_utils::Graceful,
+) {
+    let listener = match listen.bind().await {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to bind to {}: {e:?}", listen.addr);
+            std::process::exit(1);
+        }
+    };
+
+    let addr = match listener.local_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Failed to get local address: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    println!("Listening on socks5://{addr}");
+
+    let peer_connections = kulfi_utils::PeerStreamSenders::default();
+
+    let mut graceful_mut = graceful.clone();
+    loop {
+        tokio::select! {
+            () = graceful.cancelled() => {
+                tracing::info!("Stopping control server.");
+                break;
+            }
+            r = graceful_mut.show_info() => {
+                match r {
+                    Ok(_) => {
+                        println!("Listening on socks5://{addr}");
+                        println!("Press ctrl+c again to exit.");
+                    }
+                    Err(e) => {
+                        tracing::error!("failed to show info: {e:?}");
+                    }
+                }
+            }
+            r = listener.accept() => {
+                match r {
+                    Ok((mut stream, peer)) => {
+                        let graceful_for_handle_connection = graceful.clone();
+                        let peer_connections = peer_connections.clone();
+                        let remote = remote.clone();
+                        let listen = listen.clone();
+                        let credentials = credentials.clone();
+                        graceful.spawn(async move {
+                            let client_addr = match listen.client_addr(&mut stream, peer).await {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    tracing::info!(%peer, "rejecting connection: {e:?}");
+                                    return;
+                                }
+                            };
+                            tracing::info!(%client_addr, "got connection");
+                            if let Err(e) = handle_connection(
+                                stream,
+                                client_addr,
+                                credentials,
+                                graceful_for_handle_connection,
+                                peer_connections,
+                                remote,
+                            )
+                            .await
+                            {
+                                tracing::error!(%client_addr, "socks5 error: {e:?}");
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        tracing::error!("failed to accept: {e:?}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// sent by `malai socks5` after the `Protocol::Socks5` header, what the app asked for
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub enum Socks5Data {
+    Connect {
+        addr: String,
+    },
+    /// the stream carries the datagrams of the app, framed as in `kulfi_utils::udp`. every
+    /// datagram starts with the SOCKS5 address it is for, or on the way back, the address it came
+    /// from.
+    UdpAssociate,
+}
+
+/// sent back by `malai socks5-remote` for `Socks5Data::Connect`, once it has tried to connect.
+/// after `Connected` the stream carries the connection.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ConnectResult {
+    Connected,
+    /// the name did not resolve, or the host or its network could not be reached in time
+    HostUnreachable,
+    ConnectionRefused,
+    Failed,
+}
+
+async fn handle_connection(
+    mut stream: tokio::net::TcpStream,
+    client_addr: std::net::SocketAddr,
+    credentials: Option<malai::socks::Credentials>,
+    graceful: kulfi_utils::Graceful,
+    peer_connections: kulfi_utils::PeerStreamSenders,
+    remote: String,
+) -> eyre::Result<()> {
+    use malai::socks::Reply;
+
+    let request = malai::socks::accept(&mut stream, credentials.as_ref()).await?;
+    tracing::info!(%client_addr, "socks5 request: {request:?}");
+
+    let extra = match &request {
+        malai::socks::Request::Connect(addr) => Socks5Data::Connect { addr: addr.clone() },
+        malai::socks::Request::UdpAssociate => Socks5Data::UdpAssociate,
+    };
+    let (send, recv) = match kulfi_utils::get_stream(
+        kulfi_utils::global_iroh_endpoint().await,
+        kulfi_utils::ProtocolHeader {
+            protocol: kulfi_utils::Protocol::Socks5,
+            extra: Some(serde_json::to_string(&extra)?),
+        },
+        remote,
+        peer_connections,
+        graceful.clone(),
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            malai::socks::reply(&mut stream, Reply::GeneralFailure).await?;
+            return Err(e);
+        }
+    };
+
+    match request {
+        // the app is told how the peer's attempt to connect went, so it can tell a host that is
+        // down from one that refused the connection
+        malai::socks::Request::Connect(addr) => {
+            let mut recv = recv;
+            let reply = match kulfi_utils::next_json::<ConnectResult>(&mut recv).await {
+                Ok(ConnectResult::Connected) => Reply::Succeeded,
+                Ok(ConnectResult::HostUnreachable) => Reply::HostUnreachable,
+                Ok(ConnectResult::ConnectionRefused) => Reply::ConnectionRefused,
+                Ok(ConnectResult::Failed) => Reply::GeneralFailure,
+                Err(e) => {
+                    tracing::info!(%client_addr, "no connect result from the peer: {e:?}");
+                    Reply::GeneralFailure
+                }
+            };
+            malai::socks::reply(&mut stream, reply).await?;
+            if reply != Reply::Succeeded {
+                tracing::info!(%client_addr, "the peer could not connect to {addr}: {reply:?}");
+                return Ok(());
+            }
+            let stream = kulfi_utils::metrics::ClientConnection::new(stream);
+            let (tcp_recv, tcp_send) = tokio::io::split(stream);
+            kulfi_utils::pipe_tcp_stream_over_iroh(tcp_recv, tcp_send, send, recv).await?;
+        }
+        malai::socks::Request::UdpAssociate => {
+            // the app sends its datagrams to the address we listen on, on a port of their own
+            let relay = match tokio::net::UdpSocket::bind((stream.local_addr()?.ip(), 0)).await {
+                Ok(v) => v,
+                Err(e) => {
+                    malai::socks::reply(&mut stream, Reply::GeneralFailure).await?;
+                    return Err(e.into());
+                }
+            };
+            malai::socks::reply_bound(&mut stream, Reply::Succeeded, relay.local_addr()?).await?;
+            udp_associate(stream, relay, send, recv, graceful).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// relays the datagrams of the app till it closes its TCP connection. only the datagrams from the
+/// machine that made the connection are relayed, and the replies go to where the last of them
+/// came from.
+async fn udp_associate(
+    mut stream: tokio::net::TcpStream,
+    relay: tokio::net::UdpSocket,
+    mut send: iroh::endpoint::SendStream,
+    mut recv: iroh::endpoint::RecvStream,
+    graceful: kulfi_utils::Graceful,
+) -> eyre::Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    let client_ip = stream.peer_addr()?.ip();
+    let relay = std::sync::Arc::new(relay);
+    let (client_tx, client_rx) = tokio::sync::watch::channel(None::<std::net::SocketAddr>);
+
+    let mut replies = {
+        let relay = relay.clone();
+        tokio::spawn(async move {
+            let mut buf = Vec::new();
+            while let Some(frame) = kulfi_utils::udp::read_datagram(&mut recv, &mut buf).await? {
+                let client = *client_rx.borrow();
+                if let Some(client) = client {
+                    // RSV, FRAG, and the address and data from the peer
+                    let datagram = [&[0, 0, 0], frame].concat();
+                    relay.send_to(&datagram, client).await?;
+                }
+            }
+            Ok::<_, eyre::Report>(())
+        })
+    };
+
+    let mut buf = vec![0; kulfi_utils::udp::MAX_DATAGRAM_SIZE];
+    let mut closed = [0u8; 1];
+    let r = loop {
+        tokio::select! {
+            _ = graceful.cancelled() => break Ok(()),
+            _ = stream.read(&mut closed) => break Ok(()),
+            r = &mut replies => break r.map_err(Into::into).and_then(|r| r),
+            r = relay.recv_from(&mut buf) => {
+                let (n, from) = r?;
+                if from.ip() != client_ip {
+                    tracing::debug!(%from, "dropping datagram from another machine");
+                    continue;
+                }
+                // RSV, FRAG, ADDR, DATA. like most servers we do not put fragments together
+                let datagram = &buf[..n];
+                if n < 3 || datagram[2] != 0 {
+                    tracing::debug!(%from, "dropping fragmented datagram");
+                    continue;
+                }
+                client_tx.send_replace(Some(from));
+                if let Err(e) = kulfi_utils::udp::write_datagram(&mut send, &datagram[3..]).await {
+                    break Err(e);
+                }
+            }
+        }
+    };
+
+    replies.abort();
+    let _ = send.finish();
+    r
+}
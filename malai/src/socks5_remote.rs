@@ -0,0 +1,295 @@
+/// how long a name resolved for UDP ASSOCIATE is used before we resolve it again
+const RESOLVED_TTL: std::time::Duration = std::time::Duration::from_secs(60);
+/// how many resolved names a UDP ASSOCIATE keeps, names past this are resolved every time
+const MAX_RESOLVED: usize = 256;
+
+/// `malai socks5-remote` is the exit node for `malai socks5`, it makes the connections and sends
+/// the datagrams the apps on the peer's machine asked for.
+pub async fn socks5_remote(
+    acl: malai::PeerAcl,
+    access_log: kulfi_utils::AccessLog,
+    graceful: kulfi_utils::Graceful,
+) {
+    let (id52, secret_key) = match kulfi_utils::read_or_create_key().await {
+        Ok(v) => v,
+        Err(e) => {
+            malai::identity_read_err_msg(e);
+            std::process::exit(1);
+        }
+    };
+    let ep = match kulfi_utils::get_endpoint(secret_key).await {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Failed to bind to iroh network: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    InfoMode::Startup.print(&id52);
+
+    let mut graceful_mut = graceful.clone();
+
+    loop {
+        tokio::select! {
+            _ = graceful_mut.show_info() => {
+                InfoMode::OnExit.print(&id52);
+            }
+            _ = graceful.cancelled() => {
+                tracing::info!("Stopping socks5 server.");
+                break;
+            }
+            conn = ep.accept() => {
+                let conn = match conn {
+                    Some(conn) => conn,
+                    None => {
+                        tracing::info!("no connection");
+                        break;
+                    }
+                };
+
+                let graceful_for_handle_connection = graceful.clone();
+                let acl = acl.clone();
+                let self_id = ep.id();
+                let access_log = access_log.clone();
+                graceful.spawn(async move {
+                    let start = std::time::Instant::now();
+                    let conn = match conn.await {
+                        Ok(c) => c,
+                        Err(e) => {
+                            tracing::error!("failed to convert incoming to connection: {e:?}");
+                            return;
+                        }
+                    };
+                    if let Err(e) = handle_connection(conn, self_id, acl, access_log, graceful_for_handle_connection).await {
+                        tracing::error!("connection error: {e:?}");
+                    }
+                    tracing::info!("connection handled in {:?}", start.elapsed());
+                });
+            }
+        }
+    }
+
+    ep.close().await;
+}
+
+async fn handle_connection(
+    conn: iroh::endpoint::Connection,
+    self_id: iroh::EndpointId,
+    acl: malai::PeerAcl,
+    access_log: kulfi_utils::AccessLog,
+    graceful: kulfi_utils::Graceful,
+) -> eyre::Result<()> {
+    acl.guard(&conn).await?;
+    let remote_id52 = kulfi_utils::get_remote_id52(&conn);
+    let _active = kulfi_utils::metrics::ActivePeer::accepted(&remote_id52);
+
+    tracing::info!("new client: {remote_id52}, waiting for bidirectional stream");
+    loop {
+        let (extra, send, recv): (malai::Socks5Data, _, _) =
+            kulfi_utils::accept_bi_with(&conn, self_id, kulfi_utils::Protocol::Socks5)
+                .await
+                .inspect_err(|e| tracing::error!("failed to accept bidirectional stream: {e:?}"))?;
+        tracing::info!("got connection from {remote_id52}, extra: {extra:?}");
+
+        let remote_id52 = remote_id52.clone();
+        let access_log = access_log.clone();
+        graceful.spawn(async move {
+            if let Err(e) = match extra {
+                malai::Socks5Data::Connect { addr } => {
+                    connect(&addr, &remote_id52, &access_log, send, recv).await
+                }
+                malai::Socks5Data::UdpAssociate => {
+                    let mut entry = access_log.entry(&remote_id52, "*");
+                    entry.method = Some("UDP ASSOCIATE".to_string());
+                    let r = udp_associate(send, recv).await;
+                    if let Ok((bytes_in, bytes_out)) = r {
+                        entry.bytes_in = bytes_in;
+                        entry.bytes_out = bytes_out;
+                    }
+                    access_log.log(entry);
+                    r.map(|_| ())
+                }
+            } {
+                tracing::error!("failed to proxy: {e:?}");
+            }
+        });
+    }
+}
+
+/// connects to `addr` for the peer, tells it how that went, and pipes the stream to the connection
+async fn connect(
+    addr: &str,
+    remote_id52: &str,
+    access_log: &kulfi_utils::AccessLog,
+    mut send: iroh::endpoint::SendStream,
+    recv: iroh::endpoint::RecvStream,
+) -> eyre::Result<()> {
+    let stream = malai::proxy_dial(addr).await;
+    let result = match &stream {
+        Ok(_) => malai::ConnectResult::Connected,
+        Err((_, result)) => *result,
+    };
+    send.write_all(format!("{}\n", serde_json::to_string(&result)?).as_bytes())
+        .await?;
+
+    malai::proxy_pipe(addr, remote_id52, access_log, stream, send, recv).await
+}
+
+/// sends the datagrams from the peer to the addresses they start with, and sends back whatever
+/// comes to our sockets, with the address it came from. returns when the peer finishes the
+/// stream, with the number of bytes read from the peer, and the number of bytes sent to it.
+async fn udp_associate(
+    mut send: iroh::endpoint::SendStream,
+    mut recv: iroh::endpoint::RecvStream,
+) -> eyre::Result<(u64, u64)> {
+    let v4 = std::sync::Arc::new(
+        tokio::net::UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, 0)).await?,
+    );
+    // not every machine has IPv6, we only send to IPv4 addresses on those
+    let v6 = tokio::net::UdpSocket::bind((std::net::Ipv6Addr::UNSPECIFIED, 0))
+        .await
+        .inspect_err(|e| tracing::info!("no IPv6 socket: {e}"))
+        .ok()
+        .map(std::sync::Arc::new);
+
+    let (done, mut stop) = tokio::sync::oneshot::channel::<()>();
+    let replies = {
+        let v4 = v4.clone();
+        let v6 = v6.clone();
+        tokio::spawn(async move {
+            let mut buf4 = vec![0; kulfi_utils::udp::MAX_DATAGRAM_SIZE];
+            let mut buf6 = vec![0; kulfi_utils::udp::MAX_DATAGRAM_SIZE];
+            let mut bytes_out = 0;
+            loop {
+                let (r, buf) = tokio::select! {
+                    _ = &mut stop => break,
+                    r = v4.recv_from(&mut buf4) => (r, &buf4),
+                    r = recv_from(v6.as_deref(), &mut buf6) => (r, &buf6),
+                };
+                match r {
+                    Ok((n, from)) => {
+                        let mut frame = malai::socks::encode_addr(from);
+                        frame.extend_from_slice(&buf[..n]);
+                        // the address does not always fit next to the largest datagrams
+                        if let Err(e) = kulfi_utils::udp::write_datagram(&mut send, &frame).await {
+                            tracing::debug!(%from, "dropping datagram: {e}");
+                            continue;
+                        }
+                        bytes_out += n as u64;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                        tracing::debug!("udp service refused a datagram: {e}");
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            send.finish()?;
+            Ok::<_, eyre::Report>(bytes_out)
+        })
+    };
+
+    // the apps send many datagrams to the same few names, say a DNS server. the names come from
+    // the peer, so there are only so many we remember, and only for a while
+    let mut resolved: std::collections::HashMap<
+        String,
+        (std::net::SocketAddr, std::time::Instant),
+    > = std::collections::HashMap::new();
+    let mut buf = Vec::new();
+    let mut bytes_in = 0;
+    let r = loop {
+        let frame = match kulfi_utils::udp::read_datagram(&mut recv, &mut buf).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let (addr, datagram) = match malai::socks::split_addr(frame) {
+            Some(v) => v,
+            None => {
+                tracing::debug!("dropping datagram without an address");
+                continue;
+            }
+        };
+        bytes_in += datagram.len() as u64;
+
+        let to = match resolved.get(&addr) {
+            Some((to, at)) if at.elapsed() < RESOLVED_TTL => *to,
+            _ => match tokio::net::lookup_host(&addr).await.map(|mut a| a.next()) {
+                Ok(Some(to)) => {
+                    if resolved.len() >= MAX_RESOLVED {
+                        resolved.retain(|_, (_, at)| at.elapsed() < RESOLVED_TTL);
+                    }
+                    if resolved.len() < MAX_RESOLVED || resolved.contains_key(&addr) {
+                        resolved.insert(addr, (to, std::time::Instant::now()));
+                    }
+                    to
+                }
+                Ok(None) | Err(_) => {
+                    tracing::debug!("dropping datagram, could not resolve {addr}");
+                    continue;
+                }
+            },
+        };
+        let socket = match (to, &v6) {
+            (std::net::SocketAddr::V4(_), _) => &v4,
+            (std::net::SocketAddr::V6(_), Some(v6)) => v6,
+            (std::net::SocketAddr::V6(_), None) => {
+                tracing::debug!("dropping datagram to {to}, we do not have IPv6");
+                continue;
+            }
+        };
+        match socket.send_to(datagram, to).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                tracing::debug!("udp service refused a datagram: {e}");
+            }
+            Err(e) => break Err(e.into()),
+        }
+    };
+
+    let _ = done.send(());
+    let bytes_out = replies.await??;
+    r?;
+    Ok((bytes_in, bytes_out))
+}
+
+/// `recv_from()` on `socket`, if we have it
+async fn recv_from(
+    socket: Option<&tokio::net::UdpSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, std::net::SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[derive(PartialEq, Debug)]
+enum InfoMode {
+    Startup,
+    OnExit,
+}
+
+impl InfoMode {
+    fn print(&self, id52: &str) {
+        use colored::Colorize;
+
+        if self == &InfoMode::OnExit {
+            println!();
+        }
+
+        println!(
+            "{cli}: Running Public SOCKS5 Proxy at {id52}.",
+            cli = "Malai".on_green().black(),
+            id52 = id52.yellow(),
+        );
+
+        println!(
+            "Run {cli} on any machine to access this proxy server.",
+            cli = format!("malai socks5 {id52}").yellow(),
+        );
+
+        if self == &InfoMode::OnExit {
+            println!("Press ctrl+c again to exit.");
+        }
+    }
+}
@@ -1,14 +1,22 @@
-pub async fn tcp_bridge(port: u16, proxy_target: String, graceful: kulfi_utils::Graceful) {
-    use eyre::WrapErr;
-
-    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{port}"))
-        .await
-        .wrap_err_with(|| {
-            format!("can not listen to port {port}, is it busy, or you do not have root access?")
-        })
-        .unwrap();
+pub async fn tcp_bridge(
+    listen: malai::Listen,
+    proxy_target: String,
+    service: Option<String>,
+    graceful: kulfi_utils::Graceful,
+) {
+    let listener = match listen.bind().await {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to bind to {}: {e:?}", listen.addr);
+            std::process::exit(1);
+        }
+    };
 
-    println!("Listening on 127.0.0.1:{port}");
+    // the port can be 0, to bind to a random one
+    match listener.local_addr() {
+        Ok(addr) => println!("Listening on {addr}"),
+        Err(_) => println!("Listening on {}", listen.addr),
+    }
 
     let peer_connections = kulfi_utils::PeerStreamSenders::default();
 
@@ -19,14 +27,26 @@ pub async fn tcp_bridge(port: u16, proxy_target: String, graceful: kulfi_utils::
                 break;
             }
             val = listener.accept() => {
-                tracing::info!("got connection");
                 let self_endpoint = kulfi_utils::global_iroh_endpoint().await;
                 let graceful_for_handle_connection = graceful.clone();
                 let peer_connections = peer_connections.clone();
                 let proxy_target = proxy_target.clone();
+                let service = service.clone();
                 match val {
-                    Ok((stream, _addr)) => {
-                        graceful.spawn(async move { handle_connection(self_endpoint, stream, graceful_for_handle_connection, peer_connections, proxy_target).await });
+                    Ok((mut stream, peer)) => {
+                        let listen = listen.clone();
+                        graceful.spawn(async move {
+                            let client_addr = match listen.client_addr(&mut stream, peer).await {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    tracing::info!(%peer, "rejecting connection: {e:?}");
+                                    return;
+                                }
+                            };
+                            tracing::info!(%client_addr, "got connection");
+                            let stream = kulfi_utils::metrics::ClientConnection::new(stream);
+                            handle_connection(self_endpoint, stream, client_addr, graceful_for_handle_connection, peer_connections, proxy_target, service).await
+                        });
                     },
                     Err(e) => {
                         tracing::error!("failed to accept: {e:?}");
@@ -37,16 +57,26 @@ pub async fn tcp_bridge(port: u16, proxy_target: String, graceful: kulfi_utils::
     }
 }
 
+#[tracing::instrument(skip_all, fields(client = %client_addr))]
 pub async fn handle_connection(
     self_endpoint: iroh::Endpoint,
-    stream: tokio::net::TcpStream,
+    stream: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+    client_addr: std::net::SocketAddr,
     graceful: kulfi_utils::Graceful,
     peer_connections: kulfi_utils::PeerStreamSenders,
     remote_node_id52: String,
+    service: Option<String>,
 ) {
-    println!("forwarding tcp connection to {remote_node_id52}");
+    println!("forwarding tcp connection from {client_addr} to {remote_node_id52}");
+    let header = match malai::TcpTarget::header(service) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("failed to create the protocol header: {e:?}");
+            return;
+        }
+    };
     if let Err(e) = kulfi_utils::tcp_to_peer(
-        kulfi_utils::Protocol::Tcp.into(),
+        header,
         self_endpoint,
         stream,
         &remote_node_id52,
@@ -0,0 +1,173 @@
+/// how long we wait for the connection to the peer
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+/// how long we wait before opening the tunnel again, after it closed
+const RETRY_AFTER: std::time::Duration = std::time::Duration::from_secs(5);
+/// how often we check the connection is still alive, a tunnel can be idle for a long time
+const PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(12);
+
+/// `malai tcp-reverse` shares a TCP service through a peer running `malai tcp-reverse-remote`, for
+/// when the peers that want the service can not connect to us, say because we are behind a strict
+/// firewall. we connect to the peer instead, it listens on `remote_port` on its side, and every
+/// connection it gets there comes back to us over the same kulfi connection.
+///
+/// if the tunnel can not be opened the first time, we exit, after that it is opened again
+/// whenever it closes, till we are stopped.
+pub async fn tcp_reverse(
+    remote_id52: String,
+    remote_port: u16,
+    addr: kulfi_utils::UpstreamAddr,
+    graceful: kulfi_utils::Graceful,
+) {
+    let remote = {
+        use std::str::FromStr;
+        match kulfi_id52::PublicKey::from_str(&remote_id52)
+            .ok()
+            .and_then(|public_key| iroh::EndpointId::from_bytes(&public_key.to_bytes()).ok())
+        {
+            Some(v) => v,
+            None => {
+                eprintln!("{remote_id52} is not a valid id52");
+                std::process::exit(1);
+            }
+        }
+    };
+
+    let (id52, secret_key) = match kulfi_utils::read_or_create_key().await {
+        Ok(v) => v,
+        Err(e) => {
+            malai::identity_read_err_msg(e);
+            std::process::exit(1);
+        }
+    };
+
+    let ep = match kulfi_utils::get_endpoint(secret_key).await {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Failed to bind to iroh network:");
+            eprintln!("{e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    println!("Connecting to {remote_id52} as {id52}");
+    let mut opened = false;
+    loop {
+        match tunnel(&ep, remote, remote_port, &addr, &mut opened, &graceful).await {
+            Ok(()) => break,
+            Err(e) if !opened => {
+                eprintln!("Failed to open the tunnel: {e:?}");
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("The tunnel closed, opening it again in {RETRY_AFTER:?}: {e}");
+                tracing::info!("tunnel error: {e:?}");
+            }
+        }
+
+        tokio::select! {
+            _ = graceful.cancelled() => break,
+            _ = tokio::time::sleep(RETRY_AFTER) => {}
+        }
+    }
+
+    ep.close().await;
+}
+
+/// opens the tunnel, and serves the connections that come through it. returns when we are
+/// stopped, or with the reason the tunnel closed. `opened` is set once the peer listens for us.
+async fn tunnel(
+    ep: &iroh::Endpoint,
+    remote: iroh::EndpointId,
+    remote_port: u16,
+    addr: &kulfi_utils::UpstreamAddr,
+    opened: &mut bool,
+    graceful: &kulfi_utils::Graceful,
+) -> eyre::Result<()> {
+    let conn = tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        ep.connect(remote, kulfi_utils::APNS_IDENTITY),
+    )
+    .await
+    .map_err(|_| eyre::anyhow!("connecting took more than {CONNECT_TIMEOUT:?}"))??;
+
+    // the peer listens for as long as this stream is open, so `send` has to be kept around
+    let (mut send, mut recv) = kulfi_utils::open_bi(
+        &conn,
+        kulfi_utils::ProtocolHeader {
+            protocol: kulfi_utils::Protocol::TcpReverse,
+            extra: Some(serde_json::to_string(&malai::ReverseRequest {
+                port: remote_port,
+            })?),
+        },
+    )
+    .await?;
+
+    match kulfi_utils::next_json(&mut recv).await? {
+        malai::ReverseReply::Listening(remote_addr) => {
+            println!("Peer is forwarding connections on {remote_addr} to {addr}");
+        }
+        malai::ReverseReply::Failed(e) => return Err(eyre::anyhow!("{e}")),
+    }
+    *opened = true;
+
+    let mut accepting = {
+        let conn = conn.clone();
+        let addr = addr.clone();
+        let graceful = graceful.clone();
+        let self_id = ep.id();
+        // returns the error that stopped it
+        tokio::spawn(async move {
+            loop {
+                let accepted =
+                    kulfi_utils::accept_bi(&conn, self_id, kulfi_utils::Protocol::Tcp).await;
+                let (send, recv) = match accepted {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let addr = addr.clone();
+                graceful.spawn(async move {
+                    if let Err(e) = match &addr {
+                        kulfi_utils::UpstreamAddr::Tcp(addr) => {
+                            kulfi_utils::peer_to_tcp(addr, send, recv).await
+                        }
+                        #[cfg(unix)]
+                        kulfi_utils::UpstreamAddr::Unix(path) => {
+                            kulfi_utils::peer_to_unix(path, send, recv).await
+                        }
+                        #[cfg(not(unix))]
+                        kulfi_utils::UpstreamAddr::Unix(_) => Err(eyre::anyhow!(
+                            "unix sockets are not supported on this platform"
+                        )),
+                    } {
+                        tracing::error!("failed to proxy tcp: {e:?}");
+                    }
+                });
+            }
+        })
+    };
+
+    let mut buf = [0u8; 1];
+    let r = loop {
+        tokio::select! {
+            _ = graceful.cancelled() => {
+                let _ = send.finish();
+                conn.close(0u32.into(), b"done");
+                break Ok(());
+            }
+            _ = recv.read(&mut buf) => {
+                break Err(eyre::anyhow!("the peer closed the tunnel"));
+            }
+            _ = tokio::time::sleep(PING_INTERVAL) => {
+                if let Err(e) = kulfi_utils::ping(&conn).await {
+                    break Err(e);
+                }
+            }
+            e = &mut accepting => {
+                break Err(e.unwrap_or_else(Into::into));
+            }
+        }
+    };
+
+    accepting.abort();
+    r
+}
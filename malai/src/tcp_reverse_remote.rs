@@ -0,0 +1,233 @@
+/// sent by `malai tcp-reverse` after the protocol header, the port it wants us to listen on
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ReverseRequest {
+    pub port: u16,
+}
+
+/// our reply to a `ReverseRequest`, a line of JSON on the same stream
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub enum ReverseReply {
+    Listening(std::net::SocketAddr),
+    Failed(String),
+}
+
+/// `malai tcp-reverse-remote` listens on ports for the peers running `malai tcp-reverse`, and sends
+/// every connection it gets on those ports back to the peer that asked for it, over the connection
+/// the peer made to us. this way a machine that can not be connected to, say behind a strict
+/// firewall, can still share its services.
+///
+/// the port is open for as long as the peer keeps its `Protocol::TcpReverse` stream open.
+pub async fn tcp_reverse_remote(
+    bind: std::net::IpAddr,
+    acl: malai::PeerAcl,
+    access_log: kulfi_utils::AccessLog,
+    graceful: kulfi_utils::Graceful,
+) {
+    let (id52, secret_key) = match kulfi_utils::read_or_create_key().await {
+        Ok(v) => v,
+        Err(e) => {
+            malai::identity_read_err_msg(e);
+            std::process::exit(1);
+        }
+    };
+
+    let ep = match kulfi_utils::get_endpoint(secret_key).await {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Failed to bind to iroh network:");
+            eprintln!("{e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    InfoMode::Startup.print(bind, &id52);
+
+    let mut graceful_mut = graceful.clone();
+    loop {
+        let graceful_for_handle_connection = graceful.clone();
+
+        tokio::select! {
+            _ = graceful_mut.show_info() => {
+                InfoMode::OnExit.print(bind, &id52);
+            }
+            _ = graceful.cancelled() => {
+                tracing::info!("Stopping control server.");
+                break;
+            }
+            conn = ep.accept() => {
+                let conn = match conn {
+                    Some(conn) => conn,
+                    None => {
+                        tracing::info!("no connection");
+                        break;
+                    }
+                };
+                let acl = acl.clone();
+                let access_log = access_log.clone();
+                let self_id = ep.id();
+
+                graceful.spawn(async move {
+                    let start = std::time::Instant::now();
+                    let conn = match conn.await {
+                        Ok(c) => c,
+                        Err(e) => {
+                            tracing::error!("failed to convert incoming to connection: {:?}", e);
+                            return;
+                        }
+                    };
+                    if let Err(e) = handle_connection(conn, self_id, bind, acl, access_log, graceful_for_handle_connection).await {
+                        tracing::error!("connection error: {:?}", e);
+                    }
+                    tracing::info!("connection handled in {:?}", start.elapsed());
+                });
+            }
+        }
+    }
+
+    ep.close().await;
+}
+
+async fn handle_connection(
+    conn: iroh::endpoint::Connection,
+    self_id: iroh::EndpointId,
+    bind: std::net::IpAddr,
+    acl: malai::PeerAcl,
+    access_log: kulfi_utils::AccessLog,
+    graceful: kulfi_utils::Graceful,
+) -> eyre::Result<()> {
+    acl.guard(&conn).await?;
+    let remote_id52 = kulfi_utils::get_remote_id52(&conn);
+    let _active = kulfi_utils::metrics::ActivePeer::accepted(&remote_id52);
+
+    tracing::info!("new client: {remote_id52}, waiting for bidirectional stream");
+    loop {
+        let (send, recv) =
+            kulfi_utils::accept_bi(&conn, self_id, kulfi_utils::Protocol::TcpReverse)
+                .await
+                .inspect_err(|e| tracing::error!("failed to accept bidirectional stream: {e:?}"))?;
+        let conn = conn.clone();
+        let access_log = access_log.clone();
+        let graceful_for_tunnel = graceful.clone();
+        graceful.spawn(async move {
+            if let Err(e) =
+                handle_tunnel(conn, bind, send, recv, access_log, graceful_for_tunnel).await
+            {
+                tracing::error!("tunnel error: {e:?}");
+            }
+        });
+    }
+}
+
+/// listens on the port the peer asked for, till the peer closes the stream
+async fn handle_tunnel(
+    conn: iroh::endpoint::Connection,
+    bind: std::net::IpAddr,
+    mut send: iroh::endpoint::SendStream,
+    mut recv: iroh::endpoint::RecvStream,
+    access_log: kulfi_utils::AccessLog,
+    graceful: kulfi_utils::Graceful,
+) -> eyre::Result<()> {
+    let remote_id52 = kulfi_utils::get_remote_id52(&conn);
+    let request: ReverseRequest = kulfi_utils::next_json(&mut recv).await?;
+
+    let listener = match tokio::net::TcpListener::bind((bind, request.port)).await {
+        Ok(v) => v,
+        Err(e) => {
+            let msg = format!("failed to listen on port {}: {e}", request.port);
+            reply(&mut send, &ReverseReply::Failed(msg.clone())).await?;
+            send.finish()?;
+            return Err(eyre::anyhow!("{remote_id52}: {msg}"));
+        }
+    };
+    let addr = listener.local_addr()?;
+    reply(&mut send, &ReverseReply::Listening(addr)).await?;
+    println!("Forwarding connections on {addr} to {remote_id52}");
+
+    // the peer sends nothing more on this stream, it finishing the stream, or the connection
+    // going away, is the end of the tunnel
+    let mut buf = [0u8; 1];
+    loop {
+        tokio::select! {
+            _ = graceful.cancelled() => {
+                break;
+            }
+            _ = recv.read(&mut buf) => {
+                break;
+            }
+            val = listener.accept() => {
+                let (stream, client_addr) = match val {
+                    Ok(v) => v,
+                    Err(e) => {
+                        tracing::error!("failed to accept: {e:?}");
+                        continue;
+                    }
+                };
+                tracing::info!(%client_addr, "got connection");
+                let stream = kulfi_utils::metrics::ClientConnection::new(stream);
+                let conn = conn.clone();
+                let access_log = access_log.clone();
+                let mut entry = access_log.entry(&remote_id52, addr.to_string());
+                graceful.spawn(async move {
+                    let r = match kulfi_utils::open_bi(&conn, kulfi_utils::Protocol::Tcp.into()).await {
+                        Ok((send, recv)) => {
+                            let (tcp_recv, tcp_send) = tokio::io::split(stream);
+                            kulfi_utils::pipe_tcp_stream_over_iroh(tcp_recv, tcp_send, send, recv)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match r {
+                        Ok((bytes_in, bytes_out)) => {
+                            entry.bytes_in = bytes_in;
+                            entry.bytes_out = bytes_out;
+                        }
+                        Err(e) => tracing::error!("failed to proxy tcp: {e:?}"),
+                    }
+                    access_log.log(entry);
+                });
+            }
+        }
+    }
+
+    println!("Stopped forwarding connections on {addr} to {remote_id52}");
+    Ok(())
+}
+
+async fn reply(send: &mut iroh::endpoint::SendStream, reply: &ReverseReply) -> eyre::Result<()> {
+    send.write_all(format!("{}\n", serde_json::to_string(reply)?).as_bytes())
+        .await?;
+    Ok(())
+}
+
+#[derive(PartialEq, Debug)]
+enum InfoMode {
+    Startup,
+    OnExit,
+}
+
+impl InfoMode {
+    fn print(&self, bind: std::net::IpAddr, id52: &str) {
+        use colored::Colorize;
+
+        if self == &InfoMode::OnExit {
+            println!();
+        }
+
+        if self == &InfoMode::Startup {
+            println!(
+                "{}: Listening on {bind} for reverse tunnels",
+                "Malai".on_green().black()
+            );
+        }
+
+        println!(
+            "Run {}",
+            format!("malai tcp-reverse {id52} <remote-port> <local-port>").yellow()
+        );
+        println!("to share a service from any machine through this one.");
+
+        if self == &InfoMode::OnExit {
+            println!("Press ctrl+c again to exit.");
+        }
+    }
+}
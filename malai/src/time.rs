@@ -0,0 +1,66 @@
+/// `malai time` asks peers for the time, and prints how far off our clock is from what most of
+/// them say. this is for machines without NTP, which still want to know their clock is sane
+/// before they trust anything that expires.
+///
+/// we exit with a non zero status unless most of the peers we ask answer, and agree on the time,
+/// so scripts can check.
+pub async fn time(peers: Vec<String>) {
+    use colored::Colorize;
+
+    let ep = kulfi_utils::global_iroh_endpoint().await;
+    let answers = futures_util::future::join_all(
+        peers
+            .iter()
+            .map(|id52| kulfi_utils::time::ask_time(&ep, id52)),
+    )
+    .await;
+
+    let mut samples = Vec::new();
+    for (id52, answer) in peers.iter().zip(answers) {
+        match answer {
+            Ok(sample) => {
+                println!(
+                    "{id52}: offset {}, round trip {:?}",
+                    seconds(sample.offset),
+                    sample.rtt
+                );
+                samples.push(sample);
+            }
+            Err(e) => println!("{id52}: {}", format!("no answer, {e}").red()),
+        }
+    }
+
+    let consensus = kulfi_utils::time::consensus(&samples);
+    ep.close().await;
+
+    match consensus {
+        Some(c) if c.agreeing * 2 > peers.len() => {
+            println!(
+                "The peers' clocks are {} ± {:.3}s from ours, {} of {} agree.",
+                seconds(c.offset).green(),
+                c.error as f64 / 1_000_000.0,
+                c.agreeing,
+                peers.len(),
+            );
+        }
+        Some(c) => {
+            eprintln!(
+                "Only {} of the {} peers agree on the time, their clocks are {} ± {:.3}s from ours.",
+                c.agreeing,
+                peers.len(),
+                seconds(c.offset),
+                c.error as f64 / 1_000_000.0,
+            );
+            std::process::exit(1);
+        }
+        None => {
+            eprintln!("None of the peers told us the time.");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// microseconds as seconds, `+0.012s` when the peers are ahead of us
+fn seconds(micros: i64) -> String {
+    format!("{:+.3}s", micros as f64 / 1_000_000.0)
+}
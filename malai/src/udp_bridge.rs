@@ -0,0 +1,191 @@
+/// how many datagrams from a client can wait for its stream to the peer, more are dropped, as a
+/// busy router would
+const FLOW_QUEUE: usize = 128;
+
+/// the open flows, by the client address, every flow has its own stream to the peer
+type Flows =
+    std::sync::Arc<std::sync::Mutex<std::collections::HashMap<std::net::SocketAddr, FlowSender>>>;
+type FlowSender = tokio::sync::mpsc::Sender<Vec<u8>>;
+
+pub async fn udp_bridge(
+    addr: std::net::SocketAddr,
+    proxy_target: String,
+    idle_timeout: std::time::Duration,
+    graceful: kulfi_utils::Graceful,
+) {
+    let socket = match tokio::net::UdpSocket::bind(addr).await {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Failed to bind to {addr}: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    // the port can be 0, to bind to a random one
+    match socket.local_addr() {
+        Ok(addr) => println!("Listening on {addr} (UDP)"),
+        Err(_) => println!("Listening on {addr} (UDP)"),
+    }
+
+    let bridge = Bridge {
+        socket: std::sync::Arc::new(socket),
+        flows: Default::default(),
+        proxy_target,
+        idle_timeout,
+        peer_connections: Default::default(),
+        graceful: graceful.clone(),
+    };
+
+    let mut buf = vec![0; kulfi_utils::udp::MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            _ = graceful.cancelled() => {
+                tracing::info!("Stopping control server.");
+                break;
+            }
+            val = bridge.socket.recv_from(&mut buf) => {
+                match val {
+                    Ok((n, client_addr)) => bridge.datagram(client_addr, buf[..n].to_vec()).await,
+                    Err(e) => {
+                        tracing::error!("failed to receive: {e:?}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Bridge {
+    socket: std::sync::Arc<tokio::net::UdpSocket>,
+    flows: Flows,
+    proxy_target: String,
+    idle_timeout: std::time::Duration,
+    peer_connections: kulfi_utils::PeerStreamSenders,
+    graceful: kulfi_utils::Graceful,
+}
+
+impl Bridge {
+    /// passes the datagram to the flow of the client, starting one if there is none
+    async fn datagram(&self, client_addr: std::net::SocketAddr, datagram: Vec<u8>) {
+        use tokio::sync::mpsc::error::TrySendError;
+
+        let datagrams = {
+            let mut flows = self
+                .flows
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            let datagram = match flows.get(&client_addr) {
+                None => datagram,
+                Some(flow) => match flow.try_send(datagram) {
+                    Ok(()) => return,
+                    Err(TrySendError::Full(_)) => {
+                        tracing::debug!(%client_addr, "flow is busy, dropping a datagram");
+                        return;
+                    }
+                    // the flow has just expired
+                    Err(TrySendError::Closed(datagram)) => datagram,
+                },
+            };
+
+            let (sender, datagrams) = tokio::sync::mpsc::channel(FLOW_QUEUE);
+            sender
+                .try_send(datagram)
+                .expect("a new channel has room for a datagram");
+            flows.insert(client_addr, sender);
+            datagrams
+        };
+
+        let self_endpoint = kulfi_utils::global_iroh_endpoint().await;
+        let bridge = self.clone();
+        self.graceful
+            .spawn(async move { bridge.flow(self_endpoint, client_addr, datagrams).await });
+    }
+
+    #[tracing::instrument(skip_all, fields(client = %client_addr))]
+    async fn flow(
+        self,
+        self_endpoint: iroh::Endpoint,
+        client_addr: std::net::SocketAddr,
+        mut datagrams: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    ) {
+        println!(
+            "forwarding udp flow from {client_addr} to {}",
+            self.proxy_target
+        );
+        if let Err(e) = self.pipe(self_endpoint, client_addr, &mut datagrams).await {
+            tracing::error!("failed to proxy udp: {e:?}");
+        }
+
+        // the datagrams that came in meanwhile are lost, the client will send more, and they start
+        // a new flow
+        datagrams.close();
+        let mut flows = self
+            .flows
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if flows.get(&client_addr).is_some_and(|f| f.is_closed()) {
+            flows.remove(&client_addr);
+        }
+    }
+
+    /// returns once the flow has been idle for `idle_timeout`, or the peer ends it
+    async fn pipe(
+        &self,
+        self_endpoint: iroh::Endpoint,
+        client_addr: std::net::SocketAddr,
+        datagrams: &mut tokio::sync::mpsc::Receiver<Vec<u8>>,
+    ) -> eyre::Result<()> {
+        let (conn, send, recv) = kulfi_utils::get_stream_with_connection(
+            self_endpoint,
+            kulfi_utils::Protocol::Udp.into(),
+            self.proxy_target.clone(),
+            self.peer_connections.clone(),
+            self.graceful.clone(),
+        )
+        .await?;
+        let (mut send, mut recv) = kulfi_utils::udp::opened_flow(conn, send, recv);
+
+        let replied = std::sync::Arc::new(tokio::sync::Notify::new());
+        let mut replies = {
+            let socket = self.socket.clone();
+            let replied = replied.clone();
+            tokio::spawn(async move {
+                while let Some(datagram) = recv.recv().await? {
+                    socket.send_to(&datagram, client_addr).await?;
+                    replied.notify_one();
+                }
+                Ok::<_, eyre::Report>(())
+            })
+        };
+
+        let r = loop {
+            tokio::select! {
+                datagram = datagrams.recv() => {
+                    let Some(datagram) = datagram else {
+                        break Ok(());
+                    };
+                    if let Err(e) = send.send(&datagram).await {
+                        break Err(e);
+                    }
+                }
+                _ = replied.notified() => {}
+                r = &mut replies => {
+                    break r.map_err(Into::into).and_then(|r| r);
+                }
+                _ = tokio::time::sleep(self.idle_timeout) => {
+                    tracing::info!("flow is idle, closing it");
+                    break Ok(());
+                }
+                _ = self.graceful.cancelled() => {
+                    break Ok(());
+                }
+            }
+        };
+
+        // the peer sees the end of the stream, and closes its side of the flow
+        let _ = send.finish();
+        replies.abort();
+        r
+    }
+}
@@ -0,0 +1,82 @@
+/// WatchedFile keeps the parsed content of a config file in memory, and re-reads the file when its
+/// modification time changes.
+///
+/// we check the modification time lazily, when the value is requested, instead of running a
+/// watcher task. all the users read the value once per incoming connection or request, and a
+/// `stat()` call is cheap compared to the rest of the work done for a connection.
+///
+/// if the file has changed but can not be read or parsed (say the editor is in the middle of
+/// writing it), we log the error and keep serving the last good content.
+pub struct WatchedFile<T> {
+    path: std::path::PathBuf,
+    parse: fn(&str) -> eyre::Result<T>,
+    state: tokio::sync::Mutex<(Option<std::time::SystemTime>, std::sync::Arc<T>)>,
+}
+
+impl<T> WatchedFile<T> {
+    /// reads and parses the file, errors if the file can not be read or parsed
+    pub async fn new(
+        path: impl Into<std::path::PathBuf>,
+        parse: fn(&str) -> eyre::Result<T>,
+    ) -> eyre::Result<Self> {
+        let path = path.into();
+        let (modified, value) = read(&path, parse).await?;
+
+        Ok(Self {
+            path,
+            parse,
+            state: tokio::sync::Mutex::new((modified, std::sync::Arc::new(value))),
+        })
+    }
+
+    pub async fn get(&self) -> std::sync::Arc<T> {
+        let mut state = self.state.lock().await;
+
+        let modified = match modified(&self.path).await {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::error!(path = ?self.path, "failed to stat watched file: {e:?}");
+                return state.1.clone();
+            }
+        };
+
+        if modified == state.0 {
+            return state.1.clone();
+        }
+
+        match read(&self.path, self.parse).await {
+            Ok((modified, value)) => {
+                tracing::info!(path = ?self.path, "reloaded watched file");
+                *state = (modified, std::sync::Arc::new(value));
+            }
+            Err(e) => {
+                tracing::error!(path = ?self.path, "failed to reload watched file: {e:?}");
+            }
+        }
+
+        state.1.clone()
+    }
+}
+
+async fn modified(path: &std::path::Path) -> std::io::Result<Option<std::time::SystemTime>> {
+    // some platforms do not support modification time, in which case we read the file once and
+    // never reload it.
+    Ok(tokio::fs::metadata(path).await?.modified().ok())
+}
+
+async fn read<T>(
+    path: &std::path::Path,
+    parse: fn(&str) -> eyre::Result<T>,
+) -> eyre::Result<(Option<std::time::SystemTime>, T)> {
+    use eyre::WrapErr;
+
+    let modified = modified(path)
+        .await
+        .wrap_err_with(|| format!("failed to stat {path:?}"))?;
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .wrap_err_with(|| format!("failed to read {path:?}"))?;
+    let value = parse(&content).wrap_err_with(|| format!("failed to parse {path:?}"))?;
+
+    Ok((modified, value))
+}